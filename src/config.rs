@@ -1,21 +1,240 @@
-use std::path::PathBuf;
+use std::collections::HashSet;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
+use serde::Deserialize;
+
+use crate::exceptions::TopCatError;
+use crate::tag_expr::TagExpr;
+
+/// How to split the output into several files.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SplitBy {
+    /// One output file per layer.
+    Layer,
+    /// One output file per top level directory of the input directories.
+    Directory,
+}
+
+/// The line ending to convert the output to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LineEnding {
+    Lf,
+    Crlf,
+}
+
+impl FromStr for LineEnding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "lf" => Ok(LineEnding::Lf),
+            "crlf" => Ok(LineEnding::Crlf),
+            _ => Err(format!("expected 'lf' or 'crlf', got '{}'", s)),
+        }
+    }
+}
+
+/// How the file suffix (eg `;`) is added to the end of each file.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum SuffixMode {
+    /// Append the suffix unless the file already ends with it.
+    #[default]
+    Plain,
+    /// Look past trailing line and block comments to decide if the suffix is needed, and add
+    /// it after the last piece of code.
+    CommentAware,
+}
+
+impl FromStr for SuffixMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "plain" => Ok(SuffixMode::Plain),
+            "comment-aware" => Ok(SuffixMode::CommentAware),
+            _ => Err(format!("expected 'plain' or 'comment-aware', got '{}'", s)),
+        }
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// A layer and how its files are written, as defined in a `--config` file.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Layer {
+    pub name: String,
+    /// Replaces `--file-separator` for files in this layer.
+    #[serde(default)]
+    pub separator: Option<String>,
+    /// Replaces `--file-suffix` for files in this layer.
+    #[serde(default)]
+    pub suffix: Option<String>,
+    /// A template written before the first file of this layer in each output file.
+    #[serde(default)]
+    pub preamble: Option<String>,
+    /// A template written after the last file of this layer in each output file.
+    #[serde(default)]
+    pub postamble: Option<String>,
+    /// Whether files in other layers may require files in this one.
+    #[serde(default = "default_true")]
+    pub cross_layer_deps: bool,
+    /// Whether the selection, prefix and `--where` filters apply to this layer. Every file of a
+    /// layer that isn't optional is included in filtered builds, along with its dependencies.
+    #[serde(default = "default_true")]
+    pub optional: bool,
+}
+
+/// The settings read from a `--config` file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    /// The layers in order.
+    pub layers: Vec<Layer>,
+    #[serde(default)]
+    pub fallback_layer: Option<String>,
+}
+
+impl ConfigFile {
+    /// Read a config file, or stdin if `path` is `-`.
+    pub fn read(path: &Path) -> Result<ConfigFile, TopCatError> {
+        let content = if path.as_os_str() == "-" {
+            let mut content = String::new();
+            io::stdin().read_to_string(&mut content)?;
+            content
+        } else {
+            fs::read_to_string(path)?
+        };
+        let source = if path.as_os_str() == "-" {
+            Path::new("<stdin>")
+        } else {
+            path
+        };
+        let invalid = |message: String| TopCatError::InvalidConfig(source.to_path_buf(), message);
+        let config_file: ConfigFile =
+            serde_json::from_str(&content).map_err(|e| invalid(e.to_string()))?;
+
+        if config_file.layers.is_empty() {
+            return Err(invalid("no layers are defined".to_string()));
+        }
+        let mut names = HashSet::new();
+        for layer in &config_file.layers {
+            if layer.name.is_empty() || layer.name.chars().any(char::is_whitespace) {
+                return Err(invalid(format!("invalid layer name '{}'", layer.name)));
+            }
+            if !names.insert(layer.name.as_str()) {
+                return Err(invalid(format!("layer '{}' is defined twice", layer.name)));
+            }
+        }
+        Ok(config_file)
+    }
+}
+
+#[derive(Default)]
 pub struct Config<'a> {
     pub input_dirs: Vec<PathBuf>,
     pub include_globs: Option<&'a [String]>,
     pub exclude_globs: Option<&'a [String]>,
     pub include_extensions: Option<&'a [String]>,
     pub exclude_extensions: Option<&'a [String]>,
-    pub output: PathBuf,
+    pub output: Option<PathBuf>,
     pub comment_str: String,
     pub file_separator_str: String,
     pub file_end_str: String,
+    pub suffix_mode: SuffixMode,
+    pub block_comment_start: String,
+    pub block_comment_end: String,
+    pub header_template: Option<String>,
+    pub file_prefix_template: Option<String>,
+    pub file_suffix_template: Option<String>,
+    pub footer_template: Option<String>,
+    pub reproducible: bool,
+    pub header_hash: bool,
+    pub header_inputs: bool,
+    pub source_map: bool,
+    pub split_by: Option<SplitBy>,
+    pub max_chunk_bytes: Option<usize>,
+    pub max_chunk_files: Option<usize>,
+    pub strip_bom: bool,
+    pub line_endings: Option<LineEnding>,
+    pub strip_header: bool,
     pub verbose: bool,
     pub dry_run: bool,
+    pub check: bool,
     pub include_node_prefixes: Option<&'a [String]>,
     pub exclude_node_prefixes: Option<&'a [String]>,
+    pub metadata_filters: Option<&'a [(String, String)]>,
+    pub include_tags: Option<&'a [TagExpr]>,
+    pub exclude_tags: Option<&'a [TagExpr]>,
     pub include_hidden: bool,
     pub subdir_filter: Option<PathBuf>,
     pub layers: Vec<String>,
+    /// The layers defined in a `--config` file, empty when they come from `--layers`.
+    pub layer_definitions: Vec<Layer>,
     pub fallback_layer: String,
+    pub name_from_path: bool,
+    pub require_name: bool,
+    pub infer_deps: bool,
+}
+
+impl Config<'_> {
+    /// The definition of `layer` from the `--config` file, if there is one.
+    pub fn layer_definition(&self, layer: &str) -> Option<&Layer> {
+        self.layer_definitions.iter().find(|l| l.name == layer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_config_file() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("topcat.json");
+        fs::write(
+            &path,
+            r#"{
+                "layers": [
+                    {"name": "prepend", "cross_layer_deps": false},
+                    {"name": "append", "optional": false, "preamble": "BEGIN;\n"}
+                ],
+                "fallback_layer": "append"
+            }"#,
+        )
+        .unwrap();
+        let config_file = ConfigFile::read(&path).unwrap();
+        assert_eq!(config_file.fallback_layer.as_deref(), Some("append"));
+        assert_eq!(
+            config_file.layers[1],
+            Layer {
+                name: "append".to_string(),
+                separator: None,
+                suffix: None,
+                preamble: Some("BEGIN;\n".to_string()),
+                postamble: None,
+                cross_layer_deps: true,
+                optional: false,
+            }
+        );
+        assert!(!config_file.layers[0].cross_layer_deps);
+        assert!(config_file.layers[0].optional);
+
+        for invalid in [
+            r#"{"layers": []}"#,
+            r#"{"layers": [{"name": "a"}, {"name": "a"}]}"#,
+            r#"{"layers": [{"name": "a", "separater": "--"}]}"#,
+        ] {
+            fs::write(&path, invalid).unwrap();
+            assert!(matches!(
+                ConfigFile::read(&path),
+                Err(TopCatError::InvalidConfig(..))
+            ));
+        }
+    }
 }
@@ -0,0 +1,895 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::exceptions::{FileNodeError, TopCatError};
+use crate::file_dag::TCGraph;
+use crate::file_node::FileNode;
+use crate::header::{self, Directive, HeaderLocation};
+use crate::warning::{Level, Warning};
+
+/// How errors are reported.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MessageFormat {
+    /// rustc-style text on stderr.
+    Human,
+    /// A JSON object per line on stderr.
+    Json,
+    /// A SARIF 2.1.0 log on stderr.
+    Sarif,
+}
+
+impl FromStr for MessageFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "human" => Ok(Self::Human),
+            "json" => Ok(Self::Json),
+            "sarif" => Ok(Self::Sarif),
+            _ => Err(format!(
+                "unknown message format '{}', expected 'human', 'json' or 'sarif'",
+                s
+            )),
+        }
+    }
+}
+
+const DEPENDENCY_DIRECTIVES: &[Directive] = &[Directive::Requires, Directive::DroppedBy];
+
+/// A value in a header to underline, with a note shown next to it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Label {
+    pub location: HeaderLocation,
+    pub note: String,
+}
+
+/// An error rendered in the style of rustc: the message, the header lines involved with the
+/// offending values underlined, and a hint on how to fix it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    /// The code of the error, see [`TopCatError::code`], or of the warning.
+    pub code: &'static str,
+    /// `error`, or `warning` for warnings that aren't denied.
+    pub level: &'static str,
+    pub message: String,
+    pub labels: Vec<Label>,
+    /// Files the error is about as a whole, rather than a line of their header.
+    pub files: Vec<PathBuf>,
+    pub help: Option<String>,
+}
+
+impl Diagnostic {
+    fn new(message: impl Into<String>) -> Diagnostic {
+        Diagnostic {
+            code: "",
+            level: "error",
+            message: message.into(),
+            labels: Vec::new(),
+            files: Vec::new(),
+            help: None,
+        }
+    }
+
+    fn file(mut self, path: &Path) -> Diagnostic {
+        self.files.push(path.to_path_buf());
+        self
+    }
+
+    fn label(mut self, location: Option<&HeaderLocation>, note: impl Into<String>) -> Diagnostic {
+        if let Some(location) = location {
+            self.labels.push(Label {
+                location: location.clone(),
+                note: note.into(),
+            });
+        }
+        self
+    }
+
+    fn help(mut self, help: Option<String>) -> Diagnostic {
+        self.help = help;
+        self
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}: {}", self.level, self.message)?;
+        let width = self
+            .labels
+            .iter()
+            .map(|label| label.location.line.to_string().len())
+            .max()
+            .unwrap_or(0);
+        let gutter = " ".repeat(width);
+        for (i, label) in self.labels.iter().enumerate() {
+            let location = &label.location;
+            let arrow = if i == 0 { "-->" } else { ":::" };
+            writeln!(
+                f,
+                "{}{} {}:{}:{}",
+                gutter,
+                arrow,
+                location.path.display(),
+                location.line,
+                location.column
+            )?;
+            writeln!(f, "{} |", gutter)?;
+            writeln!(f, "{:>width$} | {}", location.line, location.text)?;
+            let underline = format!(
+                "{}{} {}",
+                " ".repeat(location.column - 1),
+                "^".repeat(location.len.max(1)),
+                label.note
+            );
+            writeln!(f, "{} | {}", gutter, underline.trim_end())?;
+        }
+        for path in &self.files {
+            writeln!(f, "{}--> {}", gutter, path.display())?;
+        }
+        if let Some(help) = &self.help {
+            if !self.labels.is_empty() {
+                writeln!(f, "{} |", gutter)?;
+            }
+            writeln!(f, "{} = help: {}", gutter, help)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+struct JsonLocation<'a> {
+    path: &'a Path,
+    line: Option<usize>,
+    column: Option<usize>,
+    end_column: Option<usize>,
+    note: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+struct JsonDiagnostic<'a> {
+    code: &'a str,
+    level: &'a str,
+    message: &'a str,
+    locations: Vec<JsonLocation<'a>>,
+    help: Option<&'a str>,
+}
+
+impl Diagnostic {
+    fn json_locations(&self) -> Vec<JsonLocation<'_>> {
+        let labels = self.labels.iter().map(|label| JsonLocation {
+            path: &label.location.path,
+            line: Some(label.location.line),
+            column: Some(label.location.column),
+            end_column: Some(label.location.column + label.location.len),
+            note: Some(label.note.as_str()).filter(|note| !note.is_empty()),
+        });
+        let files = self.files.iter().map(|path| JsonLocation {
+            path,
+            line: None,
+            column: None,
+            end_column: None,
+            note: None,
+        });
+        labels.chain(files).collect()
+    }
+
+    /// The diagnostic as a single line JSON object.
+    ///
+    /// The fields are `code`, `level`, `message`, `help` and `locations`, each with a `path`,
+    /// `line`, `column`, `end_column` and `note`. Columns count characters from 1 and
+    /// `end_column` is exclusive. Lines and columns are null for errors about a whole file.
+    pub fn to_json(&self) -> String {
+        let diagnostic = JsonDiagnostic {
+            code: self.code,
+            level: self.level,
+            message: &self.message,
+            locations: self.json_locations(),
+            help: self.help.as_deref(),
+        };
+        serde_json::to_string(&diagnostic).expect("diagnostics can always be serialised")
+    }
+}
+
+fn sarif_location(location: &JsonLocation) -> Value {
+    let mut physical_location = json!({
+        "artifactLocation": { "uri": location.path.display().to_string() },
+    });
+    if let (Some(line), Some(column), Some(end_column)) =
+        (location.line, location.column, location.end_column)
+    {
+        physical_location["region"] = json!({
+            "startLine": line,
+            "startColumn": column,
+            "endColumn": end_column,
+        });
+    }
+    let mut sarif_location = json!({ "physicalLocation": physical_location });
+    if let Some(note) = location.note {
+        sarif_location["message"] = json!({ "text": note });
+    }
+    sarif_location
+}
+
+/// The diagnostics as a SARIF 2.1.0 log, with the error and warning codes as rule ids.
+///
+/// The first location of a diagnostic is its primary location, the rest are related locations.
+pub fn to_sarif(diagnostics: &[Diagnostic]) -> String {
+    let mut rules: Vec<&str> = diagnostics.iter().map(|d| d.code).collect();
+    rules.sort_unstable();
+    rules.dedup();
+    let results: Vec<Value> = diagnostics
+        .iter()
+        .map(|diagnostic| {
+            let locations: Vec<Value> = diagnostic
+                .json_locations()
+                .iter()
+                .map(sarif_location)
+                .collect();
+            let text = match &diagnostic.help {
+                Some(help) => format!("{}\nhelp: {}", diagnostic.message, help),
+                None => diagnostic.message.clone(),
+            };
+            json!({
+                "ruleId": diagnostic.code,
+                "level": diagnostic.level,
+                "message": { "text": text },
+                "locations": locations.first().into_iter().collect::<Vec<_>>(),
+                "relatedLocations": locations.iter().skip(1).collect::<Vec<_>>(),
+            })
+        })
+        .collect();
+    let log = json!({
+        "$schema": "https://json.schemastore.org/sarif-2.1.0.json",
+        "version": "2.1.0",
+        "runs": [{
+            "tool": {
+                "driver": {
+                    "name": env!("CARGO_PKG_NAME"),
+                    "version": env!("CARGO_PKG_VERSION"),
+                    "informationUri": env!("CARGO_PKG_REPOSITORY"),
+                    "rules": rules.iter().map(|id| json!({ "id": id })).collect::<Vec<_>>(),
+                }
+            },
+            "results": results,
+        }],
+    });
+    serde_json::to_string_pretty(&log).expect("diagnostics can always be serialised")
+}
+
+/// The number of single character insertions, deletions and substitutions to turn `a` into `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, b_char) in b.iter().enumerate() {
+            let above = row[j + 1];
+            row[j + 1] = if a_char == *b_char {
+                diagonal
+            } else {
+                1 + diagonal.min(row[j]).min(above)
+            };
+            diagonal = above;
+        }
+    }
+    row[b.len()]
+}
+
+/// The candidate closest to `target`, if any is close enough to be a likely typo.
+pub fn suggest<'a>(target: &str, candidates: impl IntoIterator<Item = &'a str>) -> Option<&'a str> {
+    let max_distance = (target.chars().count() / 3).max(1);
+    candidates
+        .into_iter()
+        .map(|candidate| (edit_distance(target, candidate), candidate))
+        .filter(|(distance, _)| *distance > 0 && *distance <= max_distance)
+        .min()
+        .map(|(_, candidate)| candidate)
+}
+
+fn did_you_mean<'a>(target: &str, candidates: impl IntoIterator<Item = &'a str>) -> Option<String> {
+    suggest(target, candidates).map(|name| format!("did you mean `{}`?", name))
+}
+
+fn node_names(graph: Option<&TCGraph>) -> Vec<&str> {
+    graph
+        .map(|graph| {
+            graph
+                .nodes()
+                .into_iter()
+                .map(|node| node.name.as_str())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn node<'a>(graph: Option<&'a TCGraph>, name: &str) -> Option<&'a FileNode> {
+    graph.and_then(|graph| graph.node(name))
+}
+
+/// Find where `name` is declared in a file that isn't in the graph, by reading its header again.
+fn locate_name_in_file(graph: &TCGraph, path: &Path, name: &str) -> Option<HeaderLocation> {
+    header::read_header(path, &graph.comment_str)
+        .ok()?
+        .iter()
+        .flat_map(|line| line.located_values(path, &graph.comment_str))
+        .find(|located| located.directive == Directive::Name && located.value == name)
+        .map(|located| located.location)
+}
+
+/// Describe `error`, pointing at the header lines involved. The graph the error came from is used
+/// to find them and to suggest names, and can be left out for errors from outside a graph.
+pub fn from_error(error: &TopCatError, graph: Option<&TCGraph>) -> Diagnostic {
+    let diagnostic = match error {
+        TopCatError::MissingDependency(name, dep) => {
+            let location =
+                node(graph, name).and_then(|node| node.locate(DEPENDENCY_DIRECTIVES, dep));
+            Diagnostic::new(format!(
+                "`{}` requires `{}`, which doesn't exist",
+                name, dep
+            ))
+            .label(location, "no file has this name")
+            .help(did_you_mean(dep, node_names(graph)))
+        }
+        TopCatError::MissingExist(name, target) => {
+            let location =
+                node(graph, name).and_then(|node| node.locate(&[Directive::Exists], target));
+            Diagnostic::new(format!(
+                "`{}` expects `{}` to exist, but it doesn't",
+                name, target
+            ))
+            .label(location, "no file has this name")
+            .help(did_you_mean(target, node_names(graph)))
+        }
+        TopCatError::InvalidDependency(name, message) => {
+            let mut diagnostic = Diagnostic::new(message.clone());
+            if let (Some(graph), Some(file_node)) = (graph, node(graph, name)) {
+                let layer_index = |layer: &str| graph.layers().iter().position(|l| l == layer);
+                let mut later_deps: Vec<&FileNode> = file_node
+                    .deps
+                    .iter()
+                    .filter_map(|dep| graph.node(dep))
+                    .filter(|dep| layer_index(&dep.layer) > layer_index(&file_node.layer))
+                    .collect();
+                later_deps.sort();
+                if let Some(dep) = later_deps.first() {
+                    diagnostic = Diagnostic::new(format!(
+                        "`{}` in layer '{}' requires `{}` from the later layer '{}'",
+                        name, file_node.layer, dep.name, dep.layer
+                    ))
+                    .label(
+                        file_node.locate(DEPENDENCY_DIRECTIVES, &dep.name),
+                        format!("in layer '{}'", dep.layer),
+                    )
+                    .help(Some(
+                        "files can only require files in the same or an earlier layer".to_string(),
+                    ));
+                } else {
+                    let mut closed_deps: Vec<&FileNode> = file_node
+                        .deps
+                        .iter()
+                        .filter_map(|dep| graph.node(dep))
+                        .filter(|dep| {
+                            dep.layer != file_node.layer
+                                && !graph.allows_cross_layer_deps(&dep.layer)
+                        })
+                        .collect();
+                    closed_deps.sort();
+                    if let Some(dep) = closed_deps.first() {
+                        diagnostic = Diagnostic::new(format!(
+                            "`{}` in layer '{}' requires `{}` from the layer '{}', which doesn't allow dependencies from other layers",
+                            name, file_node.layer, dep.name, dep.layer
+                        ))
+                        .label(
+                            file_node.locate(DEPENDENCY_DIRECTIVES, &dep.name),
+                            format!("in layer '{}'", dep.layer),
+                        )
+                        .help(Some(format!(
+                            "set `cross_layer_deps` of '{}' to true in the config file, or move one of the files",
+                            dep.layer
+                        )));
+                    }
+                }
+            }
+            diagnostic
+        }
+        TopCatError::NameClash(name, path, other_path) => {
+            // Files are read in no particular order, so sort them to report the clash the same way
+            // on every run
+            let (path, other_path) = if path < other_path {
+                (other_path, path)
+            } else {
+                (path, other_path)
+            };
+            let first = graph.and_then(|graph| locate_name_in_file(graph, other_path, name));
+            let second = graph.and_then(|graph| locate_name_in_file(graph, path, name));
+            let diagnostic =
+                Diagnostic::new(format!("the name `{}` is used by more than one file", name));
+            let diagnostic = match (&first, &second) {
+                (Some(_), Some(_)) => diagnostic
+                    .label(first.as_ref(), "first used here")
+                    .label(second.as_ref(), "used again here"),
+                _ => diagnostic.file(other_path).file(path),
+            };
+            diagnostic.help(Some(
+                "names must be unique, rename one of the files".to_string(),
+            ))
+        }
+        TopCatError::CyclicDependency(cycles) => {
+            let mut diagnostic = Diagnostic::new("files require each other in a cycle");
+            for cycle in cycles {
+                for (i, a) in cycle.iter().enumerate() {
+                    let b = &cycle[(i + 1) % cycle.len()];
+                    let (from, to) = if a.deps.contains(&b.name) {
+                        (a, b)
+                    } else {
+                        (b, a)
+                    };
+                    // The nodes in the error are copies from the layer graph, so look up the
+                    // loaded node for its header locations
+                    let location = node(graph, &from.name)
+                        .and_then(|node| node.locate(DEPENDENCY_DIRECTIVES, &to.name));
+                    diagnostic = diagnostic
+                        .label(location, format!("`{}` requires `{}`", from.name, to.name));
+                }
+            }
+            diagnostic.help(Some(
+                "remove one of these requires to break the cycle, or use `exists` if the order doesn't matter"
+                    .to_string(),
+            ))
+        }
+        TopCatError::Header(e) => {
+            let (message, help) = match e {
+                FileNodeError::TooManyNames(_, names, _) => (
+                    format!("more than one name is declared: {}", names.join(", ")),
+                    Some("a file can only have one name".to_string()),
+                ),
+                FileNodeError::InvalidLayer(_, layer, _) => (
+                    format!("layer '{}' isn't one of the configured layers", layer),
+                    graph.map(|graph| {
+                        let layers = graph.layers().join(", ");
+                        match suggest(layer, graph.layers().iter().map(String::as_str)) {
+                            Some(suggestion) => {
+                                format!("did you mean `{}`? The layers are: {}", suggestion, layers)
+                            }
+                            None => format!("the layers are: {}", layers),
+                        }
+                    }),
+                ),
+                FileNodeError::NoNameDefined(_) => (e.to_string(), None),
+            };
+            let diagnostic = Diagnostic::new(message).help(help);
+            match (e.location(), e) {
+                (Some(location), _) => diagnostic.label(Some(location), ""),
+                (
+                    None,
+                    FileNodeError::TooManyNames(path, ..)
+                    | FileNodeError::InvalidLayer(path, ..)
+                    | FileNodeError::NoNameDefined(path),
+                ) => diagnostic.file(path),
+            }
+        }
+        TopCatError::NoNameDefined(paths) => {
+            let mut diagnostic = Diagnostic::new("no name is defined in the header of these files")
+                .help(Some(
+                    "add a `name` header, or use --name-from-path to name them after their path"
+                        .to_string(),
+                ));
+            for path in paths {
+                diagnostic = diagnostic.file(path);
+            }
+            diagnostic
+        }
+        TopCatError::InvalidFileHeader(path, _)
+        | TopCatError::FileExists(path)
+        | TopCatError::InvalidConfig(path, _)
+        | TopCatError::UnmappedLine(path, _) => Diagnostic::new(error.to_string()).file(path),
+        TopCatError::NodeNotFound(name) => {
+            Diagnostic::new(error.to_string()).help(did_you_mean(name, node_names(graph)))
+        }
+        TopCatError::OutOfDate(paths) => {
+            let mut diagnostic = Diagnostic::new("the output is out of date").help(Some(
+                "run the same command without --check to regenerate it".to_string(),
+            ));
+            for path in paths {
+                diagnostic = diagnostic.file(path);
+            }
+            diagnostic
+        }
+        _ => Diagnostic::new(error.to_string()),
+    };
+    Diagnostic {
+        code: error.code(),
+        ..diagnostic
+    }
+}
+
+/// Build the diagnostic reporting a warning, as an error if it is denied.
+pub fn from_warning(warning: &Warning) -> Diagnostic {
+    let mut diagnostic = Diagnostic::new(&warning.message);
+    if let Some(path) = &warning.path {
+        diagnostic = diagnostic.file(path);
+    }
+    let (level, help) = match warning.level {
+        Level::Deny => (
+            "error",
+            Some(format!(
+                "`{}` is denied by --deny or --deny-warnings",
+                warning.code
+            )),
+        ),
+        Level::Warn | Level::Allow => ("warning", None),
+    };
+    Diagnostic {
+        code: warning.code.as_str(),
+        level,
+        ..diagnostic.help(help)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::test_utils::{self, write_node};
+    use crate::warning::WarningCode;
+
+    #[test]
+    fn test_suggest() {
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        let names = ["my_schema.a", "my_schema.b", "other"];
+        assert_eq!(suggest("my_schem.a", names), Some("my_schema.a"));
+        assert_eq!(suggest("unrelated", names), None);
+    }
+
+    #[test]
+    fn test_from_warning() {
+        let warning = Warning {
+            code: WarningCode::NoName,
+            level: Level::Warn,
+            message: "Ignoring a.sql, it has no name header".to_string(),
+            path: Some(PathBuf::from("a.sql")),
+        };
+        assert_eq!(
+            from_warning(&warning).to_string(),
+            "warning: Ignoring a.sql, it has no name header\n--> a.sql\n"
+        );
+        let denied = from_warning(&Warning {
+            level: Level::Deny,
+            ..warning
+        });
+        assert_eq!(
+            denied.to_string(),
+            "error: Ignoring a.sql, it has no name header
+--> a.sql
+ = help: `no-name` is denied by --deny or --deny-warnings
+"
+        );
+        let json: Value = serde_json::from_str(&denied.to_json()).unwrap();
+        assert_eq!(json["code"], "no-name");
+        assert_eq!(json["level"], "error");
+    }
+
+    #[test]
+    fn test_missing_dependency() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        write_node(temp_dir.path(), "a.sql", "-- name: my_schema.a");
+        write_node(
+            temp_dir.path(),
+            "b.sql",
+            "-- name: my_schema.b\n-- requires: other,  my_schem.a",
+        );
+        write_node(temp_dir.path(), "c.sql", "-- name: other");
+        let mut graph = TCGraph::new(&test_utils::config(temp_dir.path()));
+        let error = graph.build_graph().unwrap_err();
+
+        assert_eq!(
+            from_error(&error, Some(&graph)).to_string(),
+            format!(
+                "error: `my_schema.b` requires `my_schem.a`, which doesn't exist
+ --> {}/b.sql:2:22
+  |
+2 | -- requires: other,  my_schem.a
+  |                      ^^^^^^^^^^ no file has this name
+  |
+  = help: did you mean `my_schema.a`?
+",
+                temp_dir.path().display()
+            )
+        );
+
+        let diagnostic = from_error(&error, Some(&graph));
+        let json: Value = serde_json::from_str(&diagnostic.to_json()).unwrap();
+        assert_eq!(
+            json,
+            json!({
+                "code": "missing-dependency",
+                "level": "error",
+                "message": "`my_schema.b` requires `my_schem.a`, which doesn't exist",
+                "locations": [{
+                    "path": temp_dir.path().join("b.sql"),
+                    "line": 2,
+                    "column": 22,
+                    "end_column": 32,
+                    "note": "no file has this name",
+                }],
+                "help": "did you mean `my_schema.a`?",
+            })
+        );
+
+        let sarif: Value = serde_json::from_str(&to_sarif(&[diagnostic])).unwrap();
+        let result = &sarif["runs"][0]["results"][0];
+        assert_eq!(result["ruleId"], "missing-dependency");
+        assert_eq!(
+            result["locations"][0]["physicalLocation"]["region"],
+            json!({ "startLine": 2, "startColumn": 22, "endColumn": 32 })
+        );
+    }
+
+    fn build_error(config: &Config) -> (TopCatError, TCGraph) {
+        let mut graph = TCGraph::new(config);
+        let error = graph.build_graph().unwrap_err();
+        (error, graph)
+    }
+
+    #[test]
+    fn test_invalid_layer() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path();
+        write_node(dir, "a.sql", "-- name: a\n-- layer: normall");
+        let (error, graph) = build_error(&test_utils::config(dir));
+
+        assert_eq!(
+            from_error(&error, Some(&graph)).to_string(),
+            format!(
+                "error: layer 'normall' isn't one of the configured layers
+ --> {}/a.sql:2:11
+  |
+2 | -- layer: normall
+  |           ^^^^^^^
+  |
+  = help: did you mean `normal`? The layers are: normal
+",
+                dir.display()
+            )
+        );
+
+        write_node(dir, "a.sql", "-- name: a\n-- layer: seed");
+        let (error, graph) = build_error(&test_utils::config(dir));
+        assert_eq!(
+            from_error(&error, Some(&graph)).help.as_deref(),
+            Some("the layers are: normal")
+        );
+    }
+
+    #[test]
+    fn test_dependency_on_other_layer() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path();
+        write_node(
+            dir,
+            "a.sql",
+            "-- name: a\n-- layer: prepend\n-- requires: b",
+        );
+        write_node(dir, "b.sql", "-- name: b");
+        let mut config = Config {
+            layers: vec!["prepend".to_string(), "normal".to_string()],
+            ..test_utils::config(dir)
+        };
+        let (error, graph) = build_error(&config);
+
+        assert_eq!(
+            from_error(&error, Some(&graph)).to_string(),
+            format!(
+                "error: `a` in layer 'prepend' requires `b` from the later layer 'normal'
+ --> {}/a.sql:3:14
+  |
+3 | -- requires: b
+  |              ^ in layer 'normal'
+  |
+  = help: files can only require files in the same or an earlier layer
+",
+                dir.display()
+            )
+        );
+
+        write_node(dir, "a.sql", "-- name: a\n-- layer: prepend");
+        write_node(dir, "b.sql", "-- name: b\n-- requires: a");
+        let layer = |name: &str, cross_layer_deps: bool| crate::config::Layer {
+            name: name.to_string(),
+            separator: None,
+            suffix: None,
+            preamble: None,
+            postamble: None,
+            cross_layer_deps,
+            optional: false,
+        };
+        config.layer_definitions = vec![layer("prepend", false), layer("normal", true)];
+        let (error, graph) = build_error(&config);
+        let diagnostic = from_error(&error, Some(&graph));
+        assert_eq!(
+            diagnostic.message,
+            "`b` in layer 'normal' requires `a` from the layer 'prepend', which doesn't allow dependencies from other layers"
+        );
+        assert_eq!(diagnostic.labels[0].location.line, 2);
+        assert_eq!(
+            diagnostic.help.as_deref(),
+            Some("set `cross_layer_deps` of 'prepend' to true in the config file, or move one of the files")
+        );
+    }
+
+    #[test]
+    fn test_name_clash() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path();
+        write_node(dir, "a.sql", "-- name: a");
+        write_node(dir, "b.sql", "-- A comment\n-- name: a");
+        let (error, graph) = build_error(&test_utils::config(dir));
+
+        assert_eq!(
+            from_error(&error, Some(&graph)).to_string(),
+            format!(
+                "error: the name `a` is used by more than one file
+ --> {dir}/a.sql:1:10
+  |
+1 | -- name: a
+  |          ^ first used here
+ ::: {dir}/b.sql:2:10
+  |
+2 | -- name: a
+  |          ^ used again here
+  |
+  = help: names must be unique, rename one of the files
+",
+                dir = dir.display()
+            )
+        );
+    }
+
+    #[test]
+    fn test_cycle() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path();
+        write_node(dir, "a.sql", "-- name: a\n-- requires: c");
+        write_node(dir, "b.sql", "-- name: b\n-- requires: a");
+        write_node(dir, "c.sql", "-- name: c\n-- requires: b");
+        write_node(dir, "d.sql", "-- name: d\n-- requires: a");
+        let (error, graph) = build_error(&test_utils::config(dir));
+
+        let diagnostic = from_error(&error, Some(&graph));
+        assert_eq!(diagnostic.message, "files require each other in a cycle");
+        let mut labels: Vec<(PathBuf, usize, usize, &str)> = diagnostic
+            .labels
+            .iter()
+            .map(|label| {
+                let location = &label.location;
+                (
+                    location.path.clone(),
+                    location.line,
+                    location.column,
+                    label.note.as_str(),
+                )
+            })
+            .collect();
+        labels.sort();
+        assert_eq!(
+            labels,
+            vec![
+                (dir.join("a.sql"), 2, 14, "`a` requires `c`"),
+                (dir.join("b.sql"), 2, 14, "`b` requires `a`"),
+                (dir.join("c.sql"), 2, 14, "`c` requires `b`"),
+            ]
+        );
+        assert_eq!(
+            diagnostic.help.as_deref(),
+            Some("remove one of these requires to break the cycle, or use `exists` if the order doesn't matter")
+        );
+    }
+
+    #[test]
+    fn test_json_and_sarif() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path();
+        let json_of = |config: &Config| {
+            let (error, graph) = build_error(config);
+            let diagnostic = from_error(&error, Some(&graph));
+            let json: Value = serde_json::from_str(&diagnostic.to_json()).unwrap();
+            let sarif: Value = serde_json::from_str(&to_sarif(&[diagnostic])).unwrap();
+            (json, sarif["runs"][0]["results"][0].clone())
+        };
+        let region = |line: usize, column: usize, end_column: usize| json!({ "startLine": line, "startColumn": column, "endColumn": end_column });
+
+        write_node(dir, "a.sql", "-- name: a\n-- layer: normall");
+        let (json, result) = json_of(&test_utils::config(dir));
+        assert_eq!(
+            json,
+            json!({
+                "code": "invalid-layer",
+                "level": "error",
+                "message": "layer 'normall' isn't one of the configured layers",
+                "locations": [{
+                    "path": dir.join("a.sql"),
+                    "line": 2,
+                    "column": 11,
+                    "end_column": 18,
+                    "note": null,
+                }],
+                "help": "did you mean `normal`? The layers are: normal",
+            })
+        );
+        assert_eq!(result["ruleId"], "invalid-layer");
+        assert_eq!(
+            result["locations"][0]["physicalLocation"]["region"],
+            region(2, 11, 18)
+        );
+        assert_eq!(
+            result["message"]["text"],
+            "layer 'normall' isn't one of the configured layers\nhelp: did you mean `normal`? The layers are: normal"
+        );
+
+        write_node(dir, "a.sql", "-- name: a");
+        write_node(dir, "b.sql", "-- name: a");
+        let (json, result) = json_of(&test_utils::config(dir));
+        assert_eq!(json["code"], "name-clash");
+        let locations = json["locations"].as_array().unwrap();
+        assert_eq!(locations.len(), 2);
+        assert_eq!(locations[0]["path"], json!(dir.join("a.sql")));
+        assert_eq!(locations[0]["note"], "first used here");
+        assert_eq!(locations[1]["path"], json!(dir.join("b.sql")));
+        assert_eq!(locations[1]["note"], "used again here");
+        assert_eq!(result["locations"].as_array().unwrap().len(), 1);
+        assert_eq!(
+            result["relatedLocations"][0]["physicalLocation"]["artifactLocation"]["uri"],
+            dir.join("b.sql").display().to_string()
+        );
+        assert_eq!(
+            result["relatedLocations"][0]["message"]["text"],
+            "used again here"
+        );
+
+        write_node(dir, "a.sql", "-- name: a\n-- requires: b");
+        write_node(dir, "b.sql", "-- name: b\n-- requires: a");
+        let (json, result) = json_of(&test_utils::config(dir));
+        assert_eq!(json["code"], "cyclic-dependency");
+        let mut notes: Vec<&str> = json["locations"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|location| location["note"].as_str().unwrap())
+            .collect();
+        notes.sort();
+        assert_eq!(notes, vec!["`a` requires `b`", "`b` requires `a`"]);
+        assert_eq!(result["ruleId"], "cyclic-dependency");
+        assert_eq!(
+            result["locations"][0]["physicalLocation"]["region"],
+            region(2, 14, 15)
+        );
+        assert_eq!(result["relatedLocations"].as_array().unwrap().len(), 1);
+
+        // Errors about a whole file have no line or column
+        std::fs::write(dir.join("b.sql"), "SELECT 1;\n").unwrap();
+        let (json, result) = json_of(&Config {
+            require_name: true,
+            ..test_utils::config(dir)
+        });
+        assert_eq!(json["code"], "no-name-defined");
+        assert_eq!(
+            json["locations"],
+            json!([{
+                "path": dir.join("b.sql"),
+                "line": null,
+                "column": null,
+                "end_column": null,
+                "note": null,
+            }])
+        );
+        assert_eq!(
+            result["locations"][0]["physicalLocation"],
+            json!({ "artifactLocation": { "uri": dir.join("b.sql").display().to_string() } })
+        );
+    }
+}
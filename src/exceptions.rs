@@ -1,19 +1,34 @@
 use crate::file_node::FileNode;
+use crate::header::HeaderLocation;
 use std::collections::HashSet;
 use std::error::Error;
 use std::path::PathBuf;
+use std::process::ExitCode;
 use std::{fmt, io};
 
 #[derive(Debug)]
 pub enum TopCatError {
     Io(io::Error),
     InvalidFileHeader(PathBuf, String),
+    /// A problem with a file's header found while reading it.
+    Header(FileNodeError),
     GraphMissing,
     NameClash(String, PathBuf, PathBuf),
+    NoNameDefined(Vec<PathBuf>),
     MissingExist(String, String),
     MissingDependency(String, String),
     InvalidDependency(String, String),
     CyclicDependency(Vec<Vec<FileNode>>),
+    InvalidTemplate(String, String),
+    InvalidConfig(PathBuf, String),
+    /// Command line options that are missing or can't be used together.
+    InvalidArguments(String),
+    UnmappedLine(PathBuf, usize),
+    NodeNotFound(String),
+    NoDependencyPath(String, String),
+    FileExists(PathBuf),
+    /// `--check` found output files that don't match what would be generated.
+    OutOfDate(Vec<PathBuf>),
     UnknownError(String),
 }
 
@@ -24,6 +39,7 @@ impl fmt::Display for TopCatError {
             Self::InvalidFileHeader(x, s) => {
                 write!(f, "Invalid file header in {}: {}", x.display(), s)
             }
+            Self::Header(e) => write!(f, "{}", e),
             Self::NameClash(name, f1, f2) => write!(
                 f,
                 "Name {} found in both {} and {}",
@@ -31,6 +47,13 @@ impl fmt::Display for TopCatError {
                 f1.display(),
                 f2.display()
             ),
+            Self::NoNameDefined(paths) => {
+                writeln!(f, "No name defined in the file header of:")?;
+                for path in paths {
+                    writeln!(f, "  - {}", path.display())?;
+                }
+                Ok(())
+            }
             Self::MissingExist(x, s) => write!(
                 f,
                 "MissingExist: {} expects {} to exist but it is not found",
@@ -65,12 +88,113 @@ impl fmt::Display for TopCatError {
 
                 write!(f, "{}", error_message)
             }
+            Self::InvalidTemplate(template, s) => {
+                write!(f, "Invalid template '{}': {}", template, s)
+            }
+            Self::UnmappedLine(x, line) => write!(
+                f,
+                "Line {} of {} was generated by topcat and doesn't come from an input file",
+                line,
+                x.display()
+            ),
+            Self::NodeNotFound(name) => write!(f, "No file named '{}' was found", name),
+            Self::NoDependencyPath(from, to) => write!(
+                f,
+                "Neither {} nor {} requires the other, directly or indirectly",
+                from, to
+            ),
+            Self::FileExists(x) => write!(f, "{} already exists, not overwriting it", x.display()),
+            Self::InvalidConfig(x, s) => write!(f, "Invalid config {}: {}", x.display(), s),
+            Self::InvalidArguments(s) => write!(f, "{}", s),
+            Self::OutOfDate(paths) => {
+                writeln!(f, "The output is out of date:")?;
+                for path in paths {
+                    writeln!(f, "  - {}", path.display())?;
+                }
+                Ok(())
+            }
             Self::Io(err) => write!(f, "IO error: {}", err),
             Self::UnknownError(s) => write!(f, "UnknownError: {}", s),
         }
     }
 }
 
+impl TopCatError {
+    /// A short name for the kind of error, which is stable across releases for tools to match on.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Io(_) => "io",
+            Self::InvalidFileHeader(..) => "invalid-file-header",
+            Self::Header(e) => e.code(),
+            Self::GraphMissing => "graph-missing",
+            Self::NameClash(..) => "name-clash",
+            Self::NoNameDefined(_) => "no-name-defined",
+            Self::MissingExist(..) => "missing-exist",
+            Self::MissingDependency(..) => "missing-dependency",
+            Self::InvalidDependency(..) => "invalid-dependency",
+            Self::CyclicDependency(_) => "cyclic-dependency",
+            Self::InvalidTemplate(..) => "invalid-template",
+            Self::InvalidConfig(..) => "invalid-config",
+            Self::InvalidArguments(_) => "invalid-arguments",
+            Self::UnmappedLine(..) => "unmapped-line",
+            Self::NodeNotFound(_) => "node-not-found",
+            Self::NoDependencyPath(..) => "no-dependency-path",
+            Self::FileExists(_) => "file-exists",
+            Self::OutOfDate(_) => "out-of-date",
+            Self::UnknownError(_) => "unknown",
+        }
+    }
+
+    pub fn class(&self) -> ErrorClass {
+        match self {
+            Self::InvalidTemplate(..)
+            | Self::InvalidConfig(..)
+            | Self::InvalidArguments(_)
+            | Self::NodeNotFound(_)
+            | Self::NoDependencyPath(..)
+            | Self::UnmappedLine(..) => ErrorClass::Config,
+            Self::InvalidFileHeader(..)
+            | Self::Header(_)
+            | Self::NameClash(..)
+            | Self::NoNameDefined(_) => ErrorClass::Header,
+            Self::MissingExist(..) | Self::MissingDependency(..) | Self::InvalidDependency(..) => {
+                ErrorClass::Dependency
+            }
+            Self::CyclicDependency(_) => ErrorClass::Cycle,
+            Self::Io(_) | Self::FileExists(_) => ErrorClass::Io,
+            Self::OutOfDate(_) => ErrorClass::OutOfDate,
+            Self::GraphMissing | Self::UnknownError(_) => ErrorClass::Failure,
+        }
+    }
+}
+
+/// What kind of failure stopped topcat, which decides its exit code.
+///
+/// The exit codes are documented in the README and are stable across releases.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorClass {
+    /// Anything not covered by the other classes.
+    Failure = 1,
+    /// Bad command line arguments or configuration.
+    Config = 2,
+    /// A file header that can't be read, or names that clash.
+    Header = 3,
+    /// A dependency that doesn't exist or is in a later layer.
+    Dependency = 4,
+    Cycle = 5,
+    Io = 6,
+    /// `--check` or `fmt --check` found files to change.
+    OutOfDate = 7,
+    /// A warning denied by `--deny` or `--deny-warnings` was emitted.
+    DeniedWarning = 8,
+}
+
+impl From<ErrorClass> for ExitCode {
+    fn from(class: ErrorClass) -> ExitCode {
+        ExitCode::from(class as u8)
+    }
+}
+
 impl From<io::Error> for TopCatError {
     fn from(err: io::Error) -> TopCatError {
         TopCatError::Io(err)
@@ -81,22 +205,43 @@ impl Error for TopCatError {}
 
 #[derive(Debug)]
 pub enum FileNodeError {
-    TooManyNames(PathBuf, Vec<String>),
+    TooManyNames(PathBuf, Vec<String>, Option<Box<HeaderLocation>>),
     NoNameDefined(PathBuf),
-    InvalidLayer(PathBuf, String),
+    InvalidLayer(PathBuf, String, Option<Box<HeaderLocation>>),
+}
+
+impl FileNodeError {
+    /// A short name for the kind of error, which is stable across releases for tools to match on.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::TooManyNames(..) => "too-many-names",
+            Self::NoNameDefined(_) => "no-name-defined",
+            Self::InvalidLayer(..) => "invalid-layer",
+        }
+    }
+
+    /// Where in the header the problem is, when it's on a single line.
+    pub fn location(&self) -> Option<&HeaderLocation> {
+        match self {
+            Self::TooManyNames(_, _, location) | Self::InvalidLayer(_, _, location) => {
+                location.as_deref()
+            }
+            Self::NoNameDefined(_) => None,
+        }
+    }
 }
 
 impl fmt::Display for FileNodeError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            Self::TooManyNames(x, s) => write!(
+            Self::TooManyNames(x, s, _) => write!(
                 f,
                 "Too many names declared in {}: {}",
                 x.display(),
                 s.join(", ")
             ),
             Self::NoNameDefined(x) => write!(f, "No name defined in {}", x.display()),
-            Self::InvalidLayer(x, layer) => {
+            Self::InvalidLayer(x, layer, _) => {
                 write!(f, "Invalid layer '{}' declared in {}", layer, x.display())
             }
         }
@@ -0,0 +1,237 @@
+use std::io::Write;
+
+use crate::exceptions::TopCatError;
+use crate::file_dag::TCGraph;
+use crate::file_node::FileNode;
+
+fn describe_chain(chain: &[&FileNode]) -> String {
+    chain
+        .iter()
+        .map(|node| node.name.as_str())
+        .collect::<Vec<_>>()
+        .join(" requires ")
+}
+
+/// Explain what puts `name` after each of the files before it in the output.
+///
+/// A file comes after another because it's in a later layer or because it requires it, directly
+/// or through other files. Anything else before it is only there because of the stable ordering.
+pub fn why(graph: &TCGraph, name: &str, writer: &mut dyn Write) -> Result<(), TopCatError> {
+    let file_node = graph
+        .node(name)
+        .ok_or_else(|| TopCatError::NodeNotFound(name.to_string()))?;
+    let sorted_nodes = graph.get_sorted_nodes()?;
+    let Some(position) = sorted_nodes.iter().position(|node| node.name == name) else {
+        writeln!(
+            writer,
+            "{} is not in the output, run `topcat why-included {}` to see why",
+            name, name
+        )?;
+        return Ok(());
+    };
+
+    writeln!(
+        writer,
+        "{} ({}) is file {} of {} in the output, in layer '{}'",
+        name,
+        file_node.path.display(),
+        position + 1,
+        sorted_nodes.len(),
+        file_node.layer
+    )?;
+    if position == 0 {
+        writeln!(writer, "  nothing comes before it")?;
+    }
+    for before in &sorted_nodes[..position] {
+        let reason = if before.layer != file_node.layer {
+            format!(
+                "layer '{}' comes before layer '{}'",
+                before.layer, file_node.layer
+            )
+        } else if let Some(chain) = graph.dependency_path([name], &before.name) {
+            describe_chain(&chain)
+        } else {
+            "not required, placed earlier by the stable ordering".to_string()
+        };
+        writeln!(writer, "  after {}: {}", before.name, reason)?;
+    }
+    Ok(())
+}
+
+/// Explain which filters selected `name` for the output, or left it out.
+pub fn why_included(
+    graph: &TCGraph,
+    name: &str,
+    writer: &mut dyn Write,
+) -> Result<(), TopCatError> {
+    let file_node = graph
+        .node(name)
+        .ok_or_else(|| TopCatError::NodeNotFound(name.to_string()))?;
+
+    let mut reasons = Vec::new();
+    let is_filtered = graph.is_optional_layer(&file_node.layer);
+    let selected = match graph.select_nodes()? {
+        // The filters don't apply to layers that aren't optional
+        _ if !is_filtered => {
+            reasons.push(format!("layer '{}' is not optional", file_node.layer));
+            true
+        }
+        None => {
+            reasons.push(
+                "no --subdir-filter, --include-tag or --exclude-tag is given, so every file is selected"
+                    .to_string(),
+            );
+            true
+        }
+        Some(initial_nodes) if initial_nodes.contains(name) => {
+            if let Some(subdir) = graph.subdir_filter() {
+                reasons.push(format!("it is in --subdir-filter {}", subdir.display()));
+            }
+            for expr in graph.include_tags.iter().flatten() {
+                if expr.matches(&file_node.tags) {
+                    reasons.push(format!("it matches --include-tag '{}'", expr));
+                }
+            }
+            if graph.exclude_tags.is_some() {
+                reasons.push("it doesn't match any --exclude-tag".to_string());
+            }
+            true
+        }
+        Some(initial_nodes) => {
+            match graph.dependency_path(initial_nodes.iter().map(String::as_str), name) {
+                Some(chain) => {
+                    reasons.push(format!(
+                        "it is required by {}, which is selected: {}",
+                        chain[0].name,
+                        describe_chain(&chain)
+                    ));
+                    true
+                }
+                None => {
+                    if let (Some(subdir), Some(false)) =
+                        (graph.subdir_filter(), graph.in_subdir_filter(file_node)?)
+                    {
+                        reasons.push(format!("it is not in --subdir-filter {}", subdir.display()));
+                    }
+                    if let Some(include) = &graph.include_tags {
+                        if !include.iter().any(|expr| expr.matches(&file_node.tags)) {
+                            reasons.push("it doesn't match any --include-tag".to_string());
+                        }
+                    }
+                    for expr in graph.exclude_tags.iter().flatten() {
+                        if expr.matches(&file_node.tags) {
+                            reasons.push(format!("it matches --exclude-tag '{}'", expr));
+                        }
+                    }
+                    reasons.push("no selected file requires it".to_string());
+                    false
+                }
+            }
+        }
+    };
+
+    let passes_prefix_filters = !is_filtered || graph.passes_prefix_filters(file_node);
+    if !passes_prefix_filters {
+        reasons.push("it is excluded by --include-prefix or --exclude-prefix".to_string());
+    }
+    let passes_metadata_filters = !is_filtered || graph.passes_metadata_filters(file_node);
+    if !passes_metadata_filters {
+        reasons.push("it doesn't match every --where filter".to_string());
+    }
+
+    let included = selected && passes_prefix_filters && passes_metadata_filters;
+    writeln!(
+        writer,
+        "{} ({}) is {}in the output",
+        name,
+        file_node.path.display(),
+        if included { "" } else { "not " }
+    )?;
+    for reason in reasons {
+        writeln!(writer, "  {}", reason)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::test_utils::{self, write_node};
+
+    #[test]
+    fn test_why_included() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        write_node(temp_dir.path(), "a.sql", "-- name: a");
+        write_node(temp_dir.path(), "b.sql", "-- name: b\n-- requires: a");
+        write_node(
+            temp_dir.path(),
+            "c.sql",
+            "-- name: c\n-- requires: b\n-- tags: seed",
+        );
+        write_node(temp_dir.path(), "d.sql", "-- name: d");
+
+        let include_tags = vec!["seed".parse().unwrap()];
+        let config = Config {
+            include_tags: Some(&include_tags),
+            ..test_utils::config(temp_dir.path())
+        };
+        let graph = test_utils::build_graph(&config);
+
+        let explain = |name: &str| {
+            let mut out = Vec::new();
+            why_included(&graph, name, &mut out).unwrap();
+            let out = String::from_utf8(out).unwrap();
+            out.lines().skip(1).collect::<Vec<_>>().join("\n")
+        };
+        assert_eq!(explain("c"), "  it matches --include-tag 'seed'");
+        assert_eq!(
+            explain("a"),
+            "  it is required by c, which is selected: c requires b requires a"
+        );
+        assert_eq!(
+            explain("d"),
+            "  it doesn't match any --include-tag\n  no selected file requires it"
+        );
+        assert!(matches!(
+            why_included(&graph, "e", &mut Vec::new()),
+            Err(TopCatError::NodeNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_why_included_non_optional_layer() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        write_node(temp_dir.path(), "a.sql", "-- name: a\n-- tags: seed");
+        write_node(temp_dir.path(), "b.sql", "-- name: b\n-- layer: append");
+
+        let layer = |name: &str, optional: bool| crate::config::Layer {
+            name: name.to_string(),
+            separator: None,
+            suffix: None,
+            preamble: None,
+            postamble: None,
+            cross_layer_deps: true,
+            optional,
+        };
+        let include_tags = vec!["seed".parse().unwrap()];
+        let prefixes = vec!["a".to_string()];
+        let config = Config {
+            layers: vec!["normal".to_string(), "append".to_string()],
+            layer_definitions: vec![layer("normal", true), layer("append", false)],
+            include_tags: Some(&include_tags),
+            include_node_prefixes: Some(&prefixes),
+            ..test_utils::config(temp_dir.path())
+        };
+        let graph = test_utils::build_graph(&config);
+
+        let mut out = Vec::new();
+        why_included(&graph, "b", &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.lines().next().unwrap().ends_with(" is in the output"));
+        assert_eq!(
+            out.lines().skip(1).collect::<Vec<_>>(),
+            vec!["  layer 'append' is not optional"]
+        );
+    }
+}
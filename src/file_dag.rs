@@ -1,11 +1,10 @@
 use graph_cycles::Cycles;
 use std::collections::{HashMap, HashSet, VecDeque};
 use std::hash::Hash;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use log::{debug, info, trace};
 use petgraph::algo::is_cyclic_directed;
-use petgraph::dot::{Config, Dot};
 use petgraph::graph::DiGraph;
 use petgraph::graph::NodeIndex;
 use petgraph::{Directed, Graph};
@@ -13,13 +12,24 @@ use petgraph::{Directed, Graph};
 use crate::exceptions::{FileNodeError, TopCatError};
 use crate::file_node::FileNode;
 use crate::stable_topo::StableTopo;
-use crate::{config, io_utils};
+use crate::tag_expr::TagExpr;
+use crate::warning::{self, WarningCode};
+use crate::{config, infer, io_utils};
+
+/// The files matching the glob patterns, warning about invalid patterns instead of failing.
+fn glob_files(patterns: &[String]) -> HashSet<PathBuf> {
+    io_utils::glob_files(patterns).unwrap_or_else(|e| {
+        warning::emit(
+            WarningCode::GlobError,
+            None,
+            format!("Ignoring the glob patterns, one is invalid: {}", e),
+        );
+        HashSet::new()
+    })
+}
 
 fn string_slice_to_array<T: Hash + Eq + Clone>(option: Option<&[T]>) -> Option<HashSet<T>> {
-    match option {
-        Some(arr) => Some(arr.iter().cloned().collect()),
-        None => None,
-    }
+    option.map(|arr| arr.iter().cloned().collect())
 }
 
 fn collect_files(
@@ -80,13 +90,13 @@ fn filter_files<'a>(
             }
         }
         if let Some(ref include) = include_file_set {
-            if !include.is_empty() && !include.contains::<PathBuf>(&*path) {
+            if !include.is_empty() && !include.contains::<PathBuf>(*path) {
                 debug!("Excluding file as it isn't in the include set: {:?}", path);
                 return false;
             }
         }
         if let Some(ref exclude) = exclude_file_set {
-            if !exclude.is_empty() && exclude.contains::<PathBuf>(&*path) {
+            if !exclude.is_empty() && exclude.contains::<PathBuf>(*path) {
                 debug!("Excluding file as it is in the exclude set: {:?}", path);
                 return false;
             }
@@ -95,21 +105,42 @@ fn filter_files<'a>(
     })
 }
 
-fn handle_file_node_error(e: FileNodeError) -> Result<(), TopCatError> {
-    return match e {
+/// Derive a node name from a file's path relative to the input directory containing it.
+///
+/// Directory separators become `.` and the file extension is dropped, so
+/// `my_schema/functions/a.sql` becomes `my_schema.functions.a`. The name is lowercased to
+/// match the names parsed from file headers.
+fn derive_name_from_path(path: &Path, input_dirs: &[PathBuf]) -> Option<String> {
+    let relative = io_utils::relative_to_input_dir(path, input_dirs)?;
+    let mut parts: Vec<String> = relative
+        .parent()
+        .map(|parent| {
+            parent
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy().to_string())
+                .collect()
+        })
+        .unwrap_or_default();
+    parts.push(relative.file_stem()?.to_string_lossy().to_string());
+    Some(parts.join(".").to_lowercase())
+}
+
+fn handle_file_node_error(
+    e: FileNodeError,
+    unnamed_files: &mut Vec<PathBuf>,
+) -> Result<(), TopCatError> {
+    match e {
         FileNodeError::NoNameDefined(p) => {
-            info!("Ignoring {:?}: No name defined in file header", p);
+            warning::emit(
+                WarningCode::NoName,
+                Some(&p),
+                format!("Ignoring {}, it has no name header", p.display()),
+            );
+            unnamed_files.push(p);
             Ok(())
         }
-        FileNodeError::TooManyNames(p, s) => Err(TopCatError::InvalidFileHeader(
-            p,
-            format!("Too many names declared: {}", s.join(", ")),
-        )),
-        FileNodeError::InvalidLayer(p, layer) => Err(TopCatError::InvalidFileHeader(
-            p,
-            format!("Invalid layer '{}' declared", layer),
-        )),
-    };
+        e => Err(TopCatError::Header(e)),
+    }
 }
 
 fn add_nodes_to_graphs(
@@ -131,19 +162,57 @@ fn add_nodes_to_graphs(
     }
 }
 
+/// The layers that don't allow dependencies from other layers.
+fn closed_layers(layer_definitions: &[config::Layer]) -> HashSet<&str> {
+    layer_definitions
+        .iter()
+        .filter(|layer| !layer.cross_layer_deps)
+        .map(|layer| layer.name.as_str())
+        .collect()
+}
+
+/// Check that the file `name` in `layer` may require `dep_node`: it has to be in the same or an
+/// earlier layer, and in a layer allowing dependencies from other layers unless it's the same one.
+fn check_layer_dependency(
+    name: &str,
+    layer: &str,
+    dep_node: &FileNode,
+    layers: &[String],
+    closed_layers: &HashSet<&str>,
+) -> Result<(), TopCatError> {
+    let layer_index = |layer: &str| layers.iter().position(|l| l == layer).unwrap();
+    let file_layer_idx = layer_index(layer);
+    let dep_layer_idx = layer_index(&dep_node.layer);
+
+    // Enforce layer ordering: lower index layers cannot depend on higher index layers
+    if file_layer_idx < dep_layer_idx {
+        return Err(TopCatError::InvalidDependency(
+            name.to_string(),
+            format!(
+                "Node in layer '{}' (index {}) cannot depend on node '{}' in layer '{}' (index {})",
+                layer, file_layer_idx, dep_node.name, dep_node.layer, dep_layer_idx
+            ),
+        ));
+    }
+    if layer != dep_node.layer && closed_layers.contains(dep_node.layer.as_str()) {
+        return Err(TopCatError::InvalidDependency(
+            name.to_string(),
+            format!(
+                "Node in layer '{}' cannot depend on node '{}' in layer '{}', which doesn't allow dependencies from other layers",
+                layer, dep_node.name, dep_node.layer
+            ),
+        ));
+    }
+    Ok(())
+}
+
 fn validate_dependencies(
     name_map: &HashMap<String, FileNode>,
     layer_graphs: &mut HashMap<String, DiGraph<FileNode, ()>>,
     layer_index_maps: &HashMap<String, HashMap<String, NodeIndex>>,
     layers: &[String],
+    closed_layers: &HashSet<&str>,
 ) -> Result<(), TopCatError> {
-    // Create a map from layer name to its index for dependency validation
-    let layer_indices: HashMap<String, usize> = layers
-        .iter()
-        .enumerate()
-        .map(|(i, layer)| (layer.clone(), i))
-        .collect();
-
     for file_node in name_map.values() {
         for ensure in &file_node.ensure_exists {
             if !name_map.contains_key(ensure) {
@@ -159,19 +228,13 @@ fn validate_dependencies(
                 TopCatError::MissingDependency(file_node.name.clone(), dep.clone())
             })?;
 
-            let file_layer_idx = layer_indices.get(&file_node.layer).unwrap();
-            let dep_layer_idx = layer_indices.get(&dep_node.layer).unwrap();
-
-            // Enforce layer ordering: lower index layers cannot depend on higher index layers
-            if file_layer_idx < dep_layer_idx {
-                return Err(TopCatError::InvalidDependency(
-                    file_node.name.clone(),
-                    format!(
-                        "Node in layer '{}' (index {}) cannot depend on node '{}' in layer '{}' (index {})",
-                        file_node.layer, file_layer_idx, dep.clone(), dep_node.layer, dep_layer_idx
-                    ),
-                ));
-            }
+            check_layer_dependency(
+                &file_node.name,
+                &file_node.layer,
+                dep_node,
+                layers,
+                closed_layers,
+            )?;
 
             // Only add edges within the same layer
             if file_node.layer == dep_node.layer {
@@ -224,6 +287,12 @@ fn check_cyclic_dependencies(
     Ok(())
 }
 
+fn is_in_dir(path: &Path, canonical_dir: &Path) -> bool {
+    path.canonicalize()
+        .map(|canonical_path| canonical_path.starts_with(canonical_dir))
+        .unwrap_or(false)
+}
+
 /// Represents a graph structure for a set of files and their dependencies.
 pub struct TCGraph {
     pub comment_str: String,
@@ -234,25 +303,29 @@ pub struct TCGraph {
     pub exclude_extensions: Option<HashSet<String>>,
     pub include_node_prefixes: Option<HashSet<String>>,
     pub exclude_node_prefixes: Option<HashSet<String>>,
+    pub metadata_filters: Option<Vec<(String, String)>>,
+    pub include_tags: Option<Vec<TagExpr>>,
+    pub exclude_tags: Option<Vec<TagExpr>>,
     layer_graphs: HashMap<String, DiGraph<FileNode, ()>>,
     layer_index_maps: HashMap<String, HashMap<String, NodeIndex>>,
     layers: Vec<String>,
+    layer_definitions: Vec<config::Layer>,
     fallback_layer: String,
     path_map: HashMap<PathBuf, FileNode>,
     name_map: HashMap<String, FileNode>,
+    unnamed_files: Vec<PathBuf>,
     include_hidden: bool,
+    name_from_path: bool,
+    require_name: bool,
+    infer_deps: bool,
     graph_is_built: bool,
     subdir_filter: Option<PathBuf>,
 }
 
 impl TCGraph {
     pub fn new(config: &config::Config) -> TCGraph {
-        let include_globs = config
-            .include_globs
-            .map(|patterns| io_utils::glob_files(patterns).unwrap_or_default());
-        let exclude_globs = config
-            .exclude_globs
-            .map(|patterns| io_utils::glob_files(patterns).unwrap_or_default());
+        let include_globs = config.include_globs.map(glob_files);
+        let exclude_globs = config.exclude_globs.map(glob_files);
         let include_extensions: Option<HashSet<String>> =
             string_slice_to_array(config.include_extensions);
         let exclude_extensions: Option<HashSet<String>> =
@@ -279,19 +352,84 @@ impl TCGraph {
             exclude_extensions,
             include_node_prefixes,
             exclude_node_prefixes,
+            metadata_filters: config.metadata_filters.map(|filters| filters.to_vec()),
+            include_tags: config.include_tags.map(|tags| tags.to_vec()),
+            exclude_tags: config.exclude_tags.map(|tags| tags.to_vec()),
             layer_graphs,
             layer_index_maps,
             layers: config.layers.clone(),
+            layer_definitions: config.layer_definitions.clone(),
             fallback_layer: config.fallback_layer.clone(),
             path_map: HashMap::new(),
             name_map: HashMap::new(),
+            unnamed_files: Vec::new(),
             include_hidden: config.include_hidden,
+            name_from_path: config.name_from_path,
+            require_name: config.require_name,
+            infer_deps: config.infer_deps,
             graph_is_built: false,
             subdir_filter: config.subdir_filter.clone(),
         }
     }
 
     pub fn build_graph(&mut self) -> Result<(), TopCatError> {
+        self.load_nodes()?;
+        if self.infer_deps {
+            self.add_inferred_dependencies()?;
+        }
+        self.validate_graph()
+    }
+
+    /// Add the dependencies inferred from each file's SQL to the ones in its header.
+    ///
+    /// Inferred dependencies on a later layer, or that would create a cycle, are skipped so
+    /// inference never breaks a graph that builds without it.
+    fn add_inferred_dependencies(&mut self) -> Result<(), TopCatError> {
+        let inferred = infer::infer_dependencies(&self.nodes())?;
+        let layer_index = |layer: &str| self.layers.iter().position(|l| l == layer);
+        for (name, deps) in inferred {
+            for (dep, reference) in deps {
+                if self.name_map[&name].deps.contains(&dep) {
+                    continue;
+                }
+                if layer_index(&self.name_map[&name].layer)
+                    < layer_index(&self.name_map[&dep].layer)
+                {
+                    debug!(
+                        "Not inferring {} requires {} from {}, it's in a later layer",
+                        name, dep, reference
+                    );
+                    continue;
+                }
+                if self.name_map[&name].layer != self.name_map[&dep].layer
+                    && !self.allows_cross_layer_deps(&self.name_map[&dep].layer)
+                {
+                    debug!(
+                        "Not inferring {} requires {} from {}, its layer doesn't allow dependencies from other layers",
+                        name, dep, reference
+                    );
+                    continue;
+                }
+                if self.dependency_path([dep.as_str()], &name).is_some() {
+                    debug!(
+                        "Not inferring {} requires {} from {}, it would create a cycle",
+                        name, dep, reference
+                    );
+                    continue;
+                }
+                debug!("Inferred {} requires {} from {}", name, dep, reference);
+                let file_node = self.name_map.get_mut(&name).unwrap();
+                file_node.deps.insert(dep.clone());
+                let path = file_node.path.clone();
+                self.path_map.get_mut(&path).unwrap().deps.insert(dep);
+            }
+        }
+        Ok(())
+    }
+
+    /// Read the header of every input file, without checking that dependencies exist or that
+    /// there are no cycles.
+    pub fn load_nodes(&mut self) -> Result<(), TopCatError> {
         debug!("include globs: {:?}", self.include_globs);
         debug!("exclude globs: {:?}", self.exclude_globs);
         debug!("include extensions: {:?}", self.include_extensions);
@@ -306,16 +444,23 @@ impl TCGraph {
             &self.exclude_extensions,
         );
 
+        let mut unnamed_files = Vec::new();
         for file in filtered_files {
+            let fallback_name = if self.name_from_path {
+                derive_name_from_path(file, &self.file_dirs)
+            } else {
+                None
+            };
             let file_node = match FileNode::from_file(
                 &self.comment_str,
-                &file,
+                file,
                 &self.layers,
                 &self.fallback_layer,
+                fallback_name.as_deref(),
             ) {
                 Ok(f) => f,
                 Err(e) => {
-                    handle_file_node_error(e)?;
+                    handle_file_node_error(e, &mut unnamed_files)?;
                     continue;
                 }
             };
@@ -333,6 +478,16 @@ impl TCGraph {
             self.path_map.insert(file_node.path.clone(), file_node);
         }
 
+        unnamed_files.sort();
+        if self.require_name && !unnamed_files.is_empty() {
+            return Err(TopCatError::NoNameDefined(unnamed_files));
+        }
+        self.unnamed_files = unnamed_files;
+        Ok(())
+    }
+
+    /// Add the loaded nodes to the layer graphs, checking their dependencies along the way.
+    fn validate_graph(&mut self) -> Result<(), TopCatError> {
         add_nodes_to_graphs(
             &mut self.layer_graphs,
             &mut self.layer_index_maps,
@@ -344,6 +499,7 @@ impl TCGraph {
             &mut self.layer_graphs,
             &self.layer_index_maps,
             &self.layers,
+            &closed_layers(&self.layer_definitions),
         )?;
 
         check_cyclic_dependencies(&self.layer_graphs)?;
@@ -383,67 +539,352 @@ impl TCGraph {
         Ok(required)
     }
 
-    pub fn graph_as_dot(
-        &self,
-        layer_name: &str,
-    ) -> Result<Dot<&DiGraph<FileNode, ()>>, TopCatError> {
-        if !self.graph_is_built {
-            return Err(TopCatError::GraphMissing);
+    fn nodes_in_subdir(&self, subdir_path: &Path) -> Result<HashSet<String>, TopCatError> {
+        info!("Applying subdirectory filter: {:?}", subdir_path);
+        let canonical_subdir_path = subdir_path.canonicalize().map_err(TopCatError::Io)?;
+
+        let nodes: HashSet<String> = self
+            .name_map
+            .values()
+            .filter(|node| is_in_dir(&node.path, &canonical_subdir_path))
+            .map(|node| node.name.clone())
+            .collect();
+
+        if nodes.is_empty() {
+            info!(
+                "No files are found within the specified subdirectory filter: {:?}",
+                subdir_path
+            );
+        }
+        Ok(nodes)
+    }
+
+    fn nodes_matching_tags(&self) -> HashSet<String> {
+        info!(
+            "Applying tag filters: include {:?}, exclude {:?}",
+            self.include_tags, self.exclude_tags
+        );
+        self.name_map
+            .values()
+            .filter(|node| {
+                let included = match &self.include_tags {
+                    Some(include) => include.iter().any(|expr| expr.matches(&node.tags)),
+                    None => true,
+                };
+                let excluded = match &self.exclude_tags {
+                    Some(exclude) => exclude.iter().any(|expr| expr.matches(&node.tags)),
+                    None => false,
+                };
+                included && !excluded
+            })
+            .map(|node| node.name.clone())
+            .collect()
+    }
+
+    /// Whether `file_node` is inside the `--subdir-filter` directory, or `None` without one.
+    pub fn in_subdir_filter(&self, file_node: &FileNode) -> Result<Option<bool>, TopCatError> {
+        match &self.subdir_filter {
+            Some(subdir_path) => {
+                let canonical_subdir_path = subdir_path.canonicalize()?;
+                Ok(Some(is_in_dir(&file_node.path, &canonical_subdir_path)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub fn subdir_filter(&self) -> Option<&Path> {
+        self.subdir_filter.as_deref()
+    }
+
+    /// Find the nodes picked by the subdirectory and tag filters, before their dependencies are
+    /// added. Returns `None` when no selection filter is active.
+    pub fn select_initial_nodes(&self) -> Result<Option<HashSet<String>>, TopCatError> {
+        let mut selected: Option<HashSet<String>> = None;
+
+        if let Some(subdir_path) = &self.subdir_filter {
+            selected = Some(self.nodes_in_subdir(subdir_path)?);
+        }
+
+        if self.include_tags.is_some() || self.exclude_tags.is_some() {
+            let tagged = self.nodes_matching_tags();
+            selected = Some(match selected {
+                Some(nodes) => nodes.intersection(&tagged).cloned().collect(),
+                None => tagged,
+            });
         }
-        let graph = self.layer_graphs.get(layer_name).ok_or_else(|| {
-            TopCatError::UnknownError(format!("Layer '{}' not found", layer_name))
-        })?;
-        let dot = Dot::with_attr_getters(
-            graph,
-            &[Config::EdgeNoLabel, Config::NodeNoLabel],
-            &|_, _| String::new(),
-            &|_, (_, f)| format!("label=\"{}\"", f.name),
+
+        Ok(selected)
+    }
+
+    /// Find the nodes a filtered build starts from: those picked by the selection filters, and
+    /// every node in a layer that isn't optional. Returns `None` when no selection filter is
+    /// active.
+    pub fn select_nodes(&self) -> Result<Option<HashSet<String>>, TopCatError> {
+        let Some(mut selected) = self.select_initial_nodes()? else {
+            return Ok(None);
+        };
+        selected.extend(
+            self.name_map
+                .values()
+                .filter(|node| !self.is_optional_layer(&node.layer))
+                .map(|node| node.name.clone()),
         );
-        Ok(dot)
+        Ok(Some(selected))
+    }
+
+    pub fn layers(&self) -> &[String] {
+        &self.layers
+    }
+
+    pub fn fallback_layer(&self) -> &str {
+        &self.fallback_layer
+    }
+
+    /// Check that a file named `name` in `layer` may require `dep_node`, as when building the
+    /// graph. `layer` must be one of the configured layers.
+    pub fn check_layer_dependency(
+        &self,
+        name: &str,
+        layer: &str,
+        dep_node: &FileNode,
+    ) -> Result<(), TopCatError> {
+        check_layer_dependency(
+            name,
+            layer,
+            dep_node,
+            &self.layers,
+            &closed_layers(&self.layer_definitions),
+        )
+    }
+
+    /// Whether files in other layers may require files in `layer`.
+    pub fn allows_cross_layer_deps(&self, layer: &str) -> bool {
+        self.layer_definitions
+            .iter()
+            .find(|l| l.name == layer)
+            .map_or(true, |l| l.cross_layer_deps)
     }
 
-    pub fn get_sorted_files(&self) -> Result<Vec<PathBuf>, TopCatError> {
+    /// Whether the selection, prefix and `--where` filters apply to files in `layer`.
+    pub fn is_optional_layer(&self, layer: &str) -> bool {
+        self.layer_definitions
+            .iter()
+            .find(|l| l.name == layer)
+            .map_or(true, |l| l.optional)
+    }
+
+    /// The files left out because they have no name header, sorted by path.
+    pub fn unnamed_files(&self) -> &[PathBuf] {
+        &self.unnamed_files
+    }
+
+    /// Every loaded node, sorted by path.
+    pub fn nodes(&self) -> Vec<&FileNode> {
+        let mut nodes: Vec<&FileNode> = self.path_map.values().collect();
+        nodes.sort_by(|a, b| a.path.cmp(&b.path));
+        nodes
+    }
+
+    pub fn node(&self, name: &str) -> Option<&FileNode> {
+        self.name_map.get(name)
+    }
+
+    /// Follow `neighbours` from `name`, one step or until nothing new is found, returning the
+    /// nodes reached sorted by name.
+    fn walk<'a>(
+        &'a self,
+        name: &str,
+        transitive: bool,
+        neighbours: impl Fn(&'a FileNode) -> Vec<&'a FileNode>,
+    ) -> Result<Vec<&'a FileNode>, TopCatError> {
+        let start = self
+            .name_map
+            .get(name)
+            .ok_or_else(|| TopCatError::NodeNotFound(name.to_string()))?;
+        let mut seen: HashSet<&str> = HashSet::new();
+        let mut found = Vec::new();
+        let mut queue = VecDeque::from([start]);
+        while let Some(file_node) = queue.pop_front() {
+            for next in neighbours(file_node) {
+                if next.name != start.name && seen.insert(&next.name) {
+                    found.push(next);
+                    if transitive {
+                        queue.push_back(next);
+                    }
+                }
+            }
+        }
+        found.sort();
+        Ok(found)
+    }
+
+    /// The nodes `name` requires, and with `transitive` everything they require in turn.
+    pub fn dependencies(
+        &self,
+        name: &str,
+        transitive: bool,
+    ) -> Result<Vec<&FileNode>, TopCatError> {
+        self.walk(name, transitive, |file_node| {
+            file_node
+                .deps
+                .iter()
+                .filter_map(|dep| self.name_map.get(dep))
+                .collect()
+        })
+    }
+
+    /// The nodes requiring `name`, and with `transitive` everything requiring them in turn.
+    pub fn dependents(&self, name: &str, transitive: bool) -> Result<Vec<&FileNode>, TopCatError> {
+        let mut dependents: HashMap<&str, Vec<&FileNode>> = HashMap::new();
+        for file_node in self.name_map.values() {
+            for dep in &file_node.deps {
+                dependents.entry(dep).or_default().push(file_node);
+            }
+        }
+        self.walk(name, transitive, |file_node| {
+            dependents
+                .get(file_node.name.as_str())
+                .cloned()
+                .unwrap_or_default()
+        })
+    }
+
+    /// The nodes that don't require anything, sorted by name.
+    pub fn roots(&self) -> Vec<&FileNode> {
+        let mut roots: Vec<&FileNode> = self
+            .name_map
+            .values()
+            .filter(|file_node| file_node.deps.is_empty())
+            .collect();
+        roots.sort();
+        roots
+    }
+
+    /// The nodes nothing requires, sorted by name.
+    pub fn leaves(&self) -> Vec<&FileNode> {
+        let required: HashSet<&str> = self
+            .name_map
+            .values()
+            .flat_map(|file_node| file_node.deps.iter().map(String::as_str))
+            .collect();
+        let mut leaves: Vec<&FileNode> = self
+            .name_map
+            .values()
+            .filter(|file_node| !required.contains(file_node.name.as_str()))
+            .collect();
+        leaves.sort();
+        leaves
+    }
+
+    /// The shortest chain of `requires` from any of `starts` to `to`, both ends included.
+    ///
+    /// Returns `None` if `to` can't be reached. Starting nodes are tried in name order so the same
+    /// chain is found on every run.
+    pub fn dependency_path<'a>(
+        &self,
+        starts: impl IntoIterator<Item = &'a str>,
+        to: &str,
+    ) -> Option<Vec<&FileNode>> {
+        let mut starts: Vec<&str> = starts.into_iter().collect();
+        starts.sort_unstable();
+        let mut previous: HashMap<&str, Option<&str>> = HashMap::new();
+        let mut queue = VecDeque::new();
+        for start in starts {
+            if let Some((name, _)) = self.name_map.get_key_value(start) {
+                if previous.insert(name, None).is_none() {
+                    queue.push_back(name.as_str());
+                }
+            }
+        }
+
+        while let Some(name) = queue.pop_front() {
+            if name == to {
+                let mut path = vec![&self.name_map[name]];
+                let mut current = name;
+                while let Some(Some(prev)) = previous.get(current) {
+                    path.push(&self.name_map[*prev]);
+                    current = prev;
+                }
+                path.reverse();
+                return Some(path);
+            }
+            let mut deps: Vec<&String> = self.name_map[name].deps.iter().collect();
+            deps.sort_unstable();
+            for dep in deps {
+                if let Some((dep, _)) = self.name_map.get_key_value(dep.as_str()) {
+                    if !previous.contains_key(dep.as_str()) {
+                        previous.insert(dep, Some(name));
+                        queue.push_back(dep);
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    /// Whether `file_node` passes `--include-prefix` and `--exclude-prefix`.
+    pub fn passes_prefix_filters(&self, file_node: &FileNode) -> bool {
+        match (&self.include_node_prefixes, &self.exclude_node_prefixes) {
+            (Some(include), Some(exclude)) => {
+                include.iter().any(|p| file_node.name.starts_with(p))
+                    && !exclude.iter().any(|p| file_node.name.starts_with(p))
+            }
+            (Some(include), None) => include.iter().any(|p| file_node.name.starts_with(p)),
+            (None, Some(exclude)) => !exclude.iter().any(|p| file_node.name.starts_with(p)),
+            (None, None) => true,
+        }
+    }
+
+    /// Whether `file_node` matches every `--where` filter.
+    pub fn passes_metadata_filters(&self, file_node: &FileNode) -> bool {
+        match &self.metadata_filters {
+            Some(filters) => filters
+                .iter()
+                .all(|(key, value)| file_node.metadata.get(key) == Some(value)),
+            None => true,
+        }
+    }
+
+    /// Warn if a selection filter doesn't match any file, which is likely a mistake.
+    ///
+    /// `--subdir-filter` and the tag filters are checked together, as are the prefix and `--where`
+    /// filters. This is checked before anything is written, so a denied warning changes nothing.
+    pub fn check_selection(&self) -> Result<(), TopCatError> {
+        let selected = self.select_initial_nodes()?;
+        let filters_nodes = self.include_node_prefixes.is_some()
+            || self.exclude_node_prefixes.is_some()
+            || self.metadata_filters.is_some();
+        let matches_nothing = selected.is_some_and(|selected| selected.is_empty())
+            || (filters_nodes
+                && !self.name_map.values().any(|file_node| {
+                    self.passes_prefix_filters(file_node) && self.passes_metadata_filters(file_node)
+                }));
+        if matches_nothing {
+            warning::emit(
+                WarningCode::EmptySelection,
+                None,
+                "No files match the selection filters",
+            );
+        }
+        Ok(())
+    }
+
+    pub fn get_sorted_nodes(&self) -> Result<Vec<&FileNode>, TopCatError> {
         if !self.graph_is_built {
             return Err(TopCatError::GraphMissing);
         }
         info!("Getting sorted files");
 
-        let required_node_names: Option<HashSet<String>> =
-            if let Some(subdir_path) = &self.subdir_filter {
-                info!("Applying subdirectory filter: {:?}", subdir_path);
-                let canonical_subdir_path =
-                    subdir_path.canonicalize().map_err(|e| TopCatError::Io(e))?;
-
-                let initial_nodes: HashSet<String> = self
-                    .name_map
-                    .values()
-                    .filter_map(|node| {
-                        node.path
-                            .canonicalize()
-                            .ok()
-                            .and_then(|canonical_node_path| {
-                                if canonical_node_path.starts_with(&canonical_subdir_path) {
-                                    Some(node.name.clone())
-                                } else {
-                                    None
-                                }
-                            })
-                    })
-                    .collect();
-
+        let required_node_names: Option<HashSet<String>> = match self.select_nodes()? {
+            Some(initial_nodes) => {
                 if initial_nodes.is_empty() {
-                    info!(
-                        "No files are found within the specified subdirectory filter: {:?}",
-                        subdir_path
-                    );
                     return Ok(Vec::new());
                 }
-
-                debug!("Initial nodes from subdir: {:?}", initial_nodes);
+                debug!("Initial nodes from selection filters: {:?}", initial_nodes);
                 Some(self.find_required_nodes(&initial_nodes)?)
-            } else {
-                None
-            };
+            }
+            None => None,
+        };
 
         if let Some(required) = &required_node_names {
             debug!(
@@ -452,7 +893,7 @@ impl TCGraph {
             );
         }
 
-        let mut sorted_files = Vec::new();
+        let mut sorted_nodes = Vec::new();
 
         for layer_name in &self.layers {
             let graph = self.layer_graphs.get(layer_name).unwrap();
@@ -464,8 +905,8 @@ impl TCGraph {
                 graph.edge_count()
             );
 
-            let mut topo = StableTopo::new(graph);
-            while let Some(node_idx) = topo.next() {
+            let topo = StableTopo::new(graph);
+            for node_idx in topo {
                 let file_node = match graph.node_weight(node_idx) {
                     Some(x) => x,
                     None => return Err(TopCatError::UnknownError("Node not found".to_string())),
@@ -477,38 +918,249 @@ impl TCGraph {
                 if let Some(required) = &required_node_names {
                     if !required.contains(&file_node.name) {
                         trace!(
-                            "Excluding node '{}' (not required by subdir filter)",
+                            "Excluding node '{}' (not required by selection filters)",
                             file_node.name
                         );
                         should_include = false;
                     }
                 }
 
-                if should_include {
-                    should_include =
-                        match (&self.include_node_prefixes, &self.exclude_node_prefixes) {
-                            (Some(include), Some(exclude)) => {
-                                include.iter().any(|p| file_node.name.starts_with(p))
-                                    && !exclude.iter().any(|p| file_node.name.starts_with(p))
-                            }
-                            (Some(include), None) => {
-                                include.iter().any(|p| file_node.name.starts_with(p))
-                            }
-                            (None, Some(exclude)) => {
-                                !exclude.iter().any(|p| file_node.name.starts_with(p))
-                            }
-                            (None, None) => true,
-                        };
+                // Layers that aren't optional are included whatever the filters
+                let is_filtered = self.is_optional_layer(&file_node.layer);
+
+                if should_include && is_filtered {
+                    should_include = self.passes_prefix_filters(file_node);
                     if !should_include {
                         trace!("Excluding node '{}' by prefix filter", file_node.name);
                     }
                 }
 
+                if should_include && is_filtered {
+                    should_include = self.passes_metadata_filters(file_node);
+                    if !should_include {
+                        trace!("Excluding node '{}' by metadata filter", file_node.name);
+                    }
+                }
+
                 if should_include {
-                    sorted_files.push(file_node.path.clone());
+                    sorted_nodes.push(file_node);
                 }
             }
         }
-        Ok(sorted_files)
+        Ok(sorted_nodes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{self, write_node};
+
+    #[test]
+    fn test_derive_name_from_path() {
+        let input_dirs = vec![PathBuf::from("sql"), PathBuf::from("sql/my_schema")];
+
+        assert_eq!(
+            derive_name_from_path(Path::new("sql/my_schema/functions/a.sql"), &input_dirs),
+            Some("functions.a".to_string())
+        );
+        assert_eq!(
+            derive_name_from_path(Path::new("sql/Other/Schema.sql"), &input_dirs),
+            Some("other.schema".to_string())
+        );
+        assert_eq!(
+            derive_name_from_path(Path::new("elsewhere/a.sql"), &input_dirs),
+            None
+        );
+    }
+
+    #[test]
+    fn test_tag_selection_includes_dependencies() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        write_node(temp_dir.path(), "a.sql", "-- name: a\n-- tags: slow");
+        write_node(
+            temp_dir.path(),
+            "b.sql",
+            "-- name: b\n-- requires: a\n-- tags: seed",
+        );
+        write_node(temp_dir.path(), "c.sql", "-- name: c\n-- tags: seed, slow");
+        write_node(temp_dir.path(), "d.sql", "-- name: d");
+
+        let include_tags = vec!["seed and not slow".parse().unwrap()];
+        let config = config::Config {
+            include_tags: Some(&include_tags),
+            ..test_utils::config(temp_dir.path())
+        };
+        let graph = test_utils::build_graph(&config);
+
+        let names: Vec<&str> = graph
+            .get_sorted_nodes()
+            .unwrap()
+            .iter()
+            .map(|node| node.name.as_str())
+            .collect();
+        assert_eq!(names, vec!["a", "b"]);
+    }
+
+    #[test]
+    fn test_layer_definitions() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        write_node(temp_dir.path(), "a.sql", "-- name: a\n-- layer: prepend");
+        write_node(temp_dir.path(), "b.sql", "-- name: b\n-- tags: seed");
+        write_node(temp_dir.path(), "c.sql", "-- name: c\n-- owner: me");
+        write_node(
+            temp_dir.path(),
+            "d.sql",
+            "-- name: d\n-- layer: append\n-- requires: a",
+        );
+
+        let layer = |name: &str, cross_layer_deps: bool, optional: bool| config::Layer {
+            name: name.to_string(),
+            separator: None,
+            suffix: None,
+            preamble: None,
+            postamble: None,
+            cross_layer_deps,
+            optional,
+        };
+        let include_tags = vec!["seed".parse().unwrap()];
+        let prefixes = vec!["b".to_string()];
+        let filters = vec![("owner".to_string(), "me".to_string())];
+        let mut config = config::Config {
+            layers: vec![
+                "prepend".to_string(),
+                "normal".to_string(),
+                "append".to_string(),
+            ],
+            layer_definitions: vec![
+                layer("prepend", true, true),
+                layer("normal", true, true),
+                layer("append", true, false),
+            ],
+            include_tags: Some(&include_tags),
+            ..test_utils::config(temp_dir.path())
+        };
+        let sorted_names = |config: &config::Config| -> Vec<String> {
+            let graph = test_utils::build_graph(config);
+            let nodes = graph.get_sorted_nodes().unwrap();
+            nodes.iter().map(|node| node.name.clone()).collect()
+        };
+        // All of `append` is included, along with what it requires
+        assert_eq!(sorted_names(&config), vec!["a", "b", "d"]);
+
+        // The prefix and --where filters don't apply to `append` either
+        config.include_tags = None;
+        config.include_node_prefixes = Some(&prefixes);
+        assert_eq!(sorted_names(&config), vec!["b", "d"]);
+        config.include_node_prefixes = None;
+        config.metadata_filters = Some(&filters);
+        assert_eq!(sorted_names(&config), vec!["c", "d"]);
+
+        config.layer_definitions[0] = layer("prepend", false, true);
+        let mut graph = TCGraph::new(&config);
+        assert!(matches!(
+            graph.build_graph(),
+            Err(TopCatError::InvalidDependency(name, _)) if name == "d"
+        ));
+    }
+
+    #[test]
+    fn test_check_selection() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        write_node(temp_dir.path(), "a.sql", "-- name: app.a\n-- tags: seed");
+        write_node(temp_dir.path(), "b.sql", "-- name: app.b\n-- owner: me");
+
+        let selection_matches_nothing = |config: config::Config| {
+            let graph = test_utils::build_graph(&config);
+            graph.check_selection().unwrap();
+            let warnings = warning::take();
+            assert!(warnings
+                .iter()
+                .all(|warning| warning.code == WarningCode::EmptySelection));
+            !warnings.is_empty()
+        };
+        let include_tags = vec!["seed".parse().unwrap()];
+        let missing_tags = vec!["slow".parse().unwrap()];
+        let prefixes = vec!["app.".to_string()];
+        let missing_prefixes = vec!["zzz".to_string()];
+        let filters = vec![("owner".to_string(), "me".to_string())];
+        let missing_filters = vec![("owner".to_string(), "you".to_string())];
+        let dir = temp_dir.path();
+
+        assert!(!selection_matches_nothing(test_utils::config(dir)));
+        assert!(!selection_matches_nothing(config::Config {
+            include_tags: Some(&include_tags),
+            include_node_prefixes: Some(&prefixes),
+            metadata_filters: Some(&filters),
+            ..test_utils::config(dir)
+        }));
+        assert!(selection_matches_nothing(config::Config {
+            include_tags: Some(&missing_tags),
+            ..test_utils::config(dir)
+        }));
+        assert!(selection_matches_nothing(config::Config {
+            include_node_prefixes: Some(&missing_prefixes),
+            ..test_utils::config(dir)
+        }));
+        assert!(selection_matches_nothing(config::Config {
+            exclude_node_prefixes: Some(&prefixes),
+            ..test_utils::config(dir)
+        }));
+        assert!(selection_matches_nothing(config::Config {
+            metadata_filters: Some(&missing_filters),
+            ..test_utils::config(dir)
+        }));
+    }
+
+    #[test]
+    fn test_dependency_path_is_shortest() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        write_node(temp_dir.path(), "a.sql", "-- name: a");
+        write_node(temp_dir.path(), "b.sql", "-- name: b\n-- requires: a");
+        write_node(temp_dir.path(), "c.sql", "-- name: c\n-- requires: b");
+        write_node(temp_dir.path(), "d.sql", "-- name: d\n-- requires: a, c");
+
+        let config = test_utils::config(temp_dir.path());
+        let graph = test_utils::build_graph(&config);
+
+        let names = |path: Option<Vec<&FileNode>>| {
+            path.map(|nodes| nodes.iter().map(|n| n.name.clone()).collect::<Vec<_>>())
+        };
+        assert_eq!(
+            names(graph.dependency_path(["d"], "b")),
+            Some(vec!["d".to_string(), "c".to_string(), "b".to_string()])
+        );
+        assert_eq!(
+            names(graph.dependency_path(["d"], "a")),
+            Some(vec!["d".to_string(), "a".to_string()])
+        );
+        assert_eq!(names(graph.dependency_path(["b"], "c")), None);
+    }
+
+    #[test]
+    fn test_dependencies_and_dependents() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        write_node(temp_dir.path(), "a.sql", "-- name: a");
+        write_node(temp_dir.path(), "b.sql", "-- name: b\n-- requires: a");
+        write_node(temp_dir.path(), "c.sql", "-- name: c\n-- requires: b");
+        write_node(temp_dir.path(), "d.sql", "-- name: d");
+
+        let config = test_utils::config(temp_dir.path());
+        let graph = test_utils::build_graph(&config);
+
+        let names = |nodes: Vec<&FileNode>| {
+            nodes
+                .iter()
+                .map(|n| n.name.clone())
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        assert_eq!(names(graph.dependencies("c", false).unwrap()), "b");
+        assert_eq!(names(graph.dependencies("c", true).unwrap()), "a,b");
+        assert_eq!(names(graph.dependents("a", false).unwrap()), "b");
+        assert_eq!(names(graph.dependents("a", true).unwrap()), "b,c");
+        assert_eq!(names(graph.roots()), "a,d");
+        assert_eq!(names(graph.leaves()), "c,d");
+        assert!(graph.dependencies("e", false).is_err());
     }
 }
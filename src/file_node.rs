@@ -1,40 +1,33 @@
 use std::cmp::Ordering;
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
 use std::fmt::Display;
-use std::fs::File;
 use std::hash::{Hash, Hasher};
-use std::io;
-use std::io::BufRead;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use crate::exceptions::FileNodeError;
-
-fn get_file_headers(path: &PathBuf, comment_str: &str) -> Vec<String> {
-    let file = match File::open(&path) {
-        Err(why) => panic!("couldn't open {}: {}", path.display(), why.to_string()),
-        Ok(file) => file,
-    };
-
-    // fill a vector with the first lines of the file starting with the comment string ignoring empty lines. Stop on the first line without the comment string.
-    let reader = io::BufReader::new(file);
-    let file_data: Vec<_> = reader
-        .lines()
-        .take_while(|x| {
-            let x = match x.as_ref() {
-                Ok(x) => x,
-                Err(_) => return false,
-            };
-            x.starts_with(comment_str) || x.is_empty()
-        })
-        .collect::<io::Result<_>>()
-        .unwrap_or_else(|_| vec![]);
-
-    // remove any empty lines from the vector and return
-    file_data
-        .iter()
-        .filter(|x| !x.is_empty())
-        .map(|x| x.to_string())
-        .collect()
+use crate::header::{self, Directive, HeaderLine, HeaderLocation, HeaderValue};
+
+/// Split off the header block at the start of a file's content.
+///
+/// The header block is every leading line that starts with `comment_str` or is empty, the same
+/// lines that are read for the header directives. Returns the rest of the content and the
+/// number of lines removed.
+pub fn strip_file_header<'a>(content: &'a str, comment_str: &str) -> (&'a str, usize) {
+    let mut rest = content;
+    let mut header_lines = 0;
+    while !rest.is_empty() {
+        let (line, remainder) = match rest.find('\n') {
+            Some(i) => (&rest[..i], &rest[i + 1..]),
+            None => (rest, ""),
+        };
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        if !(line.starts_with(comment_str) || line.is_empty()) {
+            break;
+        }
+        rest = remainder;
+        header_lines += 1;
+    }
+    (rest, header_lines)
 }
 
 #[derive(Debug, Clone)]
@@ -44,6 +37,10 @@ pub struct FileNode {
     pub deps: HashSet<String>,
     pub layer: String,
     pub ensure_exists: HashSet<String>,
+    pub tags: HashSet<String>,
+    pub metadata: BTreeMap<String, String>,
+    /// Where each header value was declared, for diagnostics.
+    pub locations: Vec<HeaderValue>,
 }
 
 // Implementing PartialEq for equality comparisons
@@ -86,6 +83,8 @@ impl FileNode {
         deps: HashSet<String>,
         layer: String,
         ensure_exists: HashSet<String>,
+        tags: HashSet<String>,
+        metadata: BTreeMap<String, String>,
     ) -> FileNode {
         FileNode {
             name,
@@ -93,100 +92,135 @@ impl FileNode {
             deps,
             layer,
             ensure_exists,
+            tags,
+            metadata,
+            locations: Vec::new(),
         }
     }
 
-    fn split_dependencies(line: &str) -> Vec<String> {
-        line.split(|c: char| c.is_whitespace() || c == ',')
-            .filter_map(|x| {
-                let x = x.trim().to_string();
-                if !x.is_empty() {
-                    Some(x)
-                } else {
-                    None
-                }
-            })
-            .collect()
+    /// Where `value` was declared by one of `directives` in the file's header.
+    pub fn locate(&self, directives: &[Directive], value: &str) -> Option<&HeaderLocation> {
+        self.locations
+            .iter()
+            .find(|located| directives.contains(&located.directive) && located.value == value)
+            .map(|located| &located.location)
     }
+
     pub fn from_file(
         comment_str: &str,
-        path: &PathBuf,
+        path: &Path,
         layers: &[String],
         fallback_layer: &str,
+        fallback_name: Option<&str>,
     ) -> Result<FileNode, FileNodeError> {
-        let file_data = get_file_headers(&path, comment_str);
-        let name_str = format!("{} name:", comment_str);
-        let dep_str = format!("{} requires:", comment_str);
-        let drop_str = format!("{} dropped_by:", comment_str);
-        let layer_str = format!("{} layer:", comment_str);
-        // Keep backward compatibility with old headers
-        let prepend_str = format!("{} is_initial", comment_str);
-        let append_str = format!("{} is_final", comment_str);
-        let ensure_exists_str = format!("{} exists:", comment_str);
+        let header = header::read_header(path, comment_str)
+            .unwrap_or_else(|why| panic!("couldn't open {}: {}", path.display(), why));
+        Self::from_header(
+            comment_str,
+            header,
+            path,
+            layers,
+            fallback_layer,
+            fallback_name,
+        )
+    }
 
+    /// Build a node from the already parsed header lines of the file at `path`.
+    pub fn from_header(
+        comment_str: &str,
+        header: Vec<HeaderLine>,
+        path: &Path,
+        layers: &[String],
+        fallback_layer: &str,
+        fallback_name: Option<&str>,
+    ) -> Result<FileNode, FileNodeError> {
         let mut name = String::new();
         let mut deps = HashSet::new();
         let mut layer = fallback_layer.to_string();
         let mut ensure_exists = HashSet::new();
-
-        for unprocessed_line in &file_data {
-            let line = unprocessed_line.trim().to_lowercase();
-            if line.starts_with(&name_str) {
-                if name.is_empty() {
-                    name = line[name_str.len()..].trim().to_string();
-                } else {
-                    // raise an error that a file has more than one name declared
-                    return Err(FileNodeError::TooManyNames(
-                        path.clone(),
-                        vec![name, line[name_str.len()..].trim().to_string()],
-                    ));
+        let mut tags = HashSet::new();
+        let mut metadata = BTreeMap::new();
+        let mut locations = Vec::new();
+        let mut layer_location = None;
+
+        for line in header {
+            let located_values = line.located_values(path, comment_str);
+            if matches!(
+                line.directive,
+                Directive::Layer | Directive::IsInitial | Directive::IsFinal
+            ) {
+                if let Some(located) = located_values.first() {
+                    layer_location = Some(located.location.clone());
                 }
-            } else if line.starts_with(&dep_str) {
-                // -- requires: tomato, potato orange -> ["tomato", "potato", "orange"]
-                // Should split on comma or space and then trim. Don't insert empty strings
-                for item in Self::split_dependencies(&line[dep_str.len()..]) {
-                    deps.insert(item);
+            }
+            locations.extend(located_values);
+            match line.directive {
+                Directive::Name => {
+                    if name.is_empty() {
+                        name = line.value;
+                    } else {
+                        // raise an error that a file has more than one name declared
+                        let location = locations
+                            .last()
+                            .map(|located| Box::new(located.location.clone()));
+                        return Err(FileNodeError::TooManyNames(
+                            path.to_path_buf(),
+                            vec![name, line.value],
+                            location,
+                        ));
+                    }
                 }
-            } else if line.starts_with(&drop_str) {
+                // -- requires: tomato, potato orange -> ["tomato", "potato", "orange"]
                 // -- dropped_by: tomato, potato -> ["tomato", "potato"]
-                for item in Self::split_dependencies(&line[drop_str.len()..]) {
-                    deps.insert(item);
-                }
-            } else if line.starts_with(&layer_str) {
+                Directive::Requires | Directive::DroppedBy => deps.extend(line.values()),
                 // -- layer: prepend -> "prepend"
-                let declared_layer = line[layer_str.len()..].trim();
-                if !declared_layer.is_empty() {
-                    layer = declared_layer.to_string();
+                Directive::Layer => {
+                    if !line.value.is_empty() {
+                        layer = line.value;
+                    }
                 }
-            } else if line.starts_with(&prepend_str) {
                 // -- is_initial -> "prepend" (backward compatibility)
-                layer = "prepend".to_string();
-            } else if line.starts_with(&append_str) {
+                Directive::IsInitial => layer = "prepend".to_string(),
                 // -- is_final -> "append" (backward compatibility)
-                layer = "append".to_string();
-            } else if line.starts_with(&ensure_exists_str) {
+                Directive::IsFinal => layer = "append".to_string(),
                 // --exists: tomato, potato -> ["tomato", "potato"]
-                for item in Self::split_dependencies(&line[ensure_exists_str.len()..]) {
-                    ensure_exists.insert(item);
+                Directive::Exists => ensure_exists.extend(line.values()),
+                // -- tags: seed, test -> ["seed", "test"]
+                Directive::Tags => tags.extend(line.values()),
+                // -- owner: Payments Team -> {"owner": "Payments Team"}
+                Directive::Metadata(key) => {
+                    metadata.insert(key, line.value);
                 }
+                Directive::Comment => {}
             }
         }
         if name.is_empty() {
-            return Err(FileNodeError::NoNameDefined(path.clone()));
+            match fallback_name {
+                Some(fallback_name) => name = fallback_name.to_string(),
+                None => return Err(FileNodeError::NoNameDefined(path.to_path_buf())),
+            }
         }
 
         // Validate that the declared layer exists in the configured layers
         if !layers.contains(&layer) {
-            return Err(FileNodeError::InvalidLayer(path.clone(), layer));
+            return Err(FileNodeError::InvalidLayer(
+                path.to_path_buf(),
+                layer,
+                layer_location.map(Box::new),
+            ));
         }
 
-        Ok(FileNode::new(
+        let mut file_node = FileNode::new(
             name,
-            path.clone(),
+            path.to_path_buf(),
             deps,
             layer,
             ensure_exists,
-        ))
+            tags,
+            metadata,
+        );
+        file_node.locations = locations;
+        Ok(file_node)
     }
 }
 
@@ -207,13 +241,8 @@ mod tests {
         let temp_file = tempfile::NamedTempFile::with_suffix(".sql").unwrap();
         std::fs::write(&temp_file, "-- name: test_node\n-- layer: first\nSELECT 1;").unwrap();
 
-        let file_node = FileNode::from_file(
-            "--",
-            &temp_file.path().to_path_buf(),
-            &layers,
-            fallback_layer,
-        )
-        .unwrap();
+        let file_node =
+            FileNode::from_file("--", temp_file.path(), &layers, fallback_layer, None).unwrap();
 
         assert_eq!(file_node.name, "test_node");
         assert_eq!(file_node.layer, "first");
@@ -231,13 +260,8 @@ mod tests {
         let temp_file = tempfile::NamedTempFile::with_suffix(".sql").unwrap();
         std::fs::write(&temp_file, "-- name: test_node\n-- is_initial\nSELECT 1;").unwrap();
 
-        let file_node = FileNode::from_file(
-            "--",
-            &temp_file.path().to_path_buf(),
-            &layers,
-            fallback_layer,
-        )
-        .unwrap();
+        let file_node =
+            FileNode::from_file("--", temp_file.path(), &layers, fallback_layer, None).unwrap();
 
         assert_eq!(file_node.name, "test_node");
         assert_eq!(file_node.layer, "prepend");
@@ -255,13 +279,8 @@ mod tests {
         let temp_file = tempfile::NamedTempFile::with_suffix(".sql").unwrap();
         std::fs::write(&temp_file, "-- name: test_node\n-- is_final\nSELECT 1;").unwrap();
 
-        let file_node = FileNode::from_file(
-            "--",
-            &temp_file.path().to_path_buf(),
-            &layers,
-            fallback_layer,
-        )
-        .unwrap();
+        let file_node =
+            FileNode::from_file("--", temp_file.path(), &layers, fallback_layer, None).unwrap();
 
         assert_eq!(file_node.name, "test_node");
         assert_eq!(file_node.layer, "append");
@@ -279,13 +298,8 @@ mod tests {
         let temp_file = tempfile::NamedTempFile::with_suffix(".sql").unwrap();
         std::fs::write(&temp_file, "-- name: test_node\nSELECT 1;").unwrap();
 
-        let file_node = FileNode::from_file(
-            "--",
-            &temp_file.path().to_path_buf(),
-            &layers,
-            fallback_layer,
-        )
-        .unwrap();
+        let file_node =
+            FileNode::from_file("--", temp_file.path(), &layers, fallback_layer, None).unwrap();
 
         assert_eq!(file_node.name, "test_node");
         assert_eq!(file_node.layer, "second");
@@ -303,16 +317,11 @@ mod tests {
         )
         .unwrap();
 
-        let result = FileNode::from_file(
-            "--",
-            &temp_file.path().to_path_buf(),
-            &layers,
-            fallback_layer,
-        );
+        let result = FileNode::from_file("--", temp_file.path(), &layers, fallback_layer, None);
 
         assert!(result.is_err());
         match result.unwrap_err() {
-            FileNodeError::InvalidLayer(_, layer) => assert_eq!(layer, "invalid"),
+            FileNodeError::InvalidLayer(_, layer, _) => assert_eq!(layer, "invalid"),
             _ => panic!("Expected InvalidLayer error"),
         }
     }
@@ -325,19 +334,97 @@ mod tests {
         let temp_file = tempfile::NamedTempFile::with_suffix(".sql").unwrap();
         std::fs::write(&temp_file, "-- name: test_node\n-- layer: first\n-- requires: dep1, dep2\n-- dropped_by: dep3\nSELECT 1;").unwrap();
 
+        let file_node =
+            FileNode::from_file("--", temp_file.path(), &layers, fallback_layer, None).unwrap();
+
+        assert_eq!(file_node.name, "test_node");
+        assert_eq!(file_node.layer, "first");
+        assert!(file_node.deps.contains("dep1"));
+        assert!(file_node.deps.contains("dep2"));
+        assert!(file_node.deps.contains("dep3"));
+        assert_eq!(file_node.deps.len(), 3);
+    }
+
+    #[test]
+    fn test_fallback_name() {
+        let layers = vec!["normal".to_string()];
+        let fallback_layer = "normal";
+
+        let temp_file = tempfile::NamedTempFile::with_suffix(".sql").unwrap();
+        std::fs::write(&temp_file, "-- requires: dep1\nSELECT 1;").unwrap();
+
+        let result = FileNode::from_file("--", temp_file.path(), &layers, fallback_layer, None);
+        match result {
+            Err(FileNodeError::NoNameDefined(_)) => {}
+            _ => panic!("Expected NoNameDefined error"),
+        }
+
         let file_node = FileNode::from_file(
             "--",
-            &temp_file.path().to_path_buf(),
+            temp_file.path(),
             &layers,
             fallback_layer,
+            Some("my_schema.functions.a"),
         )
         .unwrap();
 
-        assert_eq!(file_node.name, "test_node");
-        assert_eq!(file_node.layer, "first");
+        assert_eq!(file_node.name, "my_schema.functions.a");
         assert!(file_node.deps.contains("dep1"));
-        assert!(file_node.deps.contains("dep2"));
-        assert!(file_node.deps.contains("dep3"));
-        assert_eq!(file_node.deps.len(), 3);
+    }
+
+    #[test]
+    fn test_metadata_parsing() {
+        let layers = vec!["normal".to_string()];
+        let fallback_layer = "normal";
+
+        let temp_file = tempfile::NamedTempFile::with_suffix(".sql").unwrap();
+        std::fs::write(
+            &temp_file,
+            "-- name: test_node\n-- Owner: Payments Team\n-- requires: dep1\n-- version: 2\n-- Note that this is not metadata: really\nSELECT 1;",
+        )
+        .unwrap();
+
+        let file_node =
+            FileNode::from_file("--", temp_file.path(), &layers, fallback_layer, None).unwrap();
+
+        assert_eq!(file_node.metadata.len(), 2);
+        assert!(file_node.tags.is_empty());
+        assert_eq!(file_node.metadata["owner"], "Payments Team");
+        assert_eq!(file_node.metadata["version"], "2");
+        assert!(file_node.deps.contains("dep1"));
+    }
+
+    #[test]
+    fn test_tags_parsing() {
+        let layers = vec!["normal".to_string()];
+        let fallback_layer = "normal";
+
+        let temp_file = tempfile::NamedTempFile::with_suffix(".sql").unwrap();
+        std::fs::write(
+            &temp_file,
+            "-- name: test_node\n-- tags: Seed, test\n-- tags: slow\nSELECT 1;",
+        )
+        .unwrap();
+
+        let file_node =
+            FileNode::from_file("--", temp_file.path(), &layers, fallback_layer, None).unwrap();
+
+        let expected: HashSet<String> = ["seed", "test", "slow"]
+            .iter()
+            .map(|t| t.to_string())
+            .collect();
+        assert_eq!(file_node.tags, expected);
+        assert!(file_node.metadata.is_empty());
+    }
+
+    #[test]
+    fn test_strip_file_header() {
+        let content = "-- name: a\r\n-- requires: b\n\nSELECT 1;\n-- not header\n";
+        assert_eq!(
+            strip_file_header(content, "--"),
+            ("SELECT 1;\n-- not header\n", 3)
+        );
+        assert_eq!(strip_file_header("-- name: a\n", "--"), ("", 1));
+        assert_eq!(strip_file_header("SELECT 1;", "--"), ("SELECT 1;", 0));
     }
 }
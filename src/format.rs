@@ -0,0 +1,222 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::PathBuf;
+
+use log::info;
+
+use crate::exceptions::{FileNodeError, TopCatError};
+use crate::file_dag::TCGraph;
+use crate::file_node::{strip_file_header, FileNode};
+use crate::header::{self, Directive};
+use crate::output;
+
+fn join_values(values: &BTreeSet<String>) -> String {
+    values.iter().cloned().collect::<Vec<_>>().join(", ")
+}
+
+/// Rewrite the header block at the start of `content` in canonical form, leaving the rest of the
+/// content as it is.
+///
+/// Directives come first in the order `name`, `layer`, `requires`, `exists`, `tags`, with
+/// `dropped_by` merged into `requires`, `is_initial`/`is_final` written as a `layer`, and
+/// dependency lists sorted and deduplicated. Metadata and other comments follow in their
+/// original order. Directives with missing or extra spaces, like `--requires :a`, are rewritten
+/// too, as topcat only reads the canonical spelling.
+pub fn format_header(content: &str, comment_str: &str) -> String {
+    let header = header::parse_loose_header(content, comment_str);
+    if header.is_empty() {
+        return content.to_string();
+    }
+    let (body, header_line_count) = strip_file_header(content, comment_str);
+    let line_ending = if content.contains("\r\n") {
+        "\r\n"
+    } else {
+        "\n"
+    };
+
+    let mut names = Vec::new();
+    let mut layer = None;
+    let mut requires = BTreeSet::new();
+    let mut exists = BTreeSet::new();
+    let mut tags = BTreeSet::new();
+    let mut others = Vec::new();
+    for line in &header {
+        match &line.directive {
+            Directive::Name => names.push(line.value.clone()),
+            Directive::Layer if !line.value.is_empty() => layer = Some(line.value.clone()),
+            Directive::Layer => {}
+            Directive::IsInitial => layer = Some("prepend".to_string()),
+            Directive::IsFinal => layer = Some("append".to_string()),
+            Directive::Requires | Directive::DroppedBy => requires.extend(line.values()),
+            Directive::Exists => exists.extend(line.values()),
+            Directive::Tags => tags.extend(line.values()),
+            Directive::Metadata(_) | Directive::Comment => others.push(line.text.trim_end()),
+        }
+    }
+
+    let mut lines: Vec<String> = names
+        .iter()
+        .map(|name| format!("{} name: {}", comment_str, name))
+        .collect();
+    if let Some(layer) = layer {
+        lines.push(format!("{} layer: {}", comment_str, layer));
+    }
+    for (key, values) in [
+        ("requires", &requires),
+        ("exists", &exists),
+        ("tags", &tags),
+    ] {
+        if !values.is_empty() {
+            lines.push(format!("{} {}: {}", comment_str, key, join_values(values)));
+        }
+    }
+    lines.extend(others.into_iter().map(str::to_string));
+
+    let mut result = String::with_capacity(content.len());
+    for line in lines {
+        result.push_str(&line);
+        result.push_str(line_ending);
+    }
+    // Keep a blank line between the header and the body if there was one
+    let ends_with_blank_line = content
+        .lines()
+        .take(header_line_count)
+        .last()
+        .is_some_and(|line| line.trim_end_matches('\r').is_empty());
+    if ends_with_blank_line {
+        result.push_str(line_ending);
+    }
+    result.push_str(body);
+    result
+}
+
+fn same_header(a: &FileNode, b: &FileNode) -> bool {
+    a.name == b.name
+        && a.layer == b.layer
+        && a.deps == b.deps
+        && a.ensure_exists == b.ensure_exists
+        && a.tags == b.tags
+        && a.metadata == b.metadata
+}
+
+/// Format the header of every loaded file, returning the paths of the files that changed.
+///
+/// Files left out for having no name header are formatted too if their header has a misspaced
+/// name directive, like `--name:a`, so `fmt` can fix them.
+///
+/// With `check` the files are only compared with their formatted header, not rewritten. A file
+/// whose formatted header would be read differently is an error rather than being rewritten.
+pub fn format_files(graph: &TCGraph, check: bool) -> Result<Vec<PathBuf>, TopCatError> {
+    let files = graph
+        .nodes()
+        .into_iter()
+        .map(|file_node| (&file_node.path, Some(file_node.name.as_str())))
+        .chain(graph.unnamed_files().iter().map(|path| (path, None)));
+    let mut changed = Vec::new();
+    for (path, name) in files {
+        let content = fs::read_to_string(path)?;
+        let formatted = format_header(&content, &graph.comment_str);
+        if formatted == content {
+            continue;
+        }
+
+        let reparse = |header| {
+            FileNode::from_header(
+                &graph.comment_str,
+                header,
+                path,
+                graph.layers(),
+                graph.fallback_layer(),
+                name,
+            )
+        };
+        // `fmt` reads the original header loosely, and the formatted one must be read the same way
+        // when building the graph
+        let before = reparse(header::parse_loose_header(&content, &graph.comment_str));
+        let after = reparse(header::parse_header(&formatted, &graph.comment_str));
+        let unchanged = match (before, after) {
+            (Ok(before), Ok(after)) => same_header(&before, &after),
+            // Still unnamed, so not a file for topcat
+            (Err(FileNodeError::NoNameDefined(_)), _) if name.is_none() => continue,
+            _ => false,
+        };
+        if !unchanged {
+            return Err(TopCatError::InvalidFileHeader(
+                path.clone(),
+                "formatting would change what the header declares".to_string(),
+            ));
+        }
+
+        if !check {
+            info!("Formatting {}", path.display());
+            output::write_text(Some(path), &formatted)?;
+        }
+        changed.push(path.clone());
+    }
+    Ok(changed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+
+    #[test]
+    fn test_format_header() {
+        let content = "--Requires:b,a\n-- name: My_Schema.C\n-- dropped_by: a d\n-- owner: Payments\n-- is_final\n--tags: seed\n\nSELECT 1; -- requires: x\n";
+
+        assert_eq!(
+            format_header(content, "--"),
+            "-- name: my_schema.c\n-- layer: append\n-- requires: a, b, d\n-- tags: seed\n-- owner: Payments\n\nSELECT 1; -- requires: x\n"
+        );
+    }
+
+    #[test]
+    fn test_format_header_is_idempotent() {
+        let content =
+            "-- name: a\r\n-- exists: c, b\r\n-- Creates the a table\r\nCREATE TABLE a ();\r\n";
+        let formatted = format_header(content, "--");
+
+        assert_eq!(
+            formatted,
+            "-- name: a\r\n-- exists: b, c\r\n-- Creates the a table\r\nCREATE TABLE a ();\r\n"
+        );
+        assert_eq!(format_header(&formatted, "--"), formatted);
+        assert_eq!(format_header("SELECT 1;\n", "--"), "SELECT 1;\n");
+    }
+
+    #[test]
+    fn test_format_files_fixes_misspaced_directives() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path();
+        fs::write(dir.join("a.sql"), "--name:a\n--requires:b\nSELECT 1;\n").unwrap();
+        fs::write(dir.join("b.sql"), "-- name: b\n--requires :c\nSELECT 2;\n").unwrap();
+        fs::write(dir.join("c.sql"), "-- name: c\nSELECT 3;\n").unwrap();
+        fs::write(dir.join("d.sql"), "-- Not for topcat\nSELECT 4;\n").unwrap();
+        let config = test_utils::config(dir);
+        let graph = test_utils::load_graph(&config);
+        // Misspaced directives aren't read, so `a` is left out and `b` requires nothing
+        assert_eq!(
+            graph.unnamed_files(),
+            [dir.join("a.sql"), dir.join("d.sql")]
+        );
+        assert!(graph.node("b").unwrap().deps.is_empty());
+
+        assert_eq!(
+            format_files(&graph, false).unwrap(),
+            vec![dir.join("b.sql"), dir.join("a.sql")]
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("a.sql")).unwrap(),
+            "-- name: a\n-- requires: b\nSELECT 1;\n"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("b.sql")).unwrap(),
+            "-- name: b\n-- requires: c\nSELECT 2;\n"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("d.sql")).unwrap(),
+            "-- Not for topcat\nSELECT 4;\n"
+        );
+    }
+}
@@ -0,0 +1,737 @@
+use std::collections::HashMap;
+use std::fmt::{self, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use serde::Serialize;
+
+use crate::exceptions::TopCatError;
+use crate::file_dag::TCGraph;
+use crate::file_node::FileNode;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GraphFormat {
+    Dot,
+    Mermaid,
+    GraphMl,
+    Json,
+    Html,
+}
+
+impl FromStr for GraphFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "dot" => Ok(Self::Dot),
+            "mermaid" => Ok(Self::Mermaid),
+            "graphml" => Ok(Self::GraphMl),
+            "json" => Ok(Self::Json),
+            "html" => Ok(Self::Html),
+            _ => Err(format!(
+                "unknown graph format '{}', expected 'dot', 'mermaid', 'graphml', 'json' or 'html'",
+                s
+            )),
+        }
+    }
+}
+
+const HTML_MARGIN: usize = 20;
+const HTML_LABEL_HEIGHT: usize = 24;
+const HTML_NODE_HEIGHT: usize = 28;
+const HTML_ROW_HEIGHT: usize = 40;
+const HTML_COLUMN_GAP: usize = 60;
+
+const HTML_HEAD: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>topcat dependency graph</title>
+<style>
+body { font-family: sans-serif; margin: 0; }
+#details { position: fixed; top: 0; right: 0; padding: 10px; background: #fff; border: 1px solid #ccc; }
+.layer { fill: #f4f4f4; stroke: #ddd; }
+.layer-label { font-weight: bold; fill: #666; }
+.node rect { fill: #fff; stroke: #555; }
+.node text { font-size: 12px; font-family: monospace; pointer-events: none; }
+.node { cursor: pointer; }
+.edge { fill: none; stroke: #999; }
+.edge.exists { stroke-dasharray: 4 3; }
+.dimmed { opacity: 0.2; }
+.selected rect { fill: #ffe08a; stroke-width: 2; }
+.dependency rect { fill: #bfdcff; }
+.dependent rect { fill: #c8efc0; }
+.highlighted { stroke: #333; stroke-width: 2; }
+</style>
+</head>
+<body>
+"#;
+
+const HTML_SCRIPT: &str = r#"const dependencies = graph.nodes.map(() => []);
+const dependents = graph.nodes.map(() => []);
+for (const [from, to] of graph.edges) {
+  dependencies[to].push(from);
+  dependents[from].push(to);
+}
+
+function reachable(start, adjacent) {
+  const seen = new Set();
+  const stack = [start];
+  while (stack.length > 0) {
+    for (const next of adjacent[stack.pop()]) {
+      if (!seen.has(next)) {
+        seen.add(next);
+        stack.push(next);
+      }
+    }
+  }
+  return seen;
+}
+
+function escapeHtml(text) {
+  return text
+    .replace(/&/g, "&amp;")
+    .replace(/</g, "&lt;")
+    .replace(/>/g, "&gt;")
+    .replace(/"/g, "&quot;")
+    .replace(/'/g, "&#39;");
+}
+
+function select(index) {
+  const upstream = reachable(index, dependencies);
+  const downstream = reachable(index, dependents);
+  for (const element of document.querySelectorAll(".node")) {
+    const i = Number(element.dataset.index);
+    element.classList.toggle("selected", i === index);
+    element.classList.toggle("dependency", upstream.has(i));
+    element.classList.toggle("dependent", downstream.has(i));
+    element.classList.toggle("dimmed", i !== index && !upstream.has(i) && !downstream.has(i));
+  }
+  for (const element of document.querySelectorAll(".edge")) {
+    const from = Number(element.dataset.from);
+    const to = Number(element.dataset.to);
+    const highlighted = (upstream.has(from) && (to === index || upstream.has(to)))
+      || (downstream.has(to) && (from === index || downstream.has(from)));
+    element.classList.toggle("highlighted", highlighted);
+    element.classList.toggle("dimmed", !highlighted);
+  }
+  const node = graph.nodes[index];
+  document.getElementById("details").innerHTML =
+    "<b>" + escapeHtml(node.name) + "</b> (" + escapeHtml(node.layer) + ")<br>"
+    + "<a href=\"" + escapeHtml(node.href) + "\">" + escapeHtml(node.path) + "</a><br>"
+    + upstream.size + " dependencies, " + downstream.size + " dependents";
+}
+
+function clear() {
+  for (const element of document.querySelectorAll(".node, .edge")) {
+    element.classList.remove("selected", "dependency", "dependent", "dimmed", "highlighted");
+  }
+}
+
+document.getElementById("graph").addEventListener("click", (event) => {
+  const node = event.target.closest(".node");
+  if (node) {
+    select(Number(node.dataset.index));
+  } else {
+    clear();
+  }
+});
+</script>
+</body>
+</html>
+"#;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum EdgeKind {
+    Requires,
+    Exists,
+}
+
+impl EdgeKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Requires => "requires",
+            Self::Exists => "exists",
+        }
+    }
+}
+
+/// An edge from a file to a file that `requires` it or checks it `exists`, so edges follow the
+/// order the files are concatenated in.
+struct Edge {
+    from: usize,
+    to: usize,
+    kind: EdgeKind,
+}
+
+/// All layers of a built graph combined, ready to be written in one of the `GraphFormat`s.
+pub struct GraphExport<'a> {
+    layers: &'a [String],
+    nodes: Vec<&'a FileNode>,
+    edges: Vec<Edge>,
+}
+
+#[derive(Serialize)]
+struct JsonNode<'a> {
+    name: &'a str,
+    path: &'a Path,
+    layer: &'a str,
+    requires: Vec<&'a str>,
+    exists: Vec<&'a str>,
+}
+
+#[derive(Serialize)]
+struct HtmlNode<'a> {
+    name: &'a str,
+    layer: &'a str,
+    path: String,
+    href: String,
+}
+
+#[derive(Serialize)]
+struct HtmlGraph<'a> {
+    nodes: Vec<HtmlNode<'a>>,
+    edges: Vec<(usize, usize)>,
+}
+
+#[derive(Serialize)]
+struct JsonGraph<'a> {
+    layers: &'a [String],
+    nodes: Vec<JsonNode<'a>>,
+}
+
+fn escape_dot(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn escape_mermaid(s: &str) -> String {
+    s.replace('"', "#quot;")
+}
+
+fn escape_xml(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// A link to a file for the HTML report, percent-encoded. Relative paths stay relative, so the
+/// report is the same on every machine when the input directories are relative.
+fn file_href(path: &Path) -> String {
+    let mut href = String::new();
+    if path.is_absolute() {
+        href.push_str("file://");
+    }
+    let path = path.to_string_lossy().replace('\\', "/");
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                href.push(byte as char)
+            }
+            _ => href.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    href
+}
+
+impl<'a> GraphExport<'a> {
+    /// Collect the edges between `nodes`. Dependencies outside of `nodes`, eg ones removed by the
+    /// prefix filters, are left out.
+    pub fn new(layers: &'a [String], nodes: Vec<&'a FileNode>) -> GraphExport<'a> {
+        let index_map: HashMap<&str, usize> = nodes
+            .iter()
+            .enumerate()
+            .map(|(i, node)| (node.name.as_str(), i))
+            .collect();
+
+        let mut edges = Vec::new();
+        for (to, node) in nodes.iter().enumerate() {
+            for (names, kind) in [
+                (&node.deps, EdgeKind::Requires),
+                (&node.ensure_exists, EdgeKind::Exists),
+            ] {
+                let mut from: Vec<usize> = names
+                    .iter()
+                    .filter_map(|name| index_map.get(name.as_str()).copied())
+                    .collect();
+                from.sort_unstable();
+                edges.extend(from.into_iter().map(|from| Edge { from, to, kind }));
+            }
+        }
+
+        GraphExport {
+            layers,
+            nodes,
+            edges,
+        }
+    }
+
+    /// Export the nodes selected by the graph's filters, in the order they would be concatenated.
+    pub fn from_graph(graph: &'a TCGraph) -> Result<GraphExport<'a>, TopCatError> {
+        Ok(GraphExport::new(graph.layers(), graph.get_sorted_nodes()?))
+    }
+
+    pub fn render(&self, format: GraphFormat) -> Result<String, TopCatError> {
+        let mut result = String::new();
+        match format {
+            GraphFormat::Dot => self.write_dot(&mut result),
+            GraphFormat::Mermaid => self.write_mermaid(&mut result),
+            GraphFormat::GraphMl => self.write_graphml(&mut result),
+            GraphFormat::Json => {
+                result = self.to_json()?;
+                Ok(())
+            }
+            GraphFormat::Html => {
+                result = self.to_html()?;
+                Ok(())
+            }
+        }
+        .expect("Writing to a String should not fail");
+        Ok(result)
+    }
+
+    fn nodes_in_layer<'b>(&'b self, layer: &'b str) -> impl Iterator<Item = usize> + 'b {
+        (0..self.nodes.len()).filter(move |&i| self.nodes[i].layer == layer)
+    }
+
+    fn is_cross_layer(&self, edge: &Edge) -> bool {
+        self.nodes[edge.from].layer != self.nodes[edge.to].layer
+    }
+
+    /// Each layer is a cluster holding its nodes and the edges between them. Edges between layers
+    /// are drawn outside of the clusters and `exists` edges are dashed.
+    fn write_dot(&self, out: &mut String) -> fmt::Result {
+        writeln!(out, "digraph topcat {{")?;
+        for (layer_index, layer) in self.layers.iter().enumerate() {
+            let mut nodes = self.nodes_in_layer(layer).peekable();
+            if nodes.peek().is_none() {
+                continue;
+            }
+            writeln!(out, "    subgraph cluster_{} {{", layer_index)?;
+            writeln!(out, "        label=\"{}\";", escape_dot(layer))?;
+            for i in nodes {
+                let node = self.nodes[i];
+                writeln!(
+                    out,
+                    "        \"{}\" [tooltip=\"{}\"];",
+                    escape_dot(&node.name),
+                    escape_dot(&node.path.display().to_string())
+                )?;
+            }
+            for edge in self
+                .edges
+                .iter()
+                .filter(|edge| !self.is_cross_layer(edge) && self.nodes[edge.to].layer == *layer)
+            {
+                self.write_dot_edge(out, edge, "        ")?;
+            }
+            writeln!(out, "    }}")?;
+        }
+        for edge in self.edges.iter().filter(|edge| self.is_cross_layer(edge)) {
+            self.write_dot_edge(out, edge, "    ")?;
+        }
+        writeln!(out, "}}")
+    }
+
+    fn write_dot_edge(&self, out: &mut String, edge: &Edge, indent: &str) -> fmt::Result {
+        let style = match edge.kind {
+            EdgeKind::Requires => "",
+            EdgeKind::Exists => " [style=dashed]",
+        };
+        writeln!(
+            out,
+            "{}\"{}\" -> \"{}\"{};",
+            indent,
+            escape_dot(&self.nodes[edge.from].name),
+            escape_dot(&self.nodes[edge.to].name),
+            style
+        )
+    }
+
+    fn write_mermaid(&self, out: &mut String) -> fmt::Result {
+        writeln!(out, "flowchart TD")?;
+        for (layer_index, layer) in self.layers.iter().enumerate() {
+            let mut nodes = self.nodes_in_layer(layer).peekable();
+            if nodes.peek().is_none() {
+                continue;
+            }
+            writeln!(
+                out,
+                "    subgraph layer_{}[\"{}\"]",
+                layer_index,
+                escape_mermaid(layer)
+            )?;
+            for i in nodes {
+                writeln!(
+                    out,
+                    "        n{}[\"{}\"]",
+                    i,
+                    escape_mermaid(&self.nodes[i].name)
+                )?;
+            }
+            writeln!(out, "    end")?;
+        }
+        for edge in &self.edges {
+            let arrow = match edge.kind {
+                EdgeKind::Requires => "-->",
+                EdgeKind::Exists => "-.->",
+            };
+            writeln!(out, "    n{} {} n{}", edge.from, arrow, edge.to)?;
+        }
+        Ok(())
+    }
+
+    fn write_graphml(&self, out: &mut String) -> fmt::Result {
+        writeln!(out, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>")?;
+        writeln!(
+            out,
+            "<graphml xmlns=\"http://graphml.graphdrawing.org/xmlns\">"
+        )?;
+        for key in ["name", "path", "layer"] {
+            writeln!(
+                out,
+                "  <key id=\"{0}\" for=\"node\" attr.name=\"{0}\" attr.type=\"string\"/>",
+                key
+            )?;
+        }
+        writeln!(
+            out,
+            "  <key id=\"kind\" for=\"edge\" attr.name=\"kind\" attr.type=\"string\"/>"
+        )?;
+        writeln!(out, "  <graph id=\"topcat\" edgedefault=\"directed\">")?;
+        for (i, node) in self.nodes.iter().enumerate() {
+            writeln!(out, "    <node id=\"n{}\">", i)?;
+            writeln!(
+                out,
+                "      <data key=\"name\">{}</data>",
+                escape_xml(&node.name)
+            )?;
+            writeln!(
+                out,
+                "      <data key=\"path\">{}</data>",
+                escape_xml(&node.path.display().to_string())
+            )?;
+            writeln!(
+                out,
+                "      <data key=\"layer\">{}</data>",
+                escape_xml(&node.layer)
+            )?;
+            writeln!(out, "    </node>")?;
+        }
+        for edge in &self.edges {
+            writeln!(
+                out,
+                "    <edge source=\"n{}\" target=\"n{}\">",
+                edge.from, edge.to
+            )?;
+            writeln!(
+                out,
+                "      <data key=\"kind\">{}</data>",
+                edge.kind.as_str()
+            )?;
+            writeln!(out, "    </edge>")?;
+        }
+        writeln!(out, "  </graph>")?;
+        writeln!(out, "</graphml>")
+    }
+
+    /// The topological depth of each node within its layer, used to lay out the HTML report.
+    /// Nodes are in concatenation order, so a node's dependencies have their depth before it.
+    fn depths(&self) -> Vec<usize> {
+        let mut depths = vec![0; self.nodes.len()];
+        for edge in &self.edges {
+            if edge.kind == EdgeKind::Requires && !self.is_cross_layer(edge) {
+                depths[edge.to] = depths[edge.to].max(depths[edge.from] + 1);
+            }
+        }
+        depths
+    }
+
+    /// A single HTML page with the graph drawn as an SVG, a band per layer with a column per
+    /// depth. Clicking a node highlights everything it depends on and everything depending on it.
+    fn to_html(&self) -> Result<String, TopCatError> {
+        let depths = self.depths();
+        let max_label = self.nodes.iter().map(|n| n.name.chars().count()).max();
+        let node_width = (max_label.unwrap_or(0) * 7 + 20).max(120);
+        let column_width = node_width + HTML_COLUMN_GAP;
+
+        let width = depths.iter().max().map_or(0, |depth| depth + 1) * column_width;
+
+        let mut positions = vec![(0, 0); self.nodes.len()];
+        let mut bands = String::new();
+        let mut y = HTML_MARGIN;
+        for layer in self.layers {
+            let mut rows: HashMap<usize, usize> = HashMap::new();
+            for i in self.nodes_in_layer(layer) {
+                let row = rows.entry(depths[i]).or_insert(0);
+                positions[i] = (
+                    HTML_MARGIN + depths[i] * column_width,
+                    y + HTML_LABEL_HEIGHT + *row * HTML_ROW_HEIGHT,
+                );
+                *row += 1;
+            }
+            let Some(row_count) = rows.values().max() else {
+                continue;
+            };
+            let band_height = HTML_LABEL_HEIGHT + row_count * HTML_ROW_HEIGHT;
+            bands.push_str(&format!(
+                "<rect class=\"layer\" x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\"/>\
+                 <text class=\"layer-label\" x=\"{}\" y=\"{}\">{}</text>\n",
+                HTML_MARGIN / 2,
+                y - HTML_MARGIN / 2,
+                width + HTML_MARGIN,
+                band_height,
+                HTML_MARGIN,
+                y + 14,
+                escape_xml(layer)
+            ));
+            y += band_height + HTML_MARGIN;
+        }
+
+        let mut svg = String::new();
+        svg.push_str(&format!(
+            "<svg id=\"graph\" xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\">\n",
+            width + HTML_MARGIN * 2,
+            y
+        ));
+        svg.push_str(
+            "<defs><marker id=\"arrow\" viewBox=\"0 0 10 10\" refX=\"10\" refY=\"5\" \
+             markerWidth=\"8\" markerHeight=\"8\" orient=\"auto\">\
+             <path d=\"M0,0 L10,5 L0,10 z\"/></marker></defs>\n",
+        );
+        svg.push_str(&bands);
+        for edge in &self.edges {
+            let (from_x, from_y) = positions[edge.from];
+            let (to_x, to_y) = positions[edge.to];
+            // Edges to a later column go left to right, anything else, eg between layers, goes
+            // from the bottom of one node to the top of the other
+            let (x1, y1, x2, y2, control) = if to_x > from_x {
+                let (x1, x2) = (from_x + node_width, to_x);
+                let (y1, y2) = (from_y + HTML_NODE_HEIGHT / 2, to_y + HTML_NODE_HEIGHT / 2);
+                let middle = (x1 + x2) / 2;
+                (x1, y1, x2, y2, [(middle, y1), (middle, y2)])
+            } else {
+                let (x1, x2) = (from_x + node_width / 2, to_x + node_width / 2);
+                let (y1, y2) = (from_y + HTML_NODE_HEIGHT, to_y);
+                let middle = (y1 + y2) / 2;
+                (x1, y1, x2, y2, [(x1, middle), (x2, middle)])
+            };
+            svg.push_str(&format!(
+                "<path class=\"edge {}\" data-from=\"{}\" data-to=\"{}\" \
+                 d=\"M{},{} C{},{} {},{} {},{}\" marker-end=\"url(#arrow)\"/>\n",
+                edge.kind.as_str(),
+                edge.from,
+                edge.to,
+                x1,
+                y1,
+                control[0].0,
+                control[0].1,
+                control[1].0,
+                control[1].1,
+                x2,
+                y2
+            ));
+        }
+        for (i, node) in self.nodes.iter().enumerate() {
+            let (x, y) = positions[i];
+            svg.push_str(&format!(
+                "<g class=\"node\" data-index=\"{}\"><title>{}</title>\
+                 <rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" rx=\"4\"/>\
+                 <text x=\"{}\" y=\"{}\">{}</text></g>\n",
+                i,
+                escape_xml(&node.path.display().to_string()),
+                x,
+                y,
+                node_width,
+                HTML_NODE_HEIGHT,
+                x + 10,
+                y + HTML_NODE_HEIGHT / 2 + 5,
+                escape_xml(&node.name)
+            ));
+        }
+        svg.push_str("</svg>\n");
+
+        let nodes: Vec<HtmlNode> = self
+            .nodes
+            .iter()
+            .map(|node| HtmlNode {
+                name: &node.name,
+                layer: &node.layer,
+                path: node.path.display().to_string(),
+                href: file_href(&node.path),
+            })
+            .collect();
+        let edges = self.edges.iter().map(|e| (e.from, e.to)).collect();
+        let data = serde_json::to_string(&HtmlGraph { nodes, edges })
+            .map_err(std::io::Error::from)?
+            // Keep a name containing `</script>` from ending the script early
+            .replace("</", "<\\/");
+
+        let mut html = String::from(HTML_HEAD);
+        html.push_str(&svg);
+        html.push_str("<div id=\"details\">Click a file to highlight its dependencies and dependents.</div>\n<script>\nconst graph = ");
+        html.push_str(&data);
+        html.push_str(";\n");
+        html.push_str(HTML_SCRIPT);
+        Ok(html)
+    }
+
+    /// Each node with the names of the nodes it requires or checks exist.
+    fn to_json(&self) -> Result<String, TopCatError> {
+        let nodes = self
+            .nodes
+            .iter()
+            .enumerate()
+            .map(|(i, node)| {
+                let adjacent = |kind: EdgeKind| -> Vec<&str> {
+                    self.edges
+                        .iter()
+                        .filter(|edge| edge.to == i && edge.kind == kind)
+                        .map(|edge| self.nodes[edge.from].name.as_str())
+                        .collect()
+                };
+                JsonNode {
+                    name: &node.name,
+                    path: &node.path,
+                    layer: &node.layer,
+                    requires: adjacent(EdgeKind::Requires),
+                    exists: adjacent(EdgeKind::Exists),
+                }
+            })
+            .collect();
+        let graph = JsonGraph {
+            layers: self.layers,
+            nodes,
+        };
+        let mut json = serde_json::to_string_pretty(&graph).map_err(std::io::Error::from)?;
+        json.push('\n');
+        Ok(json)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, HashSet};
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn node(name: &str, layer: &str, deps: &[&str], exists: &[&str]) -> FileNode {
+        FileNode::new(
+            name.to_string(),
+            PathBuf::from(format!("{}.sql", name)),
+            deps.iter().map(|d| d.to_string()).collect(),
+            layer.to_string(),
+            exists.iter().map(|e| e.to_string()).collect(),
+            HashSet::new(),
+            BTreeMap::new(),
+        )
+    }
+
+    fn layers() -> Vec<String> {
+        vec!["prepend".to_string(), "normal".to_string()]
+    }
+
+    fn nodes() -> Vec<FileNode> {
+        vec![
+            node("setup", "prepend", &[], &[]),
+            node("a", "normal", &["setup"], &[]),
+            node("b", "normal", &["a"], &["setup"]),
+        ]
+    }
+
+    #[test]
+    fn test_dot_clusters_layers() {
+        let layers = layers();
+        let nodes = nodes();
+        let export = GraphExport::new(&layers, nodes.iter().collect());
+
+        assert_eq!(
+            export.render(GraphFormat::Dot).unwrap(),
+            "digraph topcat {
+    subgraph cluster_0 {
+        label=\"prepend\";
+        \"setup\" [tooltip=\"setup.sql\"];
+    }
+    subgraph cluster_1 {
+        label=\"normal\";
+        \"a\" [tooltip=\"a.sql\"];
+        \"b\" [tooltip=\"b.sql\"];
+        \"a\" -> \"b\";
+    }
+    \"setup\" -> \"a\";
+    \"setup\" -> \"b\" [style=dashed];
+}
+"
+        );
+    }
+
+    #[test]
+    fn test_mermaid() {
+        let layers = layers();
+        let nodes = nodes();
+        let export = GraphExport::new(&layers, nodes.iter().collect());
+
+        assert_eq!(
+            export.render(GraphFormat::Mermaid).unwrap(),
+            "flowchart TD
+    subgraph layer_0[\"prepend\"]
+        n0[\"setup\"]
+    end
+    subgraph layer_1[\"normal\"]
+        n1[\"a\"]
+        n2[\"b\"]
+    end
+    n0 --> n1
+    n1 --> n2
+    n0 -.-> n2
+"
+        );
+    }
+
+    #[test]
+    fn test_json_leaves_out_unselected_dependencies() {
+        let layers = layers();
+        let nodes = nodes();
+        let export = GraphExport::new(&layers, nodes[1..].iter().collect());
+
+        let json: serde_json::Value =
+            serde_json::from_str(&export.render(GraphFormat::Json).unwrap()).unwrap();
+
+        assert_eq!(
+            json["nodes"],
+            serde_json::json!([
+                {"name": "a", "path": "a.sql", "layer": "normal", "requires": [], "exists": []},
+                {"name": "b", "path": "b.sql", "layer": "normal", "requires": ["a"], "exists": []}
+            ])
+        );
+    }
+
+    #[test]
+    fn test_html_lays_out_by_layer_and_depth() {
+        let layers = layers();
+        let mut nodes = nodes();
+        nodes.push(node("c", "normal", &["setup"], &[]));
+        let export = GraphExport::new(&layers, nodes.iter().collect());
+
+        assert_eq!(export.depths(), vec![0, 0, 1, 0]);
+
+        let html = export.render(GraphFormat::Html).unwrap();
+        assert!(html.contains("<text class=\"layer-label\" x=\"20\" y=\"34\">prepend</text>"));
+        assert!(html.contains("\"name\":\"setup\",\"layer\":\"prepend\",\"path\":\"setup.sql\""));
+        assert!(html.contains("\"edges\":[[0,1],[1,2],[0,2],[0,3]]"));
+        assert!(html.contains("\"href\":\"setup.sql\""));
+    }
+
+    #[test]
+    fn test_file_href() {
+        assert_eq!(
+            file_href(Path::new("sql/my schema/\"a\"#1.sql")),
+            "sql/my%20schema/%22a%22%231.sql"
+        );
+        assert_eq!(
+            file_href(Path::new("/srv/sql/a.sql")),
+            "file:///srv/sql/a.sql"
+        );
+    }
+}
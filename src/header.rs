@@ -0,0 +1,330 @@
+use std::fs::File;
+use std::io::{self, BufRead};
+use std::path::{Path, PathBuf};
+
+/// Header keys with a built-in meaning that are never captured as metadata.
+const RESERVED_HEADER_KEYS: &[&str] =
+    &["name", "requires", "dropped_by", "layer", "exists", "tags"];
+
+/// What a line in a file's header block declares.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Directive {
+    Name,
+    Requires,
+    DroppedBy,
+    Layer,
+    Exists,
+    Tags,
+    /// `-- is_initial`, kept for backward compatibility with headers from before layers.
+    IsInitial,
+    /// `-- is_final`, kept for backward compatibility with headers from before layers.
+    IsFinal,
+    Metadata(String),
+    /// Any other comment in the header block.
+    Comment,
+}
+
+/// A single non-empty line of a file's header block.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeaderLine {
+    /// The line's number in the file, starting at 1.
+    pub number: usize,
+    pub text: String,
+    pub directive: Directive,
+    /// Everything after the directive, trimmed. Lowercased except for metadata values.
+    pub value: String,
+}
+
+/// Where a value is written in a file's header, to point at it in diagnostics.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeaderLocation {
+    pub path: PathBuf,
+    /// The line's number in the file, starting at 1.
+    pub line: usize,
+    /// The column the value starts at, counting characters from 1.
+    pub column: usize,
+    /// The length of the value in characters.
+    pub len: usize,
+    /// The whole header line.
+    pub text: String,
+}
+
+/// A value of a header directive and where it's written.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeaderValue {
+    pub directive: Directive,
+    /// The value lowercased, as it's used by [`HeaderLine::values`], except for metadata.
+    pub value: String,
+    pub location: HeaderLocation,
+}
+
+/// Split a `requires`, `exists` or `tags` value on commas and whitespace.
+///
+/// `tomato, potato orange` -> `["tomato", "potato", "orange"]`
+pub fn split_values(value: &str) -> Vec<String> {
+    value
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|x| !x.is_empty())
+        .map(|x| x.to_string())
+        .collect()
+}
+
+/// Parse a `-- key: value` header line into a lowercased key and its value.
+///
+/// The value keeps its original case. Keys must be a single word made of alphanumerics, `_`,
+/// `-` or `.`, so prose comments that happen to contain a colon are not picked up. Keys used
+/// by the built-in directives are never treated as metadata.
+fn parse_metadata(line: &str, comment_str: &str) -> Option<(String, String)> {
+    let (key, value) = line.strip_prefix(comment_str)?.split_once(':')?;
+    let key = key.trim().to_lowercase();
+    let is_valid_key = !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '_' || c == '-' || c == '.');
+    if !is_valid_key || RESERVED_HEADER_KEYS.contains(&key.as_str()) {
+        return None;
+    }
+    Some((key, value.trim().to_string()))
+}
+
+impl HeaderLine {
+    /// Parse a header line, recognising directives written exactly as `-- keyword: value`.
+    ///
+    /// With `loose`, directives with missing or extra spaces like `--requires :a` are recognised
+    /// too. Those are never read when building the graph, only by `fmt` so it can rewrite them in
+    /// canonical form.
+    pub fn parse(number: usize, text: &str, comment_str: &str, loose: bool) -> HeaderLine {
+        let line = text.trim().to_lowercase();
+        let directives = [
+            ("name:", Directive::Name),
+            ("requires:", Directive::Requires),
+            ("dropped_by:", Directive::DroppedBy),
+            ("layer:", Directive::Layer),
+            ("is_initial", Directive::IsInitial),
+            ("is_final", Directive::IsFinal),
+            ("exists:", Directive::Exists),
+            ("tags:", Directive::Tags),
+        ];
+        let rest = line.strip_prefix(comment_str).map(str::trim_start);
+        let (directive, value) = directives
+            .into_iter()
+            .find_map(|(keyword, directive)| {
+                let value = if loose {
+                    match keyword.strip_suffix(':') {
+                        Some(keyword) => rest?
+                            .strip_prefix(keyword)?
+                            .trim_start()
+                            .strip_prefix(':')?,
+                        None => rest?.strip_prefix(keyword)?,
+                    }
+                } else {
+                    line.strip_prefix(&format!("{} {}", comment_str, keyword))?
+                };
+                Some((directive, value.trim().to_string()))
+            })
+            .or_else(|| {
+                parse_metadata(text.trim(), comment_str)
+                    .map(|(key, value)| (Directive::Metadata(key), value))
+            })
+            .unwrap_or((Directive::Comment, String::new()));
+        HeaderLine {
+            number,
+            text: text.to_string(),
+            directive,
+            value,
+        }
+    }
+
+    pub fn values(&self) -> Vec<String> {
+        split_values(&self.value)
+    }
+
+    /// The byte offset and text of each value on the line, as written.
+    fn value_spans(&self, comment_str: &str) -> Vec<(usize, &str)> {
+        if !self.text.starts_with(comment_str) {
+            return Vec::new();
+        }
+        let rest_offset = match self.directive {
+            Directive::Comment => return Vec::new(),
+            // The keyword is the value, eg `-- is_initial`
+            Directive::IsInitial | Directive::IsFinal => comment_str.len(),
+            _ => match self.text[comment_str.len()..].find(':') {
+                Some(i) => comment_str.len() + i + 1,
+                None => return Vec::new(),
+            },
+        };
+        let rest = &self.text[rest_offset..];
+        match self.directive {
+            Directive::Requires | Directive::DroppedBy | Directive::Exists | Directive::Tags => {
+                let mut spans = Vec::new();
+                let mut start = None;
+                for (i, c) in rest.char_indices().chain([(rest.len(), ' ')]) {
+                    if c.is_whitespace() || c == ',' {
+                        if let Some(start) = start.take() {
+                            spans.push((rest_offset + start, &rest[start..i]));
+                        }
+                    } else if start.is_none() {
+                        start = Some(i);
+                    }
+                }
+                spans
+            }
+            _ => {
+                let value = rest.trim();
+                if value.is_empty() {
+                    return Vec::new();
+                }
+                let offset = rest_offset + rest.len() - rest.trim_start().len();
+                vec![(offset, value)]
+            }
+        }
+    }
+
+    /// Every value on the line with where it's written in the file at `path`.
+    pub fn located_values(&self, path: &Path, comment_str: &str) -> Vec<HeaderValue> {
+        self.value_spans(comment_str)
+            .into_iter()
+            .map(|(offset, value)| HeaderValue {
+                directive: self.directive.clone(),
+                value: match self.directive {
+                    Directive::Metadata(_) => value.to_string(),
+                    _ => value.to_lowercase(),
+                },
+                location: HeaderLocation {
+                    path: path.to_path_buf(),
+                    line: self.number,
+                    column: self.text[..offset].chars().count() + 1,
+                    len: value.chars().count(),
+                    text: self.text.clone(),
+                },
+            })
+            .collect()
+    }
+}
+
+/// The header block is every leading line that starts with `comment_str` or is empty. Empty
+/// lines are skipped.
+fn header_lines(
+    lines: impl Iterator<Item = io::Result<String>>,
+    comment_str: &str,
+    loose: bool,
+) -> Vec<HeaderLine> {
+    lines
+        .map_while(Result::ok)
+        .enumerate()
+        .take_while(|(_, line)| line.starts_with(comment_str) || line.is_empty())
+        .filter(|(_, line)| !line.is_empty())
+        .map(|(i, line)| HeaderLine::parse(i + 1, &line, comment_str, loose))
+        .collect()
+}
+
+/// Read the header block at the start of the file at `path`.
+pub fn read_header(path: &Path, comment_str: &str) -> io::Result<Vec<HeaderLine>> {
+    let reader = io::BufReader::new(File::open(path)?);
+    Ok(header_lines(reader.lines(), comment_str, false))
+}
+
+/// Parse the header block at the start of a file's content.
+pub fn parse_header(content: &str, comment_str: &str) -> Vec<HeaderLine> {
+    header_lines(
+        content.lines().map(|line| Ok(line.to_string())),
+        comment_str,
+        false,
+    )
+}
+
+/// Parse the header block at the start of a file's content, also recognising directives with
+/// missing or extra spaces.
+pub fn parse_loose_header(content: &str, comment_str: &str) -> Vec<HeaderLine> {
+    header_lines(
+        content.lines().map(|line| Ok(line.to_string())),
+        comment_str,
+        true,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_header() {
+        let header = parse_header(
+            "-- name: My_Schema.A\n\n-- requires: b, c d\n-- Owner: Payments\n-- a comment\nSELECT 1;\n-- name: ignored",
+            "--",
+        );
+
+        let parsed: Vec<(usize, Directive, &str)> = header
+            .iter()
+            .map(|line| (line.number, line.directive.clone(), line.value.as_str()))
+            .collect();
+        assert_eq!(
+            parsed,
+            vec![
+                (1, Directive::Name, "my_schema.a"),
+                (3, Directive::Requires, "b, c d"),
+                (4, Directive::Metadata("owner".to_string()), "Payments"),
+                (5, Directive::Comment, ""),
+            ]
+        );
+        assert_eq!(header[1].values(), vec!["b", "c", "d"]);
+    }
+
+    #[test]
+    fn test_parse_loose_header() {
+        let content = "--name:a\n--requires :b\n-- layer : x\n--is_final\n-- tags: c";
+        let directives = |header: Vec<HeaderLine>| -> Vec<Directive> {
+            header.into_iter().map(|line| line.directive).collect()
+        };
+
+        // Only the canonical spelling counts when building the graph
+        assert_eq!(
+            directives(parse_header(content, "--")),
+            vec![
+                Directive::Comment,
+                Directive::Comment,
+                Directive::Comment,
+                Directive::Comment,
+                Directive::Tags,
+            ]
+        );
+        let header = parse_loose_header(content, "--");
+        assert_eq!(header[1].values(), vec!["b"]);
+        assert_eq!(
+            directives(header),
+            vec![
+                Directive::Name,
+                Directive::Requires,
+                Directive::Layer,
+                Directive::IsFinal,
+                Directive::Tags,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_located_values() {
+        let header = parse_header(
+            "-- name:  My_Schema.A\n-- requires: b,  Cc d\n-- is_final",
+            "--",
+        );
+        let located: Vec<(String, usize, usize, usize)> = header
+            .iter()
+            .flat_map(|line| line.located_values(Path::new("a.sql"), "--"))
+            .map(|value| {
+                let location = value.location;
+                (value.value, location.line, location.column, location.len)
+            })
+            .collect();
+        assert_eq!(
+            located,
+            vec![
+                ("my_schema.a".to_string(), 1, 11, 11),
+                ("b".to_string(), 2, 14, 1),
+                ("cc".to_string(), 2, 18, 2),
+                ("d".to_string(), 2, 21, 1),
+                ("is_final".to_string(), 3, 4, 8),
+            ]
+        );
+    }
+}
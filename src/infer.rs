@@ -0,0 +1,558 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+use serde::Serialize;
+
+use crate::exceptions::TopCatError;
+use crate::file_node::FileNode;
+use crate::query::QueryFormat;
+
+/// Kinds of object whose `CREATE` statements are recorded as definitions.
+const DEFINED_KINDS: &[&str] = &[
+    "schema",
+    "table",
+    "view",
+    "function",
+    "procedure",
+    "type",
+    "sequence",
+];
+
+/// Words allowed between `CREATE` and the kind of object, eg `CREATE OR REPLACE TEMP VIEW`.
+const CREATE_MODIFIERS: &[&str] = &[
+    "or",
+    "replace",
+    "temp",
+    "temporary",
+    "unlogged",
+    "materialized",
+    "global",
+    "local",
+    "recursive",
+];
+
+/// Keywords followed by the name of a table or view being used.
+const REFERENCE_KEYWORDS: &[&str] = &["from", "join", "references", "into", "update"];
+
+/// Keywords and built-in type names that can be followed by `(` without calling a function, eg
+/// `VALUES (`, `USING (` or `varchar(`.
+const NOT_CALLED: &[&str] = &[
+    // Keywords
+    "all",
+    "and",
+    "any",
+    "array",
+    "as",
+    "by",
+    "case",
+    "cast",
+    "check",
+    "cube",
+    "exists",
+    "filter",
+    "foreign",
+    "from",
+    "grouping",
+    "if",
+    "in",
+    "key",
+    "lateral",
+    "not",
+    "on",
+    "or",
+    "over",
+    "partition",
+    "primary",
+    "return",
+    "returning",
+    "returns",
+    "rollup",
+    "row",
+    "select",
+    "set",
+    "sets",
+    "some",
+    "table",
+    "then",
+    "unique",
+    "using",
+    "values",
+    "when",
+    "where",
+    "window",
+    "with",
+    "within",
+    // Types
+    "bit",
+    "char",
+    "character",
+    "dec",
+    "decimal",
+    "float",
+    "int",
+    "integer",
+    "interval",
+    "nchar",
+    "numeric",
+    "nvarchar",
+    "time",
+    "timestamp",
+    "varbit",
+    "varchar",
+    "varying",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    /// An identifier or keyword, lowercased unless quoted, with qualified names joined by dots.
+    Word(String),
+    Symbol(char),
+}
+
+/// Split SQL into words and symbols, skipping comments and string literals.
+///
+/// The bodies of dollar quoted strings are kept, since that's where function bodies reference
+/// other objects.
+fn tokenize(sql: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut chars = sql.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '-' if chars.peek() == Some(&'-') => {
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut previous = ' ';
+                for c in chars.by_ref() {
+                    if previous == '*' && c == '/' {
+                        break;
+                    }
+                    previous = c;
+                }
+            }
+            // '' inside a string is an escaped quote, which this reads as two strings
+            '\'' => for _ in chars.by_ref().take_while(|c| *c != '\'') {},
+            '"' => {
+                let word: String = chars.by_ref().take_while(|c| *c != '"').collect();
+                tokens.push(Token::Word(word));
+            }
+            '$' => {
+                // Skip the `$tag$` delimiters of dollar quoting
+                let tag_len = chars
+                    .clone()
+                    .take_while(|c| c.is_alphanumeric() || *c == '_')
+                    .count();
+                if chars.clone().nth(tag_len) == Some('$') {
+                    chars.nth(tag_len);
+                }
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let mut word = c.to_lowercase().to_string();
+                while let Some(&c) = chars.peek() {
+                    if !(c.is_alphanumeric() || c == '_') {
+                        break;
+                    }
+                    word.extend(c.to_lowercase());
+                    chars.next();
+                }
+                tokens.push(Token::Word(word));
+            }
+            c if c.is_whitespace() => {}
+            c => tokens.push(Token::Symbol(c)),
+        }
+    }
+    join_qualified_names(tokens)
+}
+
+/// Join `schema`, `.`, `name` into a single `schema.name` word.
+fn join_qualified_names(tokens: Vec<Token>) -> Vec<Token> {
+    let mut joined: Vec<Token> = Vec::with_capacity(tokens.len());
+    let mut tokens = tokens.into_iter().peekable();
+    while let Some(token) = tokens.next() {
+        if token == Token::Symbol('.') {
+            if let (Some(Token::Word(qualifier)), Some(Token::Word(_))) =
+                (joined.last_mut(), tokens.peek())
+            {
+                if let Some(Token::Word(name)) = tokens.next() {
+                    qualifier.push('.');
+                    qualifier.push_str(&name);
+                }
+                continue;
+            }
+        }
+        joined.push(token);
+    }
+    joined
+}
+
+/// The objects a piece of SQL defines and the names it references.
+#[derive(Debug, Default, PartialEq)]
+struct Analysis {
+    definitions: Vec<String>,
+    references: Vec<String>,
+}
+
+fn word(tokens: &[Token], i: usize) -> Option<&str> {
+    match tokens.get(i) {
+        Some(Token::Word(word)) => Some(word),
+        _ => None,
+    }
+}
+
+/// The schema of a qualified name, which has to exist before the name can be created or used.
+fn schema_of(name: &str) -> Option<&str> {
+    name.rsplit_once('.').map(|(schema, _)| schema)
+}
+
+fn analyse(sql: &str) -> Analysis {
+    let tokens = tokenize(sql);
+    let mut analysis = Analysis::default();
+    let mut i = 0;
+    while i < tokens.len() {
+        match word(&tokens, i) {
+            Some("create") => {
+                let mut j = i + 1;
+                while word(&tokens, j).is_some_and(|w| CREATE_MODIFIERS.contains(&w)) {
+                    j += 1;
+                }
+                if word(&tokens, j).is_some_and(|w| DEFINED_KINDS.contains(&w)) {
+                    j += 1;
+                    while word(&tokens, j).is_some_and(|w| ["if", "not", "exists"].contains(&w)) {
+                        j += 1;
+                    }
+                    if let Some(name) = word(&tokens, j) {
+                        analysis.definitions.push(name.to_string());
+                        analysis
+                            .references
+                            .extend(schema_of(name).map(String::from));
+                        i = j;
+                    }
+                }
+            }
+            Some(keyword) if REFERENCE_KEYWORDS.contains(&keyword) => {
+                let mut j = i + 1;
+                if word(&tokens, j) == Some("only") {
+                    j += 1;
+                }
+                if let Some(name) = word(&tokens, j) {
+                    analysis.references.push(name.to_string());
+                    analysis
+                        .references
+                        .extend(schema_of(name).map(String::from));
+                }
+            }
+            Some(name)
+                if tokens.get(i + 1) == Some(&Token::Symbol('('))
+                    && !NOT_CALLED.contains(&name) =>
+            {
+                analysis.references.push(name.to_string());
+                analysis
+                    .references
+                    .extend(schema_of(name).map(String::from));
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    analysis
+}
+
+/// Infer each file's dependencies from the objects its SQL references that other files create.
+///
+/// Returns, for each file with inferred dependencies, the names of the files it depends on and
+/// the first reference to each of them. Unqualified references are also looked up in the `public`
+/// schema. Objects created by more than one file are ambiguous and ignored.
+pub fn infer_dependencies(
+    file_nodes: &[&FileNode],
+) -> Result<BTreeMap<String, BTreeMap<String, String>>, TopCatError> {
+    let mut analyses = Vec::with_capacity(file_nodes.len());
+    let mut definers: HashMap<String, Vec<&str>> = HashMap::new();
+    for file_node in file_nodes {
+        let analysis = analyse(&fs::read_to_string(&file_node.path)?);
+        for definition in &analysis.definitions {
+            let names = definers.entry(definition.clone()).or_default();
+            if !names.contains(&file_node.name.as_str()) {
+                names.push(&file_node.name);
+            }
+        }
+        analyses.push((file_node, analysis));
+    }
+
+    let resolve = |reference: &str| {
+        let candidates = [reference.to_string(), format!("public.{}", reference)];
+        let lookups = if reference.contains('.') {
+            &candidates[..1]
+        } else {
+            &candidates[..]
+        };
+        lookups
+            .iter()
+            .find_map(|name| definers.get(name))
+            .and_then(|names| match names[..] {
+                [name] => Some(name),
+                _ => None,
+            })
+    };
+
+    let mut inferred = BTreeMap::new();
+    for (file_node, analysis) in analyses {
+        let mut deps: BTreeMap<String, String> = BTreeMap::new();
+        for reference in &analysis.references {
+            if let Some(definer) = resolve(reference) {
+                if definer != file_node.name {
+                    deps.entry(definer.to_string())
+                        .or_insert_with(|| reference.clone());
+                }
+            }
+        }
+        if !deps.is_empty() {
+            inferred.insert(file_node.name.clone(), deps);
+        }
+    }
+    Ok(inferred)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DiscrepancyKind {
+    /// The SQL references something from a file that isn't in `requires`.
+    MissingRequires,
+    /// A file in `requires` defines objects, but none of them are referenced.
+    UnreferencedRequires,
+}
+
+impl DiscrepancyKind {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::MissingRequires => "missing-requires",
+            Self::UnreferencedRequires => "unreferenced-requires",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Discrepancy<'a> {
+    pub kind: DiscrepancyKind,
+    pub file_node: &'a FileNode,
+    pub dependency: String,
+    pub message: String,
+}
+
+#[derive(Serialize)]
+struct JsonDiscrepancy<'a> {
+    code: &'a str,
+    name: &'a str,
+    path: &'a Path,
+    dependency: &'a str,
+    message: &'a str,
+}
+
+/// Compare the declared `requires` of each file with the ones inferred from its SQL.
+pub fn discrepancies<'a>(file_nodes: &[&'a FileNode]) -> Result<Vec<Discrepancy<'a>>, TopCatError> {
+    let inferred = infer_dependencies(file_nodes)?;
+    let definers: Vec<&str> = inferred
+        .values()
+        .flat_map(|deps| deps.keys().map(String::as_str))
+        .collect();
+    let no_deps = BTreeMap::new();
+
+    let mut discrepancies = Vec::new();
+    for file_node in file_nodes {
+        let inferred_deps = inferred.get(&file_node.name).unwrap_or(&no_deps);
+        for (dep, reference) in inferred_deps {
+            if !file_node.deps.contains(dep) {
+                discrepancies.push(Discrepancy {
+                    kind: DiscrepancyKind::MissingRequires,
+                    file_node,
+                    dependency: dep.clone(),
+                    message: format!("references {}, add {} to requires", reference, dep),
+                });
+            }
+        }
+        let mut declared: Vec<&String> = file_node.deps.iter().collect();
+        declared.sort();
+        for dep in declared {
+            // Only files that something was inferred to depend on are known to define objects
+            if definers.contains(&dep.as_str()) && !inferred_deps.contains_key(dep) {
+                discrepancies.push(Discrepancy {
+                    kind: DiscrepancyKind::UnreferencedRequires,
+                    file_node,
+                    dependency: dep.clone(),
+                    message: format!("requires {} but doesn't reference anything it creates", dep),
+                });
+            }
+        }
+    }
+    Ok(discrepancies)
+}
+
+pub fn write_discrepancies(
+    discrepancies: &[Discrepancy],
+    format: QueryFormat,
+    writer: &mut dyn Write,
+) -> Result<(), TopCatError> {
+    match format {
+        QueryFormat::Text => {
+            for discrepancy in discrepancies {
+                writeln!(
+                    writer,
+                    "{}: {}: {}",
+                    discrepancy.file_node.path.display(),
+                    discrepancy.kind.code(),
+                    discrepancy.message
+                )?;
+            }
+        }
+        QueryFormat::Json => {
+            let entries: Vec<JsonDiscrepancy> = discrepancies
+                .iter()
+                .map(|discrepancy| JsonDiscrepancy {
+                    code: discrepancy.kind.code(),
+                    name: &discrepancy.file_node.name,
+                    path: &discrepancy.file_node.path,
+                    dependency: &discrepancy.dependency,
+                    message: &discrepancy.message,
+                })
+                .collect();
+            serde_json::to_writer_pretty(&mut *writer, &entries).map_err(std::io::Error::from)?;
+            writeln!(writer)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::test_utils;
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|v| v.to_string()).collect()
+    }
+
+    #[test]
+    fn test_tokenize() {
+        assert_eq!(
+            tokenize("SELECT \"Name\" FROM My_Schema . a -- x.y\n/* b */ WHERE c = 'd.e' || $$f$$"),
+            vec![
+                Token::Word("select".to_string()),
+                Token::Word("Name".to_string()),
+                Token::Word("from".to_string()),
+                Token::Word("my_schema.a".to_string()),
+                Token::Word("where".to_string()),
+                Token::Word("c".to_string()),
+                Token::Symbol('='),
+                Token::Symbol('|'),
+                Token::Symbol('|'),
+                Token::Word("f".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_analyse() {
+        let analysis = analyse(
+            "CREATE OR REPLACE VIEW app.active_users AS
+               SELECT u.id, app.full_name(u) FROM ONLY app.users u
+               JOIN roles r ON r.id = u.role_id;
+             CREATE TABLE IF NOT EXISTS app.audit (user_id int REFERENCES app.users (id));",
+        );
+
+        assert_eq!(
+            analysis.definitions,
+            strings(&["app.active_users", "app.audit"])
+        );
+        assert_eq!(
+            analysis.references,
+            strings(&[
+                "app",
+                "app.full_name",
+                "app",
+                "app.users",
+                "app",
+                "roles",
+                "app",
+                "app.users",
+                "app",
+                "app.users",
+                "app"
+            ])
+        );
+    }
+
+    #[test]
+    fn test_analyse_ignores_keywords_and_types_before_parens() {
+        let analysis = analyse(
+            "CREATE TABLE app.t (
+               id varchar(10), n numeric(5, 2), at timestamp(3), PRIMARY KEY (id), UNIQUE (n),
+               CHECK (n IN (1, 2)), FOREIGN KEY (id) REFERENCES app.users (id)
+             );
+             INSERT INTO app.t (id) VALUES ('a');
+             SELECT count(*) FILTER (WHERE n > 0) OVER (PARTITION BY id), CAST(n AS int)
+               FROM app.t JOIN app.u USING (id) WHERE EXISTS (SELECT app.f(1));",
+        );
+
+        let mut references = analysis.references;
+        references.sort();
+        references.dedup();
+        assert_eq!(
+            references,
+            strings(&["app", "app.f", "app.t", "app.u", "app.users", "count"])
+        );
+    }
+
+    #[test]
+    fn test_infer_dependencies() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let write = |file_name: &str, content: &str| {
+            std::fs::write(temp_dir.path().join(file_name), content).unwrap();
+        };
+        write("a.sql", "-- name: schema\nCREATE SCHEMA app;");
+        write(
+            "b.sql",
+            "-- name: users\nCREATE TABLE app.users (id int);\nCREATE TABLE roles (id int);",
+        );
+        write(
+            "c.sql",
+            "-- name: report\n-- requires: schema\nCREATE VIEW app.report AS SELECT * FROM app.users JOIN roles USING (id);",
+        );
+        write("d.sql", "-- name: other\n-- requires: users\nSELECT 1;");
+
+        let config = test_utils::config(temp_dir.path());
+        let graph = test_utils::load_graph(&config);
+
+        let mut text = Vec::new();
+        let discrepancies = discrepancies(&graph.nodes()).unwrap();
+        write_discrepancies(&discrepancies, QueryFormat::Text, &mut text).unwrap();
+        let dir = temp_dir.path().display();
+        assert_eq!(
+            String::from_utf8(text).unwrap(),
+            format!(
+                "{dir}/b.sql: missing-requires: references app, add schema to requires
+{dir}/c.sql: missing-requires: references app.users, add users to requires
+{dir}/d.sql: unreferenced-requires: requires users but doesn't reference anything it creates
+"
+            )
+        );
+
+        let graph = test_utils::build_graph(&Config {
+            infer_deps: true,
+            ..config
+        });
+        assert!(graph.node("report").unwrap().deps.contains("users"));
+        let sorted: Vec<&str> = graph
+            .get_sorted_nodes()
+            .unwrap()
+            .iter()
+            .map(|node| node.name.as_str())
+            .collect();
+        assert_eq!(sorted, vec!["schema", "users", "report", "other"]);
+    }
+}
@@ -3,7 +3,8 @@ use std::path::{Path, PathBuf};
 use std::{fs, io};
 
 use glob::glob;
-use log::error;
+
+use crate::warning::{self, WarningCode};
 
 fn is_hidden_dir_or_file(path: &Path) -> Result<bool, io::Error> {
     let file_or_dir_name = match path.file_name() {
@@ -32,7 +33,11 @@ pub fn walk_dir(dir: &Path, include_hidden: bool) -> io::Result<HashSet<PathBuf>
     let entries = match fs::read_dir(dir) {
         Ok(entries) => entries,
         Err(e) => {
-            error!("Read dir failed: {}", e);
+            warning::emit(
+                WarningCode::UnreadableDir,
+                Some(dir),
+                format!("Skipping {}, it can't be read: {}", dir.display(), e),
+            );
             return Ok(files);
         }
     };
@@ -51,23 +56,42 @@ pub fn walk_dir(dir: &Path, include_hidden: bool) -> io::Result<HashSet<PathBuf>
                     files.extend(subdir_files);
                 }
             }
-            Err(e) => error!("Read dir failed: {}", e),
+            Err(e) => warning::emit(
+                WarningCode::UnreadableDir,
+                Some(dir),
+                format!("Skipping an entry of {}: {}", dir.display(), e),
+            ),
         }
     }
 
     Ok(files)
 }
 
+/// Find the path of a file relative to the input directory it was found in.
+///
+/// When input directories are nested, the innermost one containing the file is used.
+pub fn relative_to_input_dir<'a>(path: &'a Path, input_dirs: &[PathBuf]) -> Option<&'a Path> {
+    input_dirs
+        .iter()
+        .filter_map(|dir| path.strip_prefix(dir).ok())
+        .min_by_key(|relative| relative.components().count())
+}
+
 pub fn glob_files(glob_patterns: &[String]) -> Result<HashSet<PathBuf>, glob::PatternError> {
     let mut paths = HashSet::new();
 
     for pattern in glob_patterns {
         let entries = glob(pattern)?;
         for entry in entries {
-            if let Ok(path) = entry {
-                paths.insert(path);
-            } else if let Err(e) = entry {
-                error!("Failed to read entry: {:?}", e);
+            match entry {
+                Ok(path) => {
+                    paths.insert(path);
+                }
+                Err(e) => warning::emit(
+                    WarningCode::GlobError,
+                    Some(e.path()),
+                    format!("Skipping a match of '{}': {}", pattern, e),
+                ),
             }
         }
     }
@@ -171,7 +195,7 @@ mod tests {
         let glob_pattern = format!("{}/*.txt", working_dir_path.display());
 
         // Call the glob_files function with the glob pattern
-        let result = glob_files(&vec![glob_pattern]);
+        let result = glob_files(&[glob_pattern]);
 
         // Assert the expected files are returned
         match result {
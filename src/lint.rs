@@ -0,0 +1,373 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use crate::exceptions::TopCatError;
+use crate::file_dag::TCGraph;
+use crate::file_node::FileNode;
+use crate::header::{self, Directive, HeaderLine};
+use crate::output;
+use crate::query::QueryFormat;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LintKind {
+    SelfDependency,
+    DuplicateDependency,
+    ExistsAlsoRequired,
+    RedundantRequires,
+    Unused,
+}
+
+impl LintKind {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::SelfDependency => "self-dependency",
+            Self::DuplicateDependency => "duplicate-dependency",
+            Self::ExistsAlsoRequired => "exists-also-requires",
+            Self::RedundantRequires => "redundant-requires",
+            Self::Unused => "unused",
+        }
+    }
+}
+
+/// The value at `index` of header line `line`, which `--fix` removes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Removal {
+    line: usize,
+    index: usize,
+}
+
+/// A problem found in a file's header.
+#[derive(Debug)]
+pub struct LintIssue<'a> {
+    pub kind: LintKind,
+    pub file_node: &'a FileNode,
+    /// The header line the problem is on, if it's on a single line.
+    pub line: Option<usize>,
+    pub message: String,
+    fix: Option<Removal>,
+}
+
+impl LintIssue<'_> {
+    pub fn is_fixable(&self) -> bool {
+        self.fix.is_some()
+    }
+}
+
+#[derive(Serialize)]
+struct JsonIssue<'a> {
+    code: &'a str,
+    name: &'a str,
+    path: &'a Path,
+    line: Option<usize>,
+    message: &'a str,
+    fixable: bool,
+}
+
+/// Every value of the header lines with one of `directives`, with where it is in the header.
+fn header_values<'h>(
+    header: &'h [HeaderLine],
+    directives: &'h [Directive],
+) -> impl Iterator<Item = (Removal, String)> + 'h {
+    header
+        .iter()
+        .filter(|line| directives.contains(&line.directive))
+        .flat_map(|line| {
+            line.values().into_iter().enumerate().map(|(index, value)| {
+                let removal = Removal {
+                    line: line.number,
+                    index,
+                };
+                (removal, value)
+            })
+        })
+}
+
+fn lint_node<'a>(
+    graph: &'a TCGraph,
+    file_node: &'a FileNode,
+    header: &[HeaderLine],
+    issues: &mut Vec<LintIssue<'a>>,
+) {
+    let issue = |kind, removal: Removal, message| LintIssue {
+        kind,
+        file_node,
+        line: Some(removal.line),
+        message,
+        fix: Some(removal),
+    };
+
+    let mut requires = HashSet::new();
+    for (removal, dep) in header_values(header, &[Directive::Requires, Directive::DroppedBy]) {
+        if dep == file_node.name {
+            issues.push(issue(
+                LintKind::SelfDependency,
+                removal,
+                format!("{} requires itself", dep),
+            ));
+        } else if !requires.insert(dep.clone()) {
+            issues.push(issue(
+                LintKind::DuplicateDependency,
+                removal,
+                format!("{} is already required", dep),
+            ));
+        } else if let Some(chain) = redundant_requires(graph, file_node, &dep) {
+            issues.push(issue(
+                LintKind::RedundantRequires,
+                removal,
+                format!(
+                    "{} is already required through {}",
+                    dep,
+                    chain
+                        .iter()
+                        .map(|node| node.name.as_str())
+                        .collect::<Vec<_>>()
+                        .join(" requires ")
+                ),
+            ));
+        }
+    }
+
+    let mut exists = HashSet::new();
+    for (removal, target) in header_values(header, &[Directive::Exists]) {
+        if !exists.insert(target.clone()) {
+            issues.push(issue(
+                LintKind::DuplicateDependency,
+                removal,
+                format!("{} is already checked to exist", target),
+            ));
+        } else if file_node.deps.contains(&target) {
+            issues.push(issue(
+                LintKind::ExistsAlsoRequired,
+                removal,
+                format!(
+                    "{} is also required, which already makes sure it exists",
+                    target
+                ),
+            ));
+        }
+    }
+}
+
+/// If another of `file_node`'s dependencies already requires `dep`, directly or indirectly, the
+/// chain of requires from that dependency to `dep`.
+fn redundant_requires<'a>(
+    graph: &'a TCGraph,
+    file_node: &FileNode,
+    dep: &str,
+) -> Option<Vec<&'a FileNode>> {
+    let others = file_node
+        .deps
+        .iter()
+        .map(String::as_str)
+        .filter(|other| *other != dep && *other != file_node.name);
+    let chain = graph.dependency_path(others, dep)?;
+    // With a cycle every dependency in it would look redundant, but removing them all would lose
+    // the ordering, so those are left to the cycle check.
+    let through_cycle = chain.iter().any(|node| node.name == file_node.name)
+        || graph.dependency_path([dep], &chain[0].name).is_some();
+    if through_cycle {
+        None
+    } else {
+        Some(chain)
+    }
+}
+
+/// Check the headers of every loaded file for redundant, duplicated and unused dependencies.
+///
+/// The graph only needs to be loaded, not validated, so headers with problems that would stop a
+/// build, like a file requiring itself, can still be linted.
+///
+/// # Arguments
+///
+/// * `graph` - The TCGraph with its nodes loaded.
+/// * `allow_unused` - Patterns of the names of files that are allowed to have nothing require them.
+pub fn lint<'a>(
+    graph: &'a TCGraph,
+    allow_unused: &[glob::Pattern],
+) -> Result<Vec<LintIssue<'a>>, TopCatError> {
+    let nodes = graph.nodes();
+    let mut issues = Vec::new();
+
+    let mut referenced: HashSet<&str> = HashSet::new();
+    for file_node in &nodes {
+        referenced.extend(
+            file_node
+                .deps
+                .iter()
+                .chain(&file_node.ensure_exists)
+                .filter(|name| **name != file_node.name)
+                .map(String::as_str),
+        );
+    }
+
+    for file_node in nodes {
+        let header = header::read_header(&file_node.path, &graph.comment_str)?;
+        lint_node(graph, file_node, &header, &mut issues);
+
+        let allowed = allow_unused
+            .iter()
+            .any(|pattern| pattern.matches(&file_node.name));
+        if !allowed && !referenced.contains(file_node.name.as_str()) {
+            let name_line = header.iter().find(|line| line.directive == Directive::Name);
+            issues.push(LintIssue {
+                kind: LintKind::Unused,
+                file_node,
+                line: name_line.map(|line| line.number),
+                message: format!("nothing requires {}", file_node.name),
+                fix: None,
+            });
+        }
+    }
+    Ok(issues)
+}
+
+/// Rewrite a header line without the values at `indexes`, or `None` to drop it when nothing is
+/// left. The text up to the directive's colon is kept as it is.
+fn remove_values(line: &str, comment_str: &str, indexes: &[usize]) -> Option<String> {
+    let colon = comment_str.len() + line[comment_str.len()..].find(':')?;
+    let kept: Vec<String> = header::split_values(&line[colon + 1..])
+        .into_iter()
+        .enumerate()
+        .filter(|(index, _)| !indexes.contains(index))
+        .map(|(_, value)| value)
+        .collect();
+    if kept.is_empty() {
+        None
+    } else {
+        Some(format!("{} {}", &line[..=colon], kept.join(", ")))
+    }
+}
+
+/// Remove the values of the fixable issues from the headers they were found in.
+///
+/// Returns the number of issues fixed.
+pub fn fix(issues: &[LintIssue], comment_str: &str) -> Result<usize, TopCatError> {
+    let mut removals: BTreeMap<&PathBuf, Vec<Removal>> = BTreeMap::new();
+    for issue in issues {
+        if let Some(removal) = issue.fix {
+            removals
+                .entry(&issue.file_node.path)
+                .or_default()
+                .push(removal);
+        }
+    }
+
+    let mut fixed = 0;
+    for (path, removals) in removals {
+        let content = fs::read_to_string(path)?;
+        let mut result = String::with_capacity(content.len());
+        for (i, line) in content.split_inclusive('\n').enumerate() {
+            let indexes: Vec<usize> = removals
+                .iter()
+                .filter(|removal| removal.line == i + 1)
+                .map(|removal| removal.index)
+                .collect();
+            if indexes.is_empty() {
+                result.push_str(line);
+                continue;
+            }
+            let text = line.trim_end_matches(['\r', '\n']);
+            if let Some(rewritten) = remove_values(text, comment_str, &indexes) {
+                result.push_str(&rewritten);
+                result.push_str(&line[text.len()..]);
+            }
+        }
+        output::write_text(Some(path), &result)?;
+        fixed += removals.len();
+    }
+    Ok(fixed)
+}
+
+pub fn write_issues(
+    issues: &[LintIssue],
+    format: QueryFormat,
+    writer: &mut dyn Write,
+) -> Result<(), TopCatError> {
+    match format {
+        QueryFormat::Text => {
+            for issue in issues {
+                match issue.line {
+                    Some(line) => write!(writer, "{}:{}", issue.file_node.path.display(), line)?,
+                    None => write!(writer, "{}", issue.file_node.path.display())?,
+                }
+                writeln!(writer, ": {}: {}", issue.kind.code(), issue.message)?;
+            }
+        }
+        QueryFormat::Json => {
+            let entries: Vec<JsonIssue> = issues
+                .iter()
+                .map(|issue| JsonIssue {
+                    code: issue.kind.code(),
+                    name: &issue.file_node.name,
+                    path: &issue.file_node.path,
+                    line: issue.line,
+                    message: &issue.message,
+                    fixable: issue.is_fixable(),
+                })
+                .collect();
+            serde_json::to_writer_pretty(&mut *writer, &entries).map_err(std::io::Error::from)?;
+            writeln!(writer)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{self, write_node};
+
+    #[test]
+    fn test_remove_values() {
+        assert_eq!(
+            remove_values("-- requires: a, B c", "--", &[1]),
+            Some("-- requires: a, c".to_string())
+        );
+        assert_eq!(remove_values("-- exists: a", "--", &[0]), None);
+    }
+
+    #[test]
+    fn test_lint_and_fix() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        write_node(temp_dir.path(), "a.sql", "-- name: a");
+        write_node(temp_dir.path(), "b.sql", "-- name: b\n-- requires: a, b");
+        write_node(
+            temp_dir.path(),
+            "c.sql",
+            "-- name: c\n-- requires: a b\n-- requires: b\n-- exists: a",
+        );
+        let config = test_utils::config(temp_dir.path());
+        let graph = test_utils::load_graph(&config);
+
+        let allow_unused = vec![glob::Pattern::new("c*").unwrap()];
+        let issues = lint(&graph, &allow_unused).unwrap();
+        let mut text = Vec::new();
+        write_issues(&issues, QueryFormat::Text, &mut text).unwrap();
+        let text = String::from_utf8(text).unwrap();
+        let dir = temp_dir.path().display();
+        assert_eq!(
+            text,
+            format!(
+                "{dir}/b.sql:2: self-dependency: b requires itself
+{dir}/c.sql:2: redundant-requires: a is already required through b requires a
+{dir}/c.sql:3: duplicate-dependency: b is already required
+{dir}/c.sql:4: exists-also-requires: a is also required, which already makes sure it exists
+"
+            )
+        );
+
+        assert_eq!(fix(&issues, "--").unwrap(), 4);
+        assert_eq!(
+            std::fs::read_to_string(temp_dir.path().join("b.sql")).unwrap(),
+            "-- name: b\n-- requires: a\nSELECT 1;\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(temp_dir.path().join("c.sql")).unwrap(),
+            "-- name: c\n-- requires: b\nSELECT 1;\n"
+        );
+    }
+}
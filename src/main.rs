@@ -1,23 +1,423 @@
-use std::collections::HashMap;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 use std::string::ToString;
 
 use env_logger::Builder;
-use log::{error, info, LevelFilter};
+use log::{debug, info, LevelFilter};
+use structopt::clap::ErrorKind;
 use structopt::StructOpt;
 
 use file_dag::TCGraph;
 
-use crate::exceptions::TopCatError;
+use crate::config::{LineEnding, SplitBy, SuffixMode};
+use crate::diagnostic::{Diagnostic, MessageFormat};
+use crate::exceptions::{ErrorClass, TopCatError};
+use crate::graph_export::{GraphExport, GraphFormat};
+use crate::query::QueryFormat;
+use crate::source_map::SourceMap;
+use crate::tag_expr::TagExpr;
+use crate::warning::{Policy, WarningCode};
 
 mod config;
+mod diagnostic;
 mod exceptions;
+mod explain;
 mod file_dag;
 mod file_node;
+mod format;
 mod fs;
+mod graph_export;
+mod header;
+mod infer;
 mod io_utils;
+mod lint;
 mod output;
+mod plan;
+mod query;
+mod rename;
+mod scaffold;
+mod source_map;
 mod stable_topo;
+mod tag_expr;
+mod template;
+#[cfg(test)]
+mod test_utils;
+mod warning;
+
+fn parse_metadata_filter(filter: &str) -> Result<(String, String), String> {
+    match filter.split_once('=') {
+        Some((key, value)) if !key.trim().is_empty() => {
+            Ok((key.trim().to_lowercase(), value.trim().to_string()))
+        }
+        _ => Err(format!("expected KEY=VALUE, got '{}'", filter)),
+    }
+}
+
+#[derive(Debug, StructOpt)]
+struct QueryOpt {
+    #[structopt(
+        long = "format",
+        help = "Print 'text', one name per line, or 'json'",
+        value_name = "FORMAT",
+        default_value = "text"
+    )]
+    format: QueryFormat,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    #[structopt(
+        about = "Print the ordered files and their headers as JSON instead of concatenating them"
+    )]
+    Plan,
+    #[structopt(
+        about = "Export the dependency graph of every layer to the output file, or stdout without one"
+    )]
+    Graph {
+        #[structopt(
+            long = "format",
+            help = "The format to export the graph in: 'dot', 'mermaid', 'graphml', 'json' or 'html'",
+            value_name = "FORMAT",
+            default_value = "dot"
+        )]
+        format: GraphFormat,
+    },
+    #[structopt(about = "Explain what places a file after each of the files before it")]
+    Why {
+        #[structopt(help = "The name of the file to explain")]
+        name: String,
+    },
+    #[structopt(about = "Explain which filters and dependencies put a file in the output")]
+    WhyIncluded {
+        #[structopt(help = "The name of the file to explain")]
+        name: String,
+    },
+    #[structopt(about = "List the files a file requires")]
+    Deps {
+        #[structopt(help = "The name of the file")]
+        name: String,
+        #[structopt(long = "transitive", help = "Include everything they require in turn")]
+        transitive: bool,
+        #[structopt(flatten)]
+        query: QueryOpt,
+    },
+    #[structopt(about = "List the files that require a file")]
+    Rdeps {
+        #[structopt(help = "The name of the file")]
+        name: String,
+        #[structopt(
+            long = "transitive",
+            help = "Include everything requiring them in turn"
+        )]
+        transitive: bool,
+        #[structopt(flatten)]
+        query: QueryOpt,
+    },
+    #[structopt(about = "Show the shortest chain of requires between two files")]
+    Path {
+        #[structopt(help = "The name of the file to start from")]
+        from: String,
+        #[structopt(help = "The name of the file to end at")]
+        to: String,
+        #[structopt(flatten)]
+        query: QueryOpt,
+    },
+    #[structopt(about = "List the files that don't require anything")]
+    Roots {
+        #[structopt(flatten)]
+        query: QueryOpt,
+    },
+    #[structopt(about = "List the files that nothing requires")]
+    Leaves {
+        #[structopt(flatten)]
+        query: QueryOpt,
+    },
+    #[structopt(about = "Check file headers for redundant, duplicated and unused dependencies")]
+    Lint {
+        #[structopt(
+            long = "allow-unused",
+            help = "Don't report files whose name matches this glob pattern as unused. eg 'migrations.*'",
+            value_name = "PATTERN",
+            parse(try_from_str = glob::Pattern::new)
+        )]
+        allow_unused: Vec<glob::Pattern>,
+        #[structopt(
+            long = "fix",
+            help = "Remove redundant, duplicated and self dependencies from the headers"
+        )]
+        fix: bool,
+        #[structopt(
+            long = "format",
+            help = "Print 'text', one issue per line, or 'json'",
+            value_name = "FORMAT",
+            default_value = "text"
+        )]
+        format: QueryFormat,
+    },
+    #[structopt(about = "Rewrite the header of every file in canonical form")]
+    Fmt {
+        #[structopt(
+            long = "check",
+            help = "Only list the files that aren't formatted, failing if there are any"
+        )]
+        check: bool,
+    },
+    #[structopt(
+        about = "Rename a file's name header and every requires, dropped_by and exists naming it. Use --dry-run to see a diff"
+    )]
+    Rename {
+        #[structopt(help = "The current name")]
+        old: String,
+        #[structopt(help = "The new name")]
+        new: String,
+    },
+    #[structopt(about = "Create a file with a header, checking it fits into the graph")]
+    New {
+        #[structopt(help = "Where to create the file")]
+        path: PathBuf,
+        #[structopt(long = "name", help = "The name of the file", value_name = "NAME")]
+        name: String,
+        #[structopt(
+            long = "requires",
+            help = "The names of the files it requires, which must already exist",
+            value_name = "NAMES"
+        )]
+        requires: Vec<String>,
+        #[structopt(
+            long = "layer",
+            help = "The layer to put it in, instead of the fallback layer",
+            value_name = "LAYER"
+        )]
+        layer: Option<String>,
+        #[structopt(long = "tags", help = "Tags for the file", value_name = "TAGS")]
+        tags: Vec<String>,
+    },
+    #[structopt(
+        about = "Compare the requires in each header with the dependencies inferred from its SQL"
+    )]
+    Infer {
+        #[structopt(
+            long = "format",
+            help = "Print 'text', one discrepancy per line, or 'json'",
+            value_name = "FORMAT",
+            default_value = "text"
+        )]
+        format: QueryFormat,
+    },
+    #[structopt(
+        about = "Resolve a line of a generated output file back to the input file and line"
+    )]
+    Locate {
+        #[structopt(
+            help = "The output file and line number, eg 'output.sql:4812'",
+            value_name = "FILE:LINE",
+            parse(try_from_str = source_map::parse_location)
+        )]
+        location: (PathBuf, usize),
+        #[structopt(
+            long = "source-map-file",
+            help = "Read the source map from this file instead of FILE.map.json",
+            value_name = "FILE"
+        )]
+        source_map_file: Option<PathBuf>,
+    },
+}
+
+/// Run a subcommand on the loaded graph.
+///
+/// Returns whether it passed, for the commands that check something, like `lint`.
+fn run_command(
+    graph: &TCGraph,
+    command: &Command,
+    config: &config::Config,
+) -> Result<bool, TopCatError> {
+    let stdout = &mut std::io::stdout();
+    match command {
+        Command::Lint {
+            allow_unused,
+            fix,
+            format,
+        } => {
+            let mut issues = lint::lint(graph, allow_unused)?;
+            if *fix {
+                let fixed = lint::fix(&issues, &graph.comment_str)?;
+                info!("Fixed {} issues", fixed);
+                issues.retain(|issue| !issue.is_fixable());
+            }
+            lint::write_issues(&issues, *format, stdout)?;
+            Ok(issues.is_empty())
+        }
+        Command::Fmt { check } => {
+            let changed = format::format_files(graph, *check)?;
+            if *check {
+                for path in &changed {
+                    println!("{}", path.display());
+                }
+            }
+            Ok(!*check || changed.is_empty())
+        }
+        Command::New {
+            path,
+            name,
+            requires,
+            layer,
+            tags,
+        } => {
+            // Header values are read in lowercase, so check them the same way
+            let split = |values: &[String]| {
+                values
+                    .iter()
+                    .flat_map(|value| header::split_values(&value.to_lowercase()))
+                    .collect()
+            };
+            let new_file = scaffold::NewFile {
+                name: name.trim().to_lowercase(),
+                layer: layer.as_ref().map(|layer| layer.trim().to_lowercase()),
+                requires: split(requires),
+                tags: split(tags),
+            };
+            scaffold::create(graph, path, &new_file)?;
+            Ok(true)
+        }
+        Command::Rename { old, new } => {
+            let (old, new) = (old.trim().to_lowercase(), new.trim().to_lowercase());
+            let edits = rename::rename(graph, &old, &new)?;
+            if config.dry_run {
+                rename::write_diff(&edits, stdout)?;
+            } else {
+                let changed = rename::apply(&edits)?;
+                info!("Renamed {} to {} in {} files", old, new, changed);
+            }
+            Ok(true)
+        }
+        Command::Infer { format } => {
+            let discrepancies = infer::discrepancies(&graph.nodes())?;
+            infer::write_discrepancies(&discrepancies, *format, stdout)?;
+            Ok(!discrepancies
+                .iter()
+                .any(|d| d.kind == infer::DiscrepancyKind::MissingRequires))
+        }
+        Command::Plan => plan::generate(graph, stdout).map(|_| true),
+        Command::Graph { format } => {
+            let rendered = GraphExport::from_graph(graph)?.render(*format)?;
+            output::write_text(config.output.as_deref(), &rendered)?;
+            Ok(true)
+        }
+        Command::Why { name } => explain::why(graph, name, stdout).map(|_| true),
+        Command::WhyIncluded { name } => explain::why_included(graph, name, stdout).map(|_| true),
+        Command::Deps {
+            name,
+            transitive,
+            query,
+        } => {
+            let nodes = graph.dependencies(name, *transitive)?;
+            query::write_nodes(&nodes, query.format, stdout).map(|_| true)
+        }
+        Command::Rdeps {
+            name,
+            transitive,
+            query,
+        } => {
+            let nodes = graph.dependents(name, *transitive)?;
+            query::write_nodes(&nodes, query.format, stdout).map(|_| true)
+        }
+        Command::Path { from, to, query } => {
+            if let Some(name) = [from, to]
+                .into_iter()
+                .find(|name| graph.node(name).is_none())
+            {
+                return Err(TopCatError::NodeNotFound(name.clone()));
+            }
+            let nodes = match graph.dependency_path([from.as_str()], to) {
+                Some(nodes) => nodes,
+                None => {
+                    let nodes = graph
+                        .dependency_path([to.as_str()], from)
+                        .ok_or_else(|| TopCatError::NoDependencyPath(from.clone(), to.clone()))?;
+                    info!(
+                        "{} doesn't require {}, showing the path from {} to {} instead",
+                        from, to, to, from
+                    );
+                    nodes
+                }
+            };
+            query::write_nodes(&nodes, query.format, stdout).map(|_| true)
+        }
+        Command::Roots { query } => {
+            query::write_nodes(&graph.roots(), query.format, stdout).map(|_| true)
+        }
+        Command::Leaves { query } => {
+            query::write_nodes(&graph.leaves(), query.format, stdout).map(|_| true)
+        }
+        // Handled before the graph is loaded
+        Command::Locate { .. } => Ok(true),
+    }
+}
+
+/// The exit code of a command whose check failed, one of `lint`, `fmt --check` or `infer`.
+fn check_failed(command: &Command) -> ExitCode {
+    match command {
+        Command::Lint { .. } => ErrorClass::Header,
+        Command::Infer { .. } => ErrorClass::Dependency,
+        Command::Fmt { .. } => ErrorClass::OutOfDate,
+        _ => ErrorClass::Failure,
+    }
+    .into()
+}
+
+/// Report the warnings emitted during the run and `error`, if it failed, as diagnostics pointing
+/// at the header lines involved, returning the exit code.
+///
+/// Every format goes to stderr, so diagnostics don't mix with output written to stdout. Human
+/// readable diagnostics are followed by a summary of the warnings.
+fn report(error: Option<&TopCatError>, graph: Option<&TCGraph>, format: MessageFormat) -> ExitCode {
+    let warnings = warning::take();
+    let mut diagnostics: Vec<Diagnostic> = warnings.iter().map(diagnostic::from_warning).collect();
+    diagnostics.extend(error.map(|error| diagnostic::from_error(error, graph)));
+    match format {
+        MessageFormat::Human => {
+            for diagnostic in &diagnostics {
+                eprint!("{}", diagnostic);
+            }
+            if let Some(summary) = warning::summary(&warnings) {
+                eprintln!("{}", summary);
+            }
+        }
+        MessageFormat::Json => {
+            for diagnostic in &diagnostics {
+                eprintln!("{}", diagnostic.to_json());
+            }
+        }
+        MessageFormat::Sarif if !diagnostics.is_empty() => {
+            eprintln!("{}", diagnostic::to_sarif(&diagnostics))
+        }
+        MessageFormat::Sarif => {}
+    }
+    match error {
+        Some(error) => error.class().into(),
+        None if warnings.iter().any(|w| w.level == warning::Level::Deny) => {
+            ErrorClass::DeniedWarning.into()
+        }
+        None => ExitCode::SUCCESS,
+    }
+}
+
+fn locate(output: &Path, line: usize, source_map_file: Option<PathBuf>) -> Result<(), TopCatError> {
+    let source_map_path = source_map_file.unwrap_or_else(|| SourceMap::path_for(output));
+    let source_map = SourceMap::read(&source_map_path)?;
+    match source_map.locate(line) {
+        Some((section, source_line)) => {
+            println!(
+                "{}:{} ({})",
+                section.path.display(),
+                source_line,
+                section.name
+            );
+            Ok(())
+        }
+        None => Err(TopCatError::UnmappedLine(output.to_path_buf(), line)),
+    }
+}
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "basic")]
@@ -26,7 +426,8 @@ struct Opt {
         short = "i",
         long = "input-dirs",
         help = "Paths to directories containing files to be concatenated",
-        value_name = "DIRS"
+        value_name = "DIRS",
+        global = true
     )]
     input_dirs: Vec<PathBuf>,
 
@@ -34,7 +435,8 @@ struct Opt {
         short = "e",
         long = "include-exts",
         help = "Only include files with the given file extensions",
-        value_name = "EXTENSIONS"
+        value_name = "EXTENSIONS",
+        global = true
     )]
     include_file_extensions: Option<Vec<String>>,
 
@@ -42,7 +444,8 @@ struct Opt {
         short = "E",
         long = "exclude-exts",
         help = "Exclude files with the given file extensions",
-        value_name = "EXTENSIONS"
+        value_name = "EXTENSIONS",
+        global = true
     )]
     exclude_file_extensions: Option<Vec<String>>,
 
@@ -50,7 +453,8 @@ struct Opt {
         short = "g",
         long = "include-glob",
         help = "Only include files matching glob pattern. Must be relative to the working directory, not the input directories. eg 'src/**/*.rs'",
-        value_name = "PATTERN"
+        value_name = "PATTERN",
+        global = true
     )]
     include_globs: Option<Vec<String>>,
 
@@ -58,23 +462,26 @@ struct Opt {
         short = "G",
         long = "exclude-glob",
         help = "Exclude files matching given glob pattern. Must be relative to the working directory, not the input directories. eg 'src/**/*.rs'",
-        value_name = "PATTERN"
+        value_name = "PATTERN",
+        global = true
     )]
     exclude_globs: Option<Vec<String>>,
 
     #[structopt(
         short = "o",
         long = "output-file",
-        help = "Path to generate combined output file",
-        value_name = "FILE"
+        help = "Path to generate combined output file, or '-' to write it to stdout",
+        value_name = "FILE",
+        global = true
     )]
-    output: PathBuf,
+    output: Option<PathBuf>,
 
     #[structopt(
         short = "c",
         long = "comment-prefix",
         help = "The string used to denote a comment. eg '--'",
-        default_value = "--"
+        default_value = "--",
+        global = true
     )]
     comment_str: String,
 
@@ -82,7 +489,8 @@ struct Opt {
         short = "s",
         long = "file-separator",
         help = "Add this between each concatenated file in the output. eg '---'",
-        default_value = "------------------------------------------------------------------------------------------------------------------------"
+        default_value = "------------------------------------------------------------------------------------------------------------------------",
+        global = true
     )]
     file_separator_str: String,
 
@@ -90,90 +498,412 @@ struct Opt {
         short = "a",
         long = "file-suffix",
         help = "Add this string to the end of files if it does not exist. eg ';'",
-        default_value = ";"
+        default_value = ";",
+        global = true
     )]
     ensure_each_file_ends_with_str: String,
 
-    #[structopt(long = "include-hidden", help = "Include hidden files and directories")]
+    #[structopt(
+        long = "suffix-mode",
+        help = "How to add the file suffix: 'plain' appends it unless the file ends with it, 'comment-aware' looks past trailing comments and adds it after the last code",
+        default_value = "plain",
+        value_name = "MODE",
+        global = true
+    )]
+    suffix_mode: SuffixMode,
+
+    #[structopt(
+        long = "block-comment-start",
+        help = "The string that starts a block comment, used by --suffix-mode comment-aware",
+        default_value = "/*",
+        global = true
+    )]
+    block_comment_start: String,
+
+    #[structopt(
+        long = "block-comment-end",
+        help = "The string that ends a block comment, used by --suffix-mode comment-aware",
+        default_value = "*/",
+        global = true
+    )]
+    block_comment_end: String,
+
+    #[structopt(
+        long = "header-template",
+        help = "Template for the start of the output. Placeholders: {comment}, {separator}, {command}, {count}, {inputs}, {hash}",
+        value_name = "TEMPLATE",
+        global = true
+    )]
+    header_template: Option<String>,
+
+    #[structopt(
+        long = "file-prefix-template",
+        help = "Template written before each file. Placeholders: {name}, {path}, {layer}, {index}, {deps}, {tags}, {meta.KEY}, {comment}, {separator}, {command}, {count}",
+        value_name = "TEMPLATE",
+        global = true
+    )]
+    file_prefix_template: Option<String>,
+
+    #[structopt(
+        long = "file-suffix-template",
+        help = "Template written after each file. Takes the same placeholders as --file-prefix-template",
+        value_name = "TEMPLATE",
+        global = true
+    )]
+    file_suffix_template: Option<String>,
+
+    #[structopt(
+        long = "footer-template",
+        help = "Template for the end of the output. Placeholders: {comment}, {separator}, {command}, {count}, {inputs}",
+        value_name = "TEMPLATE",
+        global = true
+    )]
+    footer_template: Option<String>,
+
+    #[structopt(
+        long = "reproducible",
+        help = "Make paths in the output relative to the working directory and leave --verbose out of the regenerate command, so output is the same on every machine",
+        global = true
+    )]
+    reproducible: bool,
+
+    #[structopt(
+        long = "header-hash",
+        help = "Add a sha256 hash of the concatenated content to the header",
+        global = true
+    )]
+    header_hash: bool,
+
+    #[structopt(
+        long = "header-inputs",
+        help = "List the concatenated input files in the header",
+        global = true
+    )]
+    header_inputs: bool,
+
+    #[structopt(
+        long = "source-map",
+        help = "Write a JSON source map next to the output file, mapping output lines back to the input files. See 'topcat locate'",
+        global = true
+    )]
+    source_map: bool,
+
+    #[structopt(
+        long = "split-by-layer",
+        help = "Write one output file per layer, plus a manifest listing them in order",
+        conflicts_with = "split-by-dir",
+        global = true
+    )]
+    split_by_layer: bool,
+
+    #[structopt(
+        long = "split-by-dir",
+        help = "Write one output file per top level directory of the input directories, plus a manifest listing them in order",
+        global = true
+    )]
+    split_by_dir: bool,
+
+    #[structopt(
+        long = "max-chunk-bytes",
+        help = "Split the output so no file is larger than this many bytes, where possible",
+        value_name = "BYTES",
+        global = true
+    )]
+    max_chunk_bytes: Option<usize>,
+
+    #[structopt(
+        long = "max-chunk-files",
+        help = "Split the output so no file contains more than this many input files",
+        value_name = "COUNT",
+        global = true
+    )]
+    max_chunk_files: Option<usize>,
+
+    #[structopt(
+        long = "strip-bom",
+        help = "Remove the UTF-8 byte order mark from the start of each file",
+        global = true
+    )]
+    strip_bom: bool,
+
+    #[structopt(
+        long = "line-endings",
+        help = "Convert all line endings in the output to 'lf' or 'crlf'",
+        value_name = "STYLE",
+        global = true
+    )]
+    line_endings: Option<LineEnding>,
+
+    #[structopt(
+        long = "strip-header",
+        help = "Remove each file's header block (the leading comment lines with its name, requires etc) from the output",
+        global = true
+    )]
+    strip_header: bool,
+
+    #[structopt(
+        long = "include-hidden",
+        help = "Include hidden files and directories",
+        global = true
+    )]
     include_hidden_files_and_directories: bool,
 
-    #[structopt(short = "v", long = "verbose", help = "Print debug information")]
+    #[structopt(
+        short = "v",
+        long = "verbose",
+        help = "Print debug information",
+        global = true
+    )]
     verbose: bool,
 
     #[structopt(
         long = "include-prefix",
         help = "Only include nodes with the given prefixes in the output",
-        value_name = "PREFIXES"
+        value_name = "PREFIXES",
+        global = true
     )]
     include_node_prefixes: Option<Vec<String>>,
 
     #[structopt(
         long = "exclude-prefix",
         help = "Exclude nodes with the given prefixes from the output",
-        value_name = "PREFIXES"
+        value_name = "PREFIXES",
+        global = true
     )]
     exclude_node_prefixes: Option<Vec<String>>,
 
     #[structopt(
         long = "subdir-filter",
         help = "Only include files from this subdirectory and their dependencies",
-        value_name = "SUBDIR"
+        value_name = "SUBDIR",
+        global = true
     )]
     subdir_filter: Option<PathBuf>,
 
+    #[structopt(
+        long = "where",
+        help = "Only include nodes whose header metadata matches. eg 'owner=payments'",
+        value_name = "KEY=VALUE",
+        parse(try_from_str = parse_metadata_filter),
+        global = true
+    )]
+    metadata_filters: Option<Vec<(String, String)>>,
+
+    #[structopt(
+        long = "include-tag",
+        help = "Only include nodes whose tags match the expression, and their dependencies. eg 'seed and not slow'",
+        value_name = "EXPRESSION",
+        global = true
+    )]
+    include_tags: Option<Vec<TagExpr>>,
+
+    #[structopt(
+        long = "exclude-tag",
+        help = "Exclude nodes whose tags match the expression unless another node depends on them. eg 'slow or flaky'",
+        value_name = "EXPRESSION",
+        global = true
+    )]
+    exclude_tags: Option<Vec<TagExpr>>,
+
     #[structopt(
         short = "d",
         long = "dry-run",
-        help = "Only print the output, do not write to file"
+        help = "Only print the output, do not write to file",
+        global = true
     )]
     dry_run: bool,
 
     #[structopt(
         long = "layers",
         help = "Comma-separated list of layer names in order",
-        value_name = "LAYERS"
+        value_name = "LAYERS",
+        global = true
     )]
     layers: Option<String>,
 
+    #[structopt(
+        long = "config",
+        help = "JSON file defining the layers and their settings, or '-' to read it from stdin",
+        value_name = "FILE",
+        conflicts_with = "layers",
+        global = true
+    )]
+    config_file: Option<PathBuf>,
+
     #[structopt(
         long = "fallback-layer",
         help = "Default layer for nodes without explicit layer declaration",
-        value_name = "LAYER"
+        value_name = "LAYER",
+        global = true
     )]
     fallback_layer: Option<String>,
+
+    #[structopt(
+        long = "name-from-path",
+        help = "Derive a name from the path relative to the input directory for files without a name header. eg 'my_schema/functions/a.sql' -> 'my_schema.functions.a'",
+        conflicts_with = "require-name",
+        global = true
+    )]
+    name_from_path: bool,
+
+    #[structopt(
+        long = "require-name",
+        help = "Fail if any file is missing a name header instead of ignoring it",
+        global = true
+    )]
+    require_name: bool,
+
+    #[structopt(
+        long = "infer-deps",
+        help = "Also order files by the dependencies inferred from the objects their SQL creates and references",
+        global = true
+    )]
+    infer_deps: bool,
+
+    #[structopt(
+        long = "message-format",
+        help = "How to report errors: 'human', 'json' for a JSON object per line, or 'sarif'",
+        default_value = "human",
+        value_name = "FORMAT",
+        global = true
+    )]
+    message_format: MessageFormat,
+
+    #[structopt(
+        long = "deny",
+        help = "Fail on the warning with this code, eg 'no-name'. Can be given more than once",
+        value_name = "CODE",
+        number_of_values = 1,
+        global = true
+    )]
+    deny: Vec<WarningCode>,
+
+    #[structopt(
+        long = "allow",
+        help = "Ignore the warning with this code, eg 'no-name'. Can be given more than once",
+        value_name = "CODE",
+        number_of_values = 1,
+        global = true
+    )]
+    allow: Vec<WarningCode>,
+
+    #[structopt(
+        long = "deny-warnings",
+        help = "Fail on every warning that isn't allowed with --allow",
+        global = true
+    )]
+    deny_warnings: bool,
+
+    #[structopt(
+        long = "check",
+        help = "Don't write the output, fail if the files written would differ from what's already there"
+    )]
+    check: bool,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
 }
-fn main() -> Result<(), TopCatError> {
-    let opt = Opt::from_args();
+fn main() -> ExitCode {
+    let opt = match Opt::from_args_safe() {
+        Ok(opt) => opt,
+        Err(e) => match e.kind {
+            ErrorKind::HelpDisplayed | ErrorKind::VersionDisplayed => e.exit(),
+            _ => {
+                eprintln!("{}", e.message);
+                return ErrorClass::Config.into();
+            }
+        },
+    };
+    let message_format = opt.message_format;
+    warning::set_policy(Policy {
+        deny_warnings: opt.deny_warnings,
+        deny: opt.deny,
+        allow: opt.allow,
+    });
     if opt.verbose {
         Builder::new().filter(None, LevelFilter::Debug).init();
-    } else {
+    } else if message_format == MessageFormat::Human {
         Builder::new().filter(None, LevelFilter::Info).init();
+    } else {
+        // Leave stderr to the diagnostics so tools can parse it
+        Builder::new().filter(None, LevelFilter::Warn).init();
     }
 
-    // Parse layers from CLI or use defaults
-    let layers = if let Some(layers_str) = opt.layers {
-        layers_str
-            .split(',')
-            .map(|s| s.trim().to_string())
-            .collect()
-    } else {
-        vec![
-            "prepend".to_string(),
-            "normal".to_string(),
-            "append".to_string(),
-        ]
+    if let Some(Command::Locate {
+        location: (output, line),
+        source_map_file,
+    }) = opt.command
+    {
+        return match locate(&output, line, source_map_file) {
+            Ok(()) => report(None, None, message_format),
+            Err(e) => report(Some(&e), None, message_format),
+        };
+    }
+
+    let config_file = match opt.config_file.as_deref().map(config::ConfigFile::read) {
+        Some(Ok(config_file)) => Some(config_file),
+        Some(Err(e)) => return report(Some(&e), None, message_format),
+        None => None,
+    };
+
+    // Take layers from the config file or CLI, or use defaults
+    let (layers, layer_definitions, config_fallback_layer) = match config_file {
+        Some(config_file) => (
+            config_file
+                .layers
+                .iter()
+                .map(|layer| layer.name.clone())
+                .collect(),
+            config_file.layers,
+            config_file.fallback_layer,
+        ),
+        None => {
+            let layers = if let Some(layers_str) = opt.layers {
+                layers_str
+                    .split(',')
+                    .map(|s| s.trim().to_string())
+                    .collect()
+            } else {
+                vec![
+                    "prepend".to_string(),
+                    "normal".to_string(),
+                    "append".to_string(),
+                ]
+            };
+            (layers, Vec::new(), None)
+        }
     };
 
     // Set fallback layer
-    let fallback_layer = opt.fallback_layer.unwrap_or_else(|| "normal".to_string());
+    let fallback_layer = opt
+        .fallback_layer
+        .or(config_fallback_layer)
+        .unwrap_or_else(|| "normal".to_string());
 
     // Validate that fallback layer exists in layers
     if !layers.contains(&fallback_layer) {
-        eprintln!(
-            "Error: Fallback layer '{}' is not in the layers list: {:?}",
+        let e = TopCatError::InvalidArguments(format!(
+            "Fallback layer '{}' is not in the layers list: {:?}",
             fallback_layer, layers
+        ));
+        return report(Some(&e), None, message_format);
+    }
+
+    if opt.command.is_none() && opt.output.is_none() && !opt.dry_run {
+        let e = TopCatError::InvalidArguments(
+            "--output-file is required unless --dry-run is given. Use '-o -' to write to stdout"
+                .to_string(),
         );
-        std::process::exit(1);
+        return report(Some(&e), None, message_format);
+    }
+
+    let writes_to_file = opt
+        .output
+        .as_ref()
+        .is_some_and(|output| output.as_os_str() != "-");
+    if opt.check && (opt.command.is_some() || opt.dry_run || !writes_to_file) {
+        let e = TopCatError::InvalidArguments("--check compares the output with the file given to --output-file, so it needs one and can't be used with --dry-run or a command".to_string());
+        return report(Some(&e), None, message_format);
     }
 
     let config = config::Config {
@@ -186,31 +916,88 @@ fn main() -> Result<(), TopCatError> {
         comment_str: opt.comment_str,
         file_separator_str: opt.file_separator_str,
         file_end_str: opt.ensure_each_file_ends_with_str,
+        suffix_mode: opt.suffix_mode,
+        block_comment_start: opt.block_comment_start,
+        block_comment_end: opt.block_comment_end,
+        header_template: opt.header_template.as_deref().map(template::unescape),
+        file_prefix_template: opt.file_prefix_template.as_deref().map(template::unescape),
+        file_suffix_template: opt.file_suffix_template.as_deref().map(template::unescape),
+        footer_template: opt.footer_template.as_deref().map(template::unescape),
+        reproducible: opt.reproducible,
+        header_hash: opt.header_hash,
+        header_inputs: opt.header_inputs,
+        source_map: opt.source_map,
+        split_by: if opt.split_by_layer {
+            Some(SplitBy::Layer)
+        } else if opt.split_by_dir {
+            Some(SplitBy::Directory)
+        } else {
+            None
+        },
+        max_chunk_bytes: opt.max_chunk_bytes,
+        max_chunk_files: opt.max_chunk_files,
+        strip_bom: opt.strip_bom,
+        line_endings: opt.line_endings,
+        strip_header: opt.strip_header,
         include_hidden: opt.include_hidden_files_and_directories,
         verbose: opt.verbose,
         include_node_prefixes: opt.include_node_prefixes.as_deref(),
         exclude_node_prefixes: opt.exclude_node_prefixes.as_deref(),
+        metadata_filters: opt.metadata_filters.as_deref(),
+        include_tags: opt.include_tags.as_deref(),
+        exclude_tags: opt.exclude_tags.as_deref(),
         dry_run: opt.dry_run,
+        check: opt.check,
         subdir_filter: opt.subdir_filter,
         layers,
+        layer_definitions,
         fallback_layer,
+        name_from_path: opt.name_from_path,
+        require_name: opt.require_name,
+        infer_deps: opt.infer_deps,
     };
 
     let mut filedag = TCGraph::new(&config);
-    let res = filedag.build_graph();
+    // Linting, formatting and inference work on headers that would fail validation, so only need
+    // them loaded
+    let res = match opt.command {
+        Some(
+            Command::Lint { .. }
+            | Command::Fmt { .. }
+            | Command::Infer { .. }
+            | Command::New { .. }
+            | Command::Rename { .. },
+        ) => filedag.load_nodes(),
+        _ => filedag
+            .build_graph()
+            .and_then(|()| filedag.check_selection()),
+    };
     match res {
         Ok(_) => {
             info!("Graph built successfully!");
         }
-        Err(e) => {
-            eprintln!("Error Encountered:\n{}\n\nExiting.", e);
-            std::process::exit(1);
-        }
+        Err(e) => return report(Some(&e), Some(&filedag), message_format),
+    }
+    // Stop before changing anything when a warning is denied
+    if warning::has_denied() {
+        return report(None, Some(&filedag), message_format);
+    }
+
+    if let Some(command) = &opt.command {
+        return match run_command(&filedag, command, &config) {
+            Ok(true) => report(None, Some(&filedag), message_format),
+            Ok(false) => {
+                report(None, Some(&filedag), message_format);
+                check_failed(command)
+            }
+            Err(e) => report(Some(&e), Some(&filedag), message_format),
+        };
     }
 
     if config.verbose {
-        for layer in &config.layers {
-            println!("{} Graph: {:#?}", layer, filedag.graph_as_dot(layer)?);
+        match GraphExport::from_graph(&filedag).and_then(|graph| graph.render(GraphFormat::Dot)) {
+            Ok(dot) => debug!("Graph:\n{}", dot),
+            Err(e) => return report(Some(&e), Some(&filedag), message_format),
         }
     }
 
@@ -219,14 +1006,8 @@ fn main() -> Result<(), TopCatError> {
     match result {
         Ok(()) => {
             info!("Generation Successful!");
+            report(None, None, message_format)
         }
-        Err(e) => {
-            let mut map = HashMap::new();
-            map.insert(1, e);
-            error!("Initialization Failure:\n{:#?}\n\nExiting.", map);
-            std::process::exit(1);
-        }
+        Err(e) => report(Some(&e), None, message_format),
     }
-
-    Ok(())
 }
@@ -1,14 +1,232 @@
+use std::collections::{HashMap, HashSet};
 use std::env;
-use std::fs::File;
+use std::ffi::OsStr;
+use std::fs;
 use std::io::Write;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use log::info;
+use sha2::{Digest, Sha256};
+use tempfile::NamedTempFile;
 
-use crate::config::Config;
+use crate::config::{Config, Layer, LineEnding, SplitBy, SuffixMode};
 use crate::exceptions::TopCatError;
 use crate::file_dag::TCGraph;
+use crate::file_node::{strip_file_header, FileNode};
 use crate::fs::FileSystem;
+use crate::io_utils;
+use crate::source_map::{Section, SourceMap};
+use crate::template;
+
+/// Passing this as the output file writes the output to stdout.
+pub const STDOUT_PATH: &str = "-";
+
+pub const DEFAULT_HEADER_TEMPLATE: &str =
+    "{comment} This file was generated by topcat. To regenerate run:\n{comment}\n{comment} topcat {command}\n\n";
+const DEFAULT_HEADER_HASH_TEMPLATE: &str = "{comment}\n{comment} sha256: {hash}\n";
+const DEFAULT_HEADER_INPUTS_TEMPLATE: &str = "{comment}\n{comment} Input files:\n{inputs}\n";
+pub const DEFAULT_FILE_PREFIX_TEMPLATE: &str = "{separator}\n{comment} {path}\n";
+pub const DEFAULT_FILE_SUFFIX_TEMPLATE: &str = "\n";
+pub const DEFAULT_FOOTER_TEMPLATE: &str = "";
+
+/// Build the header template used when `--header-template` isn't given, adding the hash and
+/// input file lines that were asked for.
+fn default_header_template(with_hash: bool, with_inputs: bool) -> String {
+    let mut header = DEFAULT_HEADER_TEMPLATE.trim_end_matches('\n').to_string();
+    header.push('\n');
+    if with_hash {
+        header.push_str(DEFAULT_HEADER_HASH_TEMPLATE);
+    }
+    if with_inputs {
+        header.push_str(DEFAULT_HEADER_INPUTS_TEMPLATE);
+    }
+    header.push('\n');
+    header
+}
+
+/// The header template of each output file, the default one unless it is configured.
+fn chunk_header_template(config: &Config) -> String {
+    match &config.header_template {
+        Some(header_template) => header_template.clone(),
+        None => default_header_template(config.header_hash, config.header_inputs),
+    }
+}
+
+/// Display a path relative to `base_dir` when it is inside it, otherwise as given.
+fn display_path(path: &Path, base_dir: Option<&Path>) -> String {
+    match base_dir {
+        Some(base_dir) if path.is_absolute() => path
+            .strip_prefix(base_dir)
+            .unwrap_or(path)
+            .display()
+            .to_string(),
+        _ => path.display().to_string(),
+    }
+}
+
+/// The short options that don't take a value, so can be combined as in `-vd`. A test checks this
+/// against the options of the command line.
+const SHORT_FLAGS: &[char] = &['v', 'd'];
+
+/// Remove `-v` from a group of combined short options, eg `-vi` becomes `-i`.
+///
+/// Returns what is left of the group, if anything, and whether it ends with an option waiting for
+/// its value in the next argument. Only the flags before the first option taking a value are
+/// looked at, as the rest of the group is that option's value.
+fn drop_verbose_flag(shorts: &str) -> (Option<String>, bool) {
+    let mut kept = String::new();
+    for (i, c) in shorts.char_indices() {
+        if c == 'v' {
+            continue;
+        }
+        kept.push(c);
+        if !SHORT_FLAGS.contains(&c) {
+            let value = &shorts[i + c.len_utf8()..];
+            kept.push_str(value);
+            return (Some(format!("-{}", kept)), value.is_empty());
+        }
+    }
+    ((!kept.is_empty()).then(|| format!("-{}", kept)), false)
+}
+
+/// Long options that don't change the output, and whether each takes a value. A test checks that
+/// every option of the command line is either listed here or known to change the output.
+const UNRECORDED_OPTIONS: &[(&str, bool)] = &[
+    ("--verbose", false),
+    ("--check", false),
+    ("--deny-warnings", false),
+    ("--message-format", true),
+    ("--deny", true),
+    ("--allow", true),
+];
+
+/// The command line to record in the header, without the options that don't change the output,
+/// so `--check` passes whichever of them it's run with.
+///
+/// `-v` is dropped too, including when combined with other short options, and `--output-file` is
+/// written as `-o`. The value of any other long option is only known to be one when it is
+/// attached, as in `--file-separator=-v`.
+fn recorded_args(args: impl Iterator<Item = String>) -> Vec<String> {
+    let mut kept = Vec::new();
+    let mut is_value = false;
+    let mut skip_value = false;
+    for arg in args {
+        if skip_value {
+            skip_value = false;
+            continue;
+        }
+        if is_value {
+            kept.push(arg);
+            is_value = false;
+            continue;
+        }
+        let shorts = arg
+            .strip_prefix('-')
+            .filter(|shorts| !shorts.is_empty() && !shorts.starts_with('-'));
+        if let Some(shorts) = shorts {
+            let (group, takes_value) = drop_verbose_flag(shorts);
+            kept.extend(group);
+            is_value = takes_value;
+            continue;
+        }
+        let (option, value) = match arg.split_once('=') {
+            Some((option, value)) => (option, Some(value)),
+            None => (arg.as_str(), None),
+        };
+        if let Some((_, takes_value)) = UNRECORDED_OPTIONS.iter().find(|(name, _)| *name == option)
+        {
+            skip_value = *takes_value && value.is_none();
+        } else if option == "--output-file" {
+            kept.push("-o".to_string());
+            match value {
+                Some(value) => kept.push(value.to_string()),
+                None => is_value = true,
+            }
+        } else {
+            kept.push(arg);
+        }
+    }
+    kept
+}
+
+/// Rebuild the command line so it is the same on every machine, by making absolute paths inside
+/// `base_dir` relative to it.
+fn normalise_command(args: Vec<String>, base_dir: Option<&Path>) -> String {
+    args.into_iter()
+        .map(|arg| match arg.split_once('=') {
+            Some((flag, value)) if flag.starts_with("--") => {
+                format!("{}={}", flag, display_path(Path::new(value), base_dir))
+            }
+            _ => display_path(Path::new(&arg), base_dir),
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+const UTF8_BOM: char = '\u{feff}';
+
+/// Convert every line ending in `content` to the given style.
+fn normalise_line_endings(content: &str, line_ending: LineEnding) -> String {
+    let lf = content.replace("\r\n", "\n");
+    match line_ending {
+        LineEnding::Lf => lf,
+        LineEnding::Crlf => lf.replace('\n', "\r\n"),
+    }
+}
+
+fn count_lines(content: &str) -> usize {
+    content.matches('\n').count()
+}
+
+/// Look up a per-file template placeholder.
+///
+/// `{deps}` and `{tags}` are sorted and comma separated. `{meta.KEY}` is the value of the `KEY`
+/// header metadata, or empty if the file doesn't declare it.
+fn file_placeholder(file_node: &FileNode, key: &str, base_dir: Option<&Path>) -> Option<String> {
+    let sorted_join = |items: &HashSet<String>| {
+        let mut items: Vec<&str> = items.iter().map(String::as_str).collect();
+        items.sort_unstable();
+        items.join(", ")
+    };
+    match key {
+        "name" => Some(file_node.name.clone()),
+        "path" => Some(display_path(&file_node.path, base_dir)),
+        "layer" => Some(file_node.layer.clone()),
+        "deps" => Some(sorted_join(&file_node.deps)),
+        "tags" => Some(sorted_join(&file_node.tags)),
+        _ => key.strip_prefix("meta.").map(|meta_key| {
+            file_node
+                .metadata
+                .get(meta_key)
+                .cloned()
+                .unwrap_or_default()
+        }),
+    }
+}
+
+/// Look up a placeholder set by a layer's definition: its `{separator}` if it overrides it.
+fn layer_placeholder(layer: Option<&Layer>, key: &str) -> Option<String> {
+    match key {
+        "separator" => layer.and_then(|layer| layer.separator.clone()),
+        _ => None,
+    }
+}
+
+/// Render a layer's preamble or postamble, which can use `{layer}` and the global placeholders.
+fn render_layer_template(
+    template: Option<&str>,
+    layer_name: &str,
+    layer: Option<&Layer>,
+    lookup: &dyn Fn(&str) -> Option<String>,
+) -> Result<String, TopCatError> {
+    match template {
+        Some(template) => template::render(template, &|key: &str| match key {
+            "layer" => Some(layer_name.to_string()),
+            _ => layer_placeholder(layer, key).or_else(|| lookup(key)),
+        }),
+        None => Ok(String::new()),
+    }
+}
 
 /// Append a string to the end of the file content.
 ///
@@ -49,46 +267,676 @@ fn append_string_to_file_content(file_content: String, append_str: &str) -> Stri
     content
 }
 
+/// The PostgreSQL dollar quote starting at byte `i` of `content`, eg `$$` or `$body$`.
+///
+/// A tag is empty or an identifier not starting with a digit, so parameters like `$1` aren't
+/// mistaken for quotes, and a `$` inside an identifier like `a$b$` doesn't start one.
+fn dollar_quote_tag(content: &str, i: usize) -> Option<&str> {
+    let rest = content[i..].strip_prefix('$')?;
+    let is_identifier_char = |c: char| c.is_alphanumeric() || c == '_';
+    if content[..i]
+        .chars()
+        .next_back()
+        .is_some_and(is_identifier_char)
+    {
+        return None;
+    }
+    let end = rest.find('$')?;
+    let tag = &rest[..end];
+    let is_tag = tag.chars().all(is_identifier_char)
+        && !tag.chars().next().is_some_and(|c| c.is_ascii_digit());
+    is_tag.then(|| &content[i..i + end + 2])
+}
+
+/// Find the end of the last piece of code in `content`, skipping trailing line and block comments.
+///
+/// Single quoted strings, double quoted identifiers and dollar quoted bodies are skipped so
+/// comment markers inside them aren't mistaken for comments. Block comments may be nested, as
+/// they can be in PostgreSQL. Returns 0 if the content is only comments and whitespace.
+fn find_code_end(content: &str, line_comment: &str, block_start: &str, block_end: &str) -> usize {
+    let mut code_end = 0;
+    let mut block_depth = 0;
+    let mut i = 0;
+    while i < content.len() {
+        let rest = &content[i..];
+        if block_depth > 0 {
+            if !block_end.is_empty() && rest.starts_with(block_end) {
+                block_depth -= 1;
+                i += block_end.len();
+            } else if !block_start.is_empty() && rest.starts_with(block_start) {
+                block_depth += 1;
+                i += block_start.len();
+            } else {
+                i += rest.chars().next().map_or(1, char::len_utf8);
+            }
+        } else if !line_comment.is_empty() && rest.starts_with(line_comment) {
+            i += rest.find('\n').unwrap_or(rest.len());
+        } else if !block_start.is_empty() && rest.starts_with(block_start) {
+            block_depth += 1;
+            i += block_start.len();
+        } else if let Some(string) = rest.strip_prefix('\'') {
+            // '' inside a string is an escaped quote, which this handles as two strings
+            i += 1 + string.find('\'').map_or(string.len(), |end| end + 1);
+            code_end = i;
+        } else if let Some(identifier) = rest.strip_prefix('"') {
+            i += 1 + identifier.find('"').map_or(identifier.len(), |end| end + 1);
+            code_end = i;
+        } else if let Some(tag) = dollar_quote_tag(content, i) {
+            let body = &rest[tag.len()..];
+            i += tag.len() + body.find(tag).map_or(body.len(), |end| end + tag.len());
+            code_end = i;
+        } else {
+            let c = rest.chars().next().unwrap();
+            i += c.len_utf8();
+            if !c.is_whitespace() {
+                code_end = i;
+            }
+        }
+    }
+    code_end
+}
+
+/// Append a string after the last piece of code in the file content, ignoring trailing comments.
+///
+/// Like `append_string_to_file_content`, but a file ending in `SELECT 1 -- comment` becomes
+/// `SELECT 1; -- comment` rather than having the string glued onto the comment, where it would
+/// never terminate the statement. A file containing only comments is left as it is.
+fn append_string_after_code(
+    file_content: String,
+    append_str: &str,
+    line_comment: &str,
+    block_comment_start: &str,
+    block_comment_end: &str,
+) -> String {
+    let content = file_content.trim_end();
+    let code_end = find_code_end(
+        content,
+        line_comment,
+        block_comment_start,
+        block_comment_end,
+    );
+    let (code, comments) = content.split_at(code_end);
+
+    let mut result = code.to_string();
+    if code_end > 0 && !code.ends_with(append_str) {
+        result.push_str(append_str);
+    }
+    result.push_str(comments);
+    result.push('\n');
+    result
+}
+
 trait OutputDestination {
-    fn write_line(&mut self, content: &str) -> std::io::Result<()>;
     fn write_str(&mut self, content: &str) -> std::io::Result<()>;
+    fn finish(self: Box<Self>) -> Result<(), TopCatError>;
 }
 
+/// Writes to a temporary file in the same directory as `file_path`, which only replaces
+/// `file_path` when `finish` is called. If anything fails before then the temporary file is
+/// removed when dropped, so a partially written output is never left behind.
 struct FileOutput {
-    writer: Box<dyn Write>,
+    file_path: PathBuf,
+    temp_file: NamedTempFile,
 }
 
 impl FileOutput {
     fn new(file_path: &Path) -> Result<Self, TopCatError> {
-        let file = File::create(file_path)?;
+        let dir = match file_path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        let mut builder = tempfile::Builder::new();
+        builder.prefix(".topcat").suffix(".tmp");
+        // Keep the permissions of the file being replaced, otherwise match `File::create`
+        match fs::metadata(file_path) {
+            Ok(metadata) => {
+                builder.permissions(metadata.permissions());
+            }
+            Err(_) => {
+                #[cfg(unix)]
+                {
+                    use std::os::unix::fs::PermissionsExt;
+                    builder.permissions(fs::Permissions::from_mode(0o666));
+                }
+            }
+        }
+        let temp_file = builder.tempfile_in(dir)?;
         Ok(Self {
-            writer: Box::new(file),
+            file_path: file_path.to_path_buf(),
+            temp_file,
         })
     }
 }
 
 impl OutputDestination for FileOutput {
-    fn write_line(&mut self, content: &str) -> std::io::Result<()> {
-        writeln!(self.writer, "{}", content)
+    fn write_str(&mut self, content: &str) -> std::io::Result<()> {
+        self.temp_file.write_all(content.as_bytes())
     }
 
+    fn finish(self: Box<Self>) -> Result<(), TopCatError> {
+        self.temp_file.as_file().sync_all()?;
+        self.temp_file
+            .persist(&self.file_path)
+            .map_err(|e| TopCatError::Io(e.error))?;
+        Ok(())
+    }
+}
+
+/// Compares what would be written with the current content of `file_path` instead of writing it,
+/// for `--check`.
+struct CheckOutput {
+    file_path: PathBuf,
+    content: String,
+}
+
+impl OutputDestination for CheckOutput {
     fn write_str(&mut self, content: &str) -> std::io::Result<()> {
-        write!(self.writer, "{}", content)
+        self.content.push_str(content);
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> Result<(), TopCatError> {
+        match fs::read_to_string(&self.file_path) {
+            Ok(current) if current == self.content => Ok(()),
+            Ok(_) => Err(TopCatError::OutOfDate(vec![self.file_path])),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                Err(TopCatError::OutOfDate(vec![self.file_path]))
+            }
+            Err(e) => Err(TopCatError::Io(e)),
+        }
     }
 }
 
-struct ConsoleOutput;
+/// Where to write an output file: in place, or nowhere but compared with it when checking.
+fn file_destination(
+    file_path: &Path,
+    config: &Config,
+) -> Result<Box<dyn OutputDestination>, TopCatError> {
+    if config.check {
+        Ok(Box::new(CheckOutput {
+            file_path: file_path.to_path_buf(),
+            content: String::new(),
+        }))
+    } else {
+        Ok(Box::new(FileOutput::new(file_path)?))
+    }
+}
 
-impl OutputDestination for ConsoleOutput {
-    fn write_line(&mut self, content: &str) -> std::io::Result<()> {
-        println!("{}", content);
+/// A chunk left over from an earlier run that split the output into more files. It is removed
+/// when the outputs are finished, or reported as out of date when checking.
+struct StaleOutput {
+    file_path: PathBuf,
+    check: bool,
+}
+
+impl OutputDestination for StaleOutput {
+    fn write_str(&mut self, _content: &str) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> Result<(), TopCatError> {
+        if !self.file_path.exists() {
+            return Ok(());
+        }
+        if self.check {
+            return Err(TopCatError::OutOfDate(vec![self.file_path]));
+        }
+        info!("Removing stale chunk {}", self.file_path.display());
+        fs::remove_file(&self.file_path)?;
         Ok(())
     }
+}
+
+struct ConsoleOutput;
 
+impl OutputDestination for ConsoleOutput {
     fn write_str(&mut self, content: &str) -> std::io::Result<()> {
         print!("{}", content);
         Ok(())
     }
+
+    fn finish(self: Box<Self>) -> Result<(), TopCatError> {
+        std::io::stdout().flush()?;
+        Ok(())
+    }
+}
+
+/// A file's banners and content, rendered and ready to be written into an output chunk.
+struct RenderedFile<'a> {
+    file_node: &'a FileNode,
+    text: String,
+    /// The line of `text` the file's content starts on, counting from 0.
+    content_offset: usize,
+    content_lines: usize,
+    /// The line of the input file the written content starts from.
+    source_start_line: usize,
+}
+
+/// A run of consecutive files that are written to the same output file.
+struct Chunk<'a> {
+    key: Option<String>,
+    files: Vec<RenderedFile<'a>>,
+}
+
+/// The written size of the parts of a chunk, so files can be added to a chunk without rendering
+/// it again each time.
+struct ChunkSizes<'a> {
+    config: &'a Config<'a>,
+    base_dir: Option<&'a Path>,
+    /// The header and footer of a chunk without any files.
+    fixed: usize,
+    /// How many times the header and footer list the chunk's input files.
+    inputs_count: usize,
+    /// The preamble and postamble written around each run of a layer's files.
+    layer_runs: HashMap<String, usize>,
+}
+
+impl<'a> ChunkSizes<'a> {
+    fn new(
+        files: &[RenderedFile],
+        config: &'a Config<'a>,
+        base_dir: Option<&'a Path>,
+        global_lookup: &dyn Fn(&str) -> Option<String>,
+    ) -> Result<ChunkSizes<'a>, TopCatError> {
+        let empty = Chunk {
+            key: None,
+            files: Vec::new(),
+        };
+        let (content, _) = render_chunk(&empty, config, base_dir, global_lookup)?;
+
+        // The hash is always the same length, so only the list of inputs changes the size
+        let templates_size = |inputs: &str| -> Result<usize, TopCatError> {
+            let lookup = |key: &str| match key {
+                "inputs" => Some(inputs.to_string()),
+                "hash" => Some(String::new()),
+                _ => global_lookup(key),
+            };
+            let header = template::render(&chunk_header_template(config), &lookup)?;
+            let footer = template::render(
+                config
+                    .footer_template
+                    .as_deref()
+                    .unwrap_or(DEFAULT_FOOTER_TEMPLATE),
+                &lookup,
+            )?;
+            Ok(header.len() + footer.len())
+        };
+        let inputs_count = templates_size("-")? - templates_size("")?;
+
+        let no_inputs = |key: &str| match key {
+            "inputs" => Some(String::new()),
+            _ => global_lookup(key),
+        };
+        let mut layer_runs = HashMap::new();
+        for file in files {
+            let layer_name = &file.file_node.layer;
+            if layer_runs.contains_key(layer_name) {
+                continue;
+            }
+            let layer = config.layer_definition(layer_name);
+            let preamble = render_layer_template(
+                layer.and_then(|layer| layer.preamble.as_deref()),
+                layer_name,
+                layer,
+                &no_inputs,
+            )?;
+            let postamble = render_layer_template(
+                layer.and_then(|layer| layer.postamble.as_deref()),
+                layer_name,
+                layer,
+                &no_inputs,
+            )?;
+            let size = written_len(&preamble, config) + written_len(&postamble, config);
+            layer_runs.insert(layer_name.clone(), size);
+        }
+
+        Ok(ChunkSizes {
+            config,
+            base_dir,
+            fixed: content.len(),
+            inputs_count,
+            layer_runs,
+        })
+    }
+
+    /// How much adding `file` to a chunk after `previous` adds to its size.
+    fn file(&self, file: &RenderedFile, previous: Option<&RenderedFile>) -> usize {
+        let layer_name = &file.file_node.layer;
+        let starts_layer_run =
+            previous.map_or(true, |previous| previous.file_node.layer != *layer_name);
+        let layer_run = if starts_layer_run {
+            self.layer_runs.get(layer_name).copied().unwrap_or(0)
+        } else {
+            0
+        };
+        let mut input = format!(
+            "{} {}",
+            self.config.comment_str,
+            display_path(&file.file_node.path, self.base_dir)
+        );
+        if previous.is_some() {
+            input.insert(0, '\n');
+        }
+        written_len(&file.text, self.config)
+            + self.inputs_count * written_len(&input, self.config)
+            + layer_run
+    }
+}
+
+/// The length of `content` once its line endings are converted as configured.
+fn written_len(content: &str, config: &Config) -> usize {
+    match config.line_endings {
+        Some(line_ending) => normalise_line_endings(content, line_ending).len(),
+        None => content.len(),
+    }
+}
+
+/// Group files into chunks without changing their order.
+///
+/// A new chunk starts whenever `key` changes between neighbouring files, or when adding the next
+/// file would take the chunk over `max_files`, or over `max_bytes`. A chunk's size is
+/// `fixed_size` for its header and footer, plus `file_size` of each file given the file before it
+/// in the chunk. A single file that doesn't fit in `max_bytes` still gets a chunk of its own.
+fn chunk_files<'a>(
+    files: Vec<RenderedFile<'a>>,
+    key: &dyn Fn(&FileNode) -> Option<String>,
+    max_bytes: Option<usize>,
+    max_files: Option<usize>,
+    fixed_size: usize,
+    file_size: &dyn Fn(&RenderedFile, Option<&RenderedFile>) -> usize,
+) -> Vec<Chunk<'a>> {
+    let mut chunks: Vec<Chunk> = Vec::new();
+    let mut size = 0;
+    for file in files {
+        let file_key = key(file.file_node);
+        if let Some(chunk) = chunks.last_mut() {
+            let added = file_size(&file, chunk.files.last());
+            let fits = chunk.key == file_key
+                && !max_files.is_some_and(|max| chunk.files.len() >= max)
+                && !max_bytes.is_some_and(|max| size + added > max);
+            if fits {
+                size += added;
+                chunk.files.push(file);
+                continue;
+            }
+        }
+        size = fixed_size + file_size(&file, None);
+        chunks.push(Chunk {
+            key: file_key,
+            files: vec![file],
+        });
+    }
+    chunks
+}
+
+/// The path of the `index`th chunk of `output`, eg `deploy.002.append.sql` for `deploy.sql`.
+fn chunk_path(output: &Path, index: usize, key: Option<&str>) -> PathBuf {
+    let stem = output.file_stem().unwrap_or_default().to_string_lossy();
+    let mut file_name = format!("{}.{:03}", stem, index);
+    if let Some(key) = key {
+        let key: String = key
+            .chars()
+            .map(|c| {
+                if c.is_alphanumeric() || c == '-' || c == '_' || c == '.' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        file_name.push('.');
+        file_name.push_str(&key);
+    }
+    if let Some(extension) = output.extension() {
+        file_name.push('.');
+        file_name.push_str(&extension.to_string_lossy());
+    }
+    output.with_file_name(file_name)
+}
+
+/// The chunks listed in the previous manifest of `output` that the new one doesn't list.
+///
+/// Only plain file names starting with the output's stem are returned, so a manifest that was
+/// edited by hand can't point at other files.
+fn stale_chunks<'a>(output: &Path, previous_manifest: &'a str, manifest: &str) -> Vec<&'a str> {
+    let stem = output.file_stem().unwrap_or_default().to_string_lossy();
+    let prefix = format!("{}.", stem);
+    previous_manifest
+        .lines()
+        .filter(|file_name| !manifest.lines().any(|name| name == *file_name))
+        .filter(|file_name| {
+            file_name.starts_with(&prefix)
+                && Path::new(file_name).file_name() == Some(OsStr::new(file_name))
+        })
+        .collect()
+}
+
+/// The path of the manifest listing the chunks of a split output, eg `deploy.sql.manifest`.
+fn manifest_path(output: &Path) -> PathBuf {
+    let mut file_name = output.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".manifest");
+    output.with_file_name(file_name)
+}
+
+fn render_file<'a>(
+    file_node: &'a FileNode,
+    index: usize,
+    config: &Config,
+    fs: &mut dyn FileSystem,
+    base_dir: Option<&Path>,
+    global_lookup: &dyn Fn(&str) -> Option<String>,
+) -> Result<RenderedFile<'a>, TopCatError> {
+    let contents = fs.read_to_string(&file_node.path)?;
+    let mut contents = contents.as_str();
+    if config.strip_bom {
+        contents = contents.trim_start_matches(UTF8_BOM);
+    }
+    let mut source_start_line = 1;
+    if config.strip_header {
+        let (body, header_lines) = strip_file_header(contents, &config.comment_str);
+        contents = body;
+        source_start_line += header_lines;
+    }
+    let index = index.to_string();
+    let layer = config.layer_definition(&file_node.layer);
+    let file_lookup = |key: &str| {
+        file_placeholder(file_node, key, base_dir)
+            .or_else(|| (key == "index").then(|| index.clone()))
+            .or_else(|| layer_placeholder(layer, key))
+            .or_else(|| global_lookup(key))
+    };
+    let file_end_str = layer
+        .and_then(|layer| layer.suffix.as_deref())
+        .unwrap_or(&config.file_end_str);
+
+    let prefix = template::render(
+        config
+            .file_prefix_template
+            .as_deref()
+            .unwrap_or(DEFAULT_FILE_PREFIX_TEMPLATE),
+        &file_lookup,
+    )?;
+    let contents = match config.suffix_mode {
+        SuffixMode::Plain => append_string_to_file_content(contents.to_string(), file_end_str),
+        SuffixMode::CommentAware => append_string_after_code(
+            contents.to_string(),
+            file_end_str,
+            &config.comment_str,
+            &config.block_comment_start,
+            &config.block_comment_end,
+        ),
+    };
+    let suffix = template::render(
+        config
+            .file_suffix_template
+            .as_deref()
+            .unwrap_or(DEFAULT_FILE_SUFFIX_TEMPLATE),
+        &file_lookup,
+    )?;
+
+    Ok(RenderedFile {
+        file_node,
+        content_offset: count_lines(&prefix),
+        content_lines: count_lines(&contents),
+        source_start_line,
+        text: prefix + &contents + &suffix,
+    })
+}
+
+/// Render the header, files and footer of a chunk, along with its source map.
+fn render_chunk(
+    chunk: &Chunk,
+    config: &Config,
+    base_dir: Option<&Path>,
+    global_lookup: &dyn Fn(&str) -> Option<String>,
+) -> Result<(String, SourceMap), TopCatError> {
+    let inputs = chunk
+        .files
+        .iter()
+        .map(|file| {
+            format!(
+                "{} {}",
+                config.comment_str,
+                display_path(&file.file_node.path, base_dir)
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    let chunk_lookup = |key: &str| match key {
+        "inputs" => Some(inputs.clone()),
+        _ => global_lookup(key),
+    };
+
+    // The body is rendered first so the header can include its hash.
+    let mut body = String::new();
+    let mut sections = Vec::new();
+    let mut body_lines = 0;
+    for (i, file) in chunk.files.iter().enumerate() {
+        let layer_name = &file.file_node.layer;
+        let layer = config.layer_definition(layer_name);
+        let is_layer_start = i == 0 || chunk.files[i - 1].file_node.layer != *layer_name;
+        let is_layer_end = chunk
+            .files
+            .get(i + 1)
+            .map_or(true, |next| next.file_node.layer != *layer_name);
+        if is_layer_start {
+            let preamble = render_layer_template(
+                layer.and_then(|layer| layer.preamble.as_deref()),
+                layer_name,
+                layer,
+                &chunk_lookup,
+            )?;
+            body_lines += count_lines(&preamble);
+            body.push_str(&preamble);
+        }
+
+        let content_start = body_lines + file.content_offset;
+        sections.push(Section {
+            name: file.file_node.name.clone(),
+            path: PathBuf::from(display_path(&file.file_node.path, base_dir)),
+            output_start_line: content_start + 1,
+            output_end_line: content_start + file.content_lines,
+            source_start_line: file.source_start_line,
+        });
+        body_lines += count_lines(&file.text);
+        body.push_str(&file.text);
+
+        if is_layer_end {
+            let postamble = render_layer_template(
+                layer.and_then(|layer| layer.postamble.as_deref()),
+                layer_name,
+                layer,
+                &chunk_lookup,
+            )?;
+            body_lines += count_lines(&postamble);
+            body.push_str(&postamble);
+        }
+    }
+
+    body.push_str(&template::render(
+        config
+            .footer_template
+            .as_deref()
+            .unwrap_or(DEFAULT_FOOTER_TEMPLATE),
+        &chunk_lookup,
+    )?);
+
+    // The hash is of the bytes that are written, so line endings are converted first
+    if let Some(line_ending) = config.line_endings {
+        body = normalise_line_endings(&body, line_ending);
+    }
+    let hash = format!("{:x}", Sha256::digest(body.as_bytes()));
+    let header = template::render(&chunk_header_template(config), &|key: &str| match key {
+        "hash" => Some(hash.clone()),
+        _ => chunk_lookup(key),
+    })?;
+
+    let mut source_map = SourceMap::new(sections);
+    source_map.offset(count_lines(&header));
+    let header = match config.line_endings {
+        Some(line_ending) => normalise_line_endings(&header, line_ending),
+        None => header,
+    };
+    Ok((header + &body, source_map))
+}
+
+/// Write rendered content, and its source map if enabled, to `output` or to stdout if there is
+/// no output file.
+///
+/// The destinations are returned unfinished so the caller can move every file into place only
+/// once all of them have been written.
+fn write_output(
+    output: Option<&Path>,
+    content: &str,
+    source_map: &SourceMap,
+    config: &Config,
+) -> Result<Vec<Box<dyn OutputDestination>>, TopCatError> {
+    let mut output_dest: Box<dyn OutputDestination> = match output {
+        Some(output) => file_destination(output, config)?,
+        None => Box::new(ConsoleOutput {}),
+    };
+    output_dest.write_str(content)?;
+    let mut destinations = vec![output_dest];
+
+    if config.source_map {
+        match output {
+            Some(output) => {
+                let source_map_path = SourceMap::path_for(output);
+                info!("Writing source map to {}", source_map_path.display());
+                let mut source_map_dest = file_destination(&source_map_path, config)?;
+                source_map_dest.write_str(&source_map.to_json()?)?;
+                destinations.push(source_map_dest);
+            }
+            None => info!("Not writing a source map as there is no output file"),
+        }
+    }
+    Ok(destinations)
+}
+
+/// Write `content` to `output`, or to stdout if there is no output file or it is `-`.
+pub fn write_text(output: Option<&Path>, content: &str) -> Result<(), TopCatError> {
+    let mut destination: Box<dyn OutputDestination> = match output {
+        Some(output) if output.as_os_str() != STDOUT_PATH => Box::new(FileOutput::new(output)?),
+        _ => Box::new(ConsoleOutput {}),
+    };
+    destination.write_str(content)?;
+    destination.finish()
+}
+
+/// Finish every destination. When checking, every out of date file is reported together.
+fn finish_outputs(destinations: Vec<Box<dyn OutputDestination>>) -> Result<(), TopCatError> {
+    let mut out_of_date = Vec::new();
+    for destination in destinations {
+        match destination.finish() {
+            Err(TopCatError::OutOfDate(paths)) => out_of_date.extend(paths),
+            result => result?,
+        }
+    }
+    if out_of_date.is_empty() {
+        Ok(())
+    } else {
+        Err(TopCatError::OutOfDate(out_of_date))
+    }
 }
 
 /// Generate output based on the given graph and configuration.
@@ -109,35 +957,128 @@ pub fn generate(
 ) -> Result<(), TopCatError> {
     info!("Generating output");
 
-    let mut output_dest: Box<dyn OutputDestination>;
-    if config.dry_run {
-        info!("Dry run enabled, not writing to file");
-        output_dest = Box::new(ConsoleOutput {});
+    let output = match (&config.output, config.dry_run) {
+        (Some(output), false) if output.as_os_str() != STDOUT_PATH => {
+            if config.check {
+                info!("Checking {} is up to date", output.display());
+            }
+            Some(output.as_path())
+        }
+        (Some(_), false) => {
+            info!("Writing output to stdout");
+            None
+        }
+        _ => {
+            info!("Dry run enabled, not writing to file");
+            None
+        }
+    };
+
+    let base_dir = if config.reproducible {
+        Some(env::current_dir()?)
+    } else {
+        None
+    };
+    let base_dir = base_dir.as_deref();
+    let args = recorded_args(env::args().skip(1));
+    let command = if config.reproducible {
+        normalise_command(args, base_dir)
     } else {
-        output_dest = Box::new(FileOutput::new(&config.output)?);
+        args.join(" ")
+    };
+    let sorted_nodes = graph.get_sorted_nodes()?;
+    let count = sorted_nodes.len().to_string();
+    let global_lookup = |key: &str| match key {
+        "comment" => Some(config.comment_str.clone()),
+        "separator" => Some(config.file_separator_str.clone()),
+        "command" => Some(command.clone()),
+        "count" => Some(count.clone()),
+        _ => None,
+    };
+
+    let mut rendered_files = Vec::new();
+    for (i, file_node) in sorted_nodes.into_iter().enumerate() {
+        rendered_files.push(render_file(
+            file_node,
+            i + 1,
+            &config,
+            fs,
+            base_dir,
+            &global_lookup,
+        )?);
+    }
+
+    let is_split = config.split_by.is_some()
+        || config.max_chunk_bytes.is_some()
+        || config.max_chunk_files.is_some();
+    if !is_split {
+        let chunk = Chunk {
+            key: None,
+            files: rendered_files,
+        };
+        let (content, source_map) = render_chunk(&chunk, &config, base_dir, &global_lookup)?;
+        return finish_outputs(write_output(output, &content, &source_map, &config)?);
     }
 
-    let command = env::args().skip(1).collect::<Vec<_>>().join(" ");
-    output_dest.write_line(&format!(
-        "{0} This file was generated by topcat. To regenerate run:\n{0}\n{0} topcat {1}\n",
-        config.comment_str, command
-    ))?;
-    let file_sep_str = config.file_separator_str.as_str();
+    let input_dirs = &config.input_dirs;
+    let chunk_key = |file_node: &FileNode| match config.split_by {
+        Some(SplitBy::Layer) => Some(file_node.layer.clone()),
+        Some(SplitBy::Directory) => {
+            io_utils::relative_to_input_dir(&file_node.path, input_dirs).and_then(|relative| {
+                let mut components = relative.components();
+                let first = components.next()?;
+                // Files directly in an input directory have no top level directory
+                components
+                    .next()
+                    .map(|_| first.as_os_str().to_string_lossy().to_string())
+            })
+        }
+        None => None,
+    };
+    let sizes = ChunkSizes::new(&rendered_files, &config, base_dir, &global_lookup)?;
+    let chunks = chunk_files(
+        rendered_files,
+        &chunk_key,
+        config.max_chunk_bytes,
+        config.max_chunk_files,
+        sizes.fixed,
+        &|file, previous| sizes.file(file, previous),
+    );
+    info!("Splitting output into {} chunks", chunks.len());
 
-    for file_name in graph.get_sorted_files()? {
-        let contents = fs.read_to_string(&file_name)?;
+    let mut destinations = Vec::new();
+    let mut manifest = String::new();
+    for (i, chunk) in chunks.iter().enumerate() {
+        let (content, source_map) = render_chunk(chunk, &config, base_dir, &global_lookup)?;
+        match output {
+            Some(output) => {
+                let path = chunk_path(output, i + 1, chunk.key.as_deref());
+                info!("Writing chunk {}", path.display());
+                destinations.extend(write_output(Some(&path), &content, &source_map, &config)?);
+                manifest.push_str(&path.file_name().unwrap_or_default().to_string_lossy());
+                manifest.push('\n');
+            }
+            None => destinations.extend(write_output(None, &content, &source_map, &config)?),
+        }
+    }
 
-        output_dest.write_line(file_sep_str)?;
-        output_dest
-            .write_line(format!("{} {}", config.comment_str, file_name.display()).as_str())?;
-        output_dest.write_str(&append_string_to_file_content(
-            contents,
-            &config.file_end_str,
-        ))?;
-        output_dest.write_line("")?;
+    if let Some(output) = output {
+        let manifest_path = manifest_path(output);
+        // Chunks of an earlier run that split the output into more files
+        let previous_manifest = fs.read_to_string(&manifest_path).unwrap_or_default();
+        for file_name in stale_chunks(output, &previous_manifest, &manifest) {
+            destinations.push(Box::new(StaleOutput {
+                file_path: output.with_file_name(file_name),
+                check: config.check,
+            }));
+        }
+        info!("Writing manifest to {}", manifest_path.display());
+        let mut manifest_output = file_destination(&manifest_path, &config)?;
+        manifest_output.write_str(&manifest)?;
+        destinations.push(manifest_output);
     }
 
-    Ok(())
+    finish_outputs(destinations)
 }
 
 #[cfg(test)]
@@ -155,4 +1096,574 @@ mod tests {
         let d = append_string_to_file_content("SELECT 1 FROM table;\n\n\n".to_string(), ";");
         assert_eq!(d, "SELECT 1 FROM table;\n");
     }
+
+    #[test]
+    fn test_append_string_after_code() {
+        let append =
+            |content: &str| append_string_after_code(content.to_string(), ";", "--", "/*", "*/");
+
+        assert_eq!(append("SELECT 1 FROM table"), "SELECT 1 FROM table;\n");
+        assert_eq!(append("SELECT 1;\n\n"), "SELECT 1;\n");
+        assert_eq!(
+            append("SELECT 1 -- trailing comment\n"),
+            "SELECT 1; -- trailing comment\n"
+        );
+        assert_eq!(
+            append("SELECT 1; -- trailing comment"),
+            "SELECT 1; -- trailing comment\n"
+        );
+        assert_eq!(
+            append("SELECT 1\n/* outer /* nested */ still comment */\n-- done"),
+            "SELECT 1;\n/* outer /* nested */ still comment */\n-- done\n"
+        );
+        assert_eq!(
+            append(
+                "CREATE FUNCTION f() RETURNS text AS $$\nBEGIN\n  RETURN 'it''s'; -- done\nEND;\n$$ LANGUAGE plpgsql -- trailing"
+            ),
+            "CREATE FUNCTION f() RETURNS text AS $$\nBEGIN\n  RETURN 'it''s'; -- done\nEND;\n$$ LANGUAGE plpgsql; -- trailing\n"
+        );
+        assert_eq!(
+            append("CREATE FUNCTION f(a int) RETURNS int AS $body$\n  SELECT $1 -- don't\n$body$ LANGUAGE sql"),
+            "CREATE FUNCTION f(a int) RETURNS int AS $body$\n  SELECT $1 -- don't\n$body$ LANGUAGE sql;\n"
+        );
+        assert_eq!(
+            append(
+                "CREATE FUNCTION f() RETURNS text AS $$ SELECT $q$it's$q$ $$ LANGUAGE sql -- done"
+            ),
+            "CREATE FUNCTION f() RETURNS text AS $$ SELECT $q$it's$q$ $$ LANGUAGE sql; -- done\n"
+        );
+        assert_eq!(
+            append("CREATE FUNCTION f() RETURNS text AS $$\n# it's python\nreturn '--'\n$$ LANGUAGE plpython3u"),
+            "CREATE FUNCTION f() RETURNS text AS $$\n# it's python\nreturn '--'\n$$ LANGUAGE plpython3u;\n"
+        );
+        assert_eq!(
+            append("DO $$ BEGIN PERFORM 1; END $$ -- it's done"),
+            "DO $$ BEGIN PERFORM 1; END $$; -- it's done\n"
+        );
+        assert_eq!(
+            append("SELECT 1 AS \"weird -- name\""),
+            "SELECT 1 AS \"weird -- name\";\n"
+        );
+        assert_eq!(
+            append("SELECT '-- not a comment'"),
+            "SELECT '-- not a comment';\n"
+        );
+        assert_eq!(append("-- only a comment\n"), "-- only a comment\n");
+    }
+
+    #[test]
+    fn test_file_placeholder() {
+        let file_node = FileNode::new(
+            "my_schema.b".to_string(),
+            std::path::PathBuf::from("sql/b.sql"),
+            HashSet::from(["my_schema.a".to_string(), "my_schema".to_string()]),
+            "normal".to_string(),
+            HashSet::new(),
+            HashSet::new(),
+            std::collections::BTreeMap::from([("owner".to_string(), "payments".to_string())]),
+        );
+
+        assert_eq!(
+            file_placeholder(&file_node, "deps", None).unwrap(),
+            "my_schema, my_schema.a"
+        );
+        assert_eq!(
+            file_placeholder(&file_node, "path", None).unwrap(),
+            "sql/b.sql"
+        );
+        assert_eq!(
+            file_placeholder(&file_node, "meta.owner", None).unwrap(),
+            "payments"
+        );
+        assert_eq!(
+            file_placeholder(&file_node, "meta.missing", None).unwrap(),
+            ""
+        );
+        assert!(file_placeholder(&file_node, "unknown", None).is_none());
+    }
+
+    #[test]
+    fn test_recorded_args() {
+        let recorded =
+            |args: &[&str]| recorded_args(args.iter().map(|arg| arg.to_string())).join(" ");
+
+        assert_eq!(
+            recorded(&["-vi", "sql", "-dv", "-ovout.sql", "-s", "-v-", "-o", "-"]),
+            "-i sql -d -ovout.sql -s -v- -o -"
+        );
+        assert_eq!(
+            recorded(&[
+                "-i",
+                "sql",
+                "--check",
+                "--message-format",
+                "json",
+                "--deny=no-name",
+                "--allow",
+                "glob-error",
+                "--deny-warnings",
+                "--verbose",
+                "--output-file",
+                "out.sql",
+            ]),
+            "-i sql -o out.sql"
+        );
+        assert_eq!(
+            recorded(&["--output-file=out.sql", "--file-separator=--check"]),
+            "-o out.sql --file-separator=--check"
+        );
+    }
+
+    /// The top level options listed in the help, with their short name and whether they take a
+    /// value.
+    fn cli_options() -> Vec<(Option<char>, String, bool)> {
+        use structopt::StructOpt;
+
+        let mut help = Vec::new();
+        crate::Opt::clap().write_help(&mut help).unwrap();
+        String::from_utf8(help)
+            .unwrap()
+            .lines()
+            .filter_map(|line| {
+                let line = line.trim_start();
+                // eg `-o, --output-file <FILE>` or `--check`
+                let (short, rest) = match line.strip_prefix('-') {
+                    Some(rest) if !rest.starts_with('-') => (rest.chars().next(), rest.get(3..)?),
+                    _ => (None, line),
+                };
+                let rest = rest
+                    .strip_prefix("--")
+                    .filter(|rest| rest.starts_with(|c: char| c.is_ascii_lowercase()))?;
+                let long: String = rest
+                    .chars()
+                    .take_while(|c| c.is_alphanumeric() || *c == '-')
+                    .collect();
+                let takes_value = rest[long.len()..].starts_with(" <");
+                Some((short, format!("--{}", long), takes_value))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_recorded_args_know_every_option() {
+        // Options that change the output, so are kept in the recorded command
+        const RECORDED_OPTIONS: &[&str] = &[
+            "--block-comment-end",
+            "--block-comment-start",
+            "--comment-prefix",
+            "--config",
+            "--dry-run",
+            "--exclude-exts",
+            "--exclude-glob",
+            "--exclude-prefix",
+            "--exclude-tag",
+            "--fallback-layer",
+            "--file-prefix-template",
+            "--file-separator",
+            "--file-suffix",
+            "--file-suffix-template",
+            "--footer-template",
+            "--header-hash",
+            "--header-inputs",
+            "--header-template",
+            "--include-exts",
+            "--include-glob",
+            "--include-hidden",
+            "--include-prefix",
+            "--include-tag",
+            "--infer-deps",
+            "--input-dirs",
+            "--layers",
+            "--line-endings",
+            "--max-chunk-bytes",
+            "--max-chunk-files",
+            "--name-from-path",
+            "--output-file",
+            "--reproducible",
+            "--require-name",
+            "--source-map",
+            "--split-by-dir",
+            "--split-by-layer",
+            "--strip-bom",
+            "--strip-header",
+            "--subdir-filter",
+            "--suffix-mode",
+            "--where",
+        ];
+        let options = cli_options();
+        assert!(options.iter().any(|(_, long, _)| long == "--output-file"));
+
+        let mut short_flags: Vec<char> = options
+            .iter()
+            .filter(|(_, long, takes_value)| {
+                !takes_value && long != "--help" && long != "--version"
+            })
+            .filter_map(|(short, _, _)| *short)
+            .collect();
+        short_flags.sort_unstable();
+        let mut expected_short_flags = SHORT_FLAGS.to_vec();
+        expected_short_flags.sort_unstable();
+        assert_eq!(
+            short_flags, expected_short_flags,
+            "SHORT_FLAGS is out of date"
+        );
+
+        for (name, takes_value) in UNRECORDED_OPTIONS {
+            assert!(
+                options
+                    .iter()
+                    .any(|(_, long, value)| long == name && value == takes_value),
+                "{} in UNRECORDED_OPTIONS doesn't match the option",
+                name
+            );
+        }
+        for (_, long, _) in &options {
+            let is_known = RECORDED_OPTIONS.contains(&long.as_str())
+                || UNRECORDED_OPTIONS.iter().any(|(name, _)| name == long)
+                || long == "--help"
+                || long == "--version";
+            assert!(
+                is_known,
+                "add {} to UNRECORDED_OPTIONS if it doesn't change the output, or else to RECORDED_OPTIONS",
+                long
+            );
+        }
+    }
+
+    #[test]
+    fn test_normalise_command() {
+        let args = [
+            "-i",
+            "/home/me/project/sql",
+            "--file-suffix=/home/me/project/end.sql",
+            "/elsewhere/sql",
+        ]
+        .iter()
+        .map(|arg| arg.to_string())
+        .collect();
+
+        assert_eq!(
+            normalise_command(args, Some(Path::new("/home/me/project"))),
+            "-i sql --file-suffix=end.sql /elsewhere/sql"
+        );
+    }
+
+    #[test]
+    fn test_default_header_template() {
+        assert_eq!(
+            default_header_template(false, false),
+            DEFAULT_HEADER_TEMPLATE
+        );
+        assert!(default_header_template(true, true)
+            .ends_with("sha256: {hash}\n{comment}\n{comment} Input files:\n{inputs}\n\n"));
+    }
+
+    fn file_node(name: &str, layer: &str) -> FileNode {
+        FileNode::new(
+            name.to_string(),
+            std::path::PathBuf::from(format!("{}.sql", name)),
+            HashSet::new(),
+            layer.to_string(),
+            HashSet::new(),
+            HashSet::new(),
+            std::collections::BTreeMap::new(),
+        )
+    }
+
+    fn rendered(nodes: &[FileNode]) -> Vec<RenderedFile<'_>> {
+        nodes
+            .iter()
+            .map(|file_node| RenderedFile {
+                file_node,
+                text: "0123456789".to_string(),
+                content_offset: 0,
+                content_lines: 1,
+                source_start_line: 1,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_chunk_files() {
+        let nodes = vec![
+            file_node("a", "prepend"),
+            file_node("b", "normal"),
+            file_node("c", "normal"),
+            file_node("d", "normal"),
+            file_node("e", "append"),
+        ];
+        let names = |chunks: &[Chunk]| -> Vec<Vec<String>> {
+            chunks
+                .iter()
+                .map(|chunk| {
+                    chunk
+                        .files
+                        .iter()
+                        .map(|file| file.file_node.name.clone())
+                        .collect()
+                })
+                .collect()
+        };
+
+        let file_size = |file: &RenderedFile, _: Option<&RenderedFile>| file.text.len();
+        let by_layer = |file_node: &FileNode| Some(file_node.layer.clone());
+        let chunks = chunk_files(rendered(&nodes), &by_layer, None, Some(2), 0, &file_size);
+        assert_eq!(
+            names(&chunks),
+            vec![vec!["a"], vec!["b", "c"], vec!["d"], vec!["e"]]
+        );
+        assert_eq!(chunks[2].key.as_deref(), Some("normal"));
+
+        let no_key = |_: &FileNode| None;
+        let chunks = chunk_files(rendered(&nodes), &no_key, Some(25), None, 0, &file_size);
+        assert_eq!(
+            names(&chunks),
+            vec![vec!["a", "b"], vec!["c", "d"], vec!["e"]]
+        );
+
+        // A 10 byte header and footer leaves room for a single file
+        let chunks = chunk_files(
+            rendered(&nodes[..2]),
+            &no_key,
+            Some(25),
+            None,
+            10,
+            &file_size,
+        );
+        assert_eq!(names(&chunks), vec![vec!["a"], vec!["b"]]);
+    }
+
+    #[test]
+    fn test_chunk_sizes_match_rendered_chunk() {
+        let nodes = vec![
+            file_node("a", "normal"),
+            file_node("b", "append"),
+            file_node("c", "append"),
+        ];
+        let config = Config {
+            comment_str: "--".to_string(),
+            header_template: Some("-- {hash}\n{inputs}\n".to_string()),
+            footer_template: Some("-- end of {inputs}\n".to_string()),
+            layer_definitions: vec![Layer {
+                name: "append".to_string(),
+                separator: None,
+                suffix: None,
+                preamble: Some("BEGIN; -- {layer}\n".to_string()),
+                postamble: Some("COMMIT;\n".to_string()),
+                cross_layer_deps: true,
+                optional: true,
+            }],
+            line_endings: Some(LineEnding::Crlf),
+            ..Default::default()
+        };
+        let with_text = |nodes| -> Vec<RenderedFile> {
+            rendered(nodes)
+                .into_iter()
+                .map(|file| RenderedFile {
+                    text: "SELECT 1;\nSELECT 2;\n".to_string(),
+                    ..file
+                })
+                .collect()
+        };
+        let files = with_text(&nodes);
+        let sizes = ChunkSizes::new(&files, &config, None, &|_| None).unwrap();
+        for end in 0..=files.len() {
+            let previous = |i: usize| i.checked_sub(1).map(|i| &files[i]);
+            let size = sizes.fixed
+                + files[..end]
+                    .iter()
+                    .enumerate()
+                    .map(|(i, file)| sizes.file(file, previous(i)))
+                    .sum::<usize>();
+            let chunk = Chunk {
+                key: None,
+                files: with_text(&nodes[..end]),
+            };
+            let (content, _) = render_chunk(&chunk, &config, None, &|_| None).unwrap();
+            assert_eq!(size, content.len());
+        }
+    }
+
+    #[test]
+    fn test_stale_chunks() {
+        let output = Path::new("out/deploy.sql");
+        let previous = "deploy.001.sql\ndeploy.002.sql\ndeploy.003.sql\n../other.sql\nelse.sql\n";
+        assert_eq!(
+            stale_chunks(output, previous, "deploy.001.sql\n"),
+            vec!["deploy.002.sql", "deploy.003.sql"]
+        );
+        assert!(stale_chunks(output, "", "deploy.001.sql\n").is_empty());
+    }
+
+    #[test]
+    fn test_layer_definitions() {
+        struct Files;
+        impl FileSystem for Files {
+            fn read_to_string(&mut self, path: &Path) -> Result<String, std::io::Error> {
+                Ok(format!("SELECT '{}'", path.display()))
+            }
+        }
+
+        let nodes = vec![
+            file_node("a", "normal"),
+            file_node("b", "append"),
+            file_node("c", "append"),
+        ];
+        let config = Config {
+            comment_str: "--".to_string(),
+            file_end_str: ";".to_string(),
+            header_template: Some(String::new()),
+            file_prefix_template: Some("{separator}\n".to_string()),
+            file_suffix_template: Some(String::new()),
+            layer_definitions: vec![Layer {
+                name: "append".to_string(),
+                separator: Some("-- append".to_string()),
+                suffix: Some(";;".to_string()),
+                preamble: Some("BEGIN; -- {layer}\n".to_string()),
+                postamble: Some("COMMIT;\n".to_string()),
+                cross_layer_deps: true,
+                optional: true,
+            }],
+            file_separator_str: "--".to_string(),
+            ..Default::default()
+        };
+        let global_lookup = |key: &str| match key {
+            "separator" => Some(config.file_separator_str.clone()),
+            _ => None,
+        };
+        let files = nodes
+            .iter()
+            .enumerate()
+            .map(|(i, node)| render_file(node, i + 1, &config, &mut Files, None, &global_lookup))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let chunk = Chunk { key: None, files };
+        let (content, source_map) = render_chunk(&chunk, &config, None, &global_lookup).unwrap();
+        assert_eq!(
+            content,
+            "--\nSELECT 'a.sql';\nBEGIN; -- append\n-- append\nSELECT 'b.sql';;\n-- append\nSELECT 'c.sql';;\nCOMMIT;\n"
+        );
+        assert_eq!(
+            source_map
+                .locate(5)
+                .map(|(section, _)| section.name.as_str()),
+            Some("b")
+        );
+    }
+
+    #[test]
+    fn test_header_hash_matches_converted_line_endings() {
+        let nodes = vec![file_node("a", "normal")];
+        let config = Config {
+            comment_str: "--".to_string(),
+            header_template: Some("-- {hash}\n".to_string()),
+            line_endings: Some(LineEnding::Crlf),
+            ..Default::default()
+        };
+        let chunk = Chunk {
+            key: None,
+            files: rendered(&nodes)
+                .into_iter()
+                .map(|file| RenderedFile {
+                    text: "SELECT 1;\nSELECT 2;\n".to_string(),
+                    ..file
+                })
+                .collect(),
+        };
+        let (content, _) = render_chunk(&chunk, &config, None, &|_| None).unwrap();
+        let (header, body) = content.split_once("\r\n").unwrap();
+        assert_eq!(body, "SELECT 1;\r\nSELECT 2;\r\n");
+        assert_eq!(header, format!("-- {:x}", Sha256::digest(body.as_bytes())));
+    }
+
+    #[test]
+    fn test_chunk_path() {
+        assert_eq!(
+            chunk_path(Path::new("out/deploy.sql"), 2, Some("my schema")),
+            PathBuf::from("out/deploy.002.my_schema.sql")
+        );
+        assert_eq!(
+            chunk_path(Path::new("deploy"), 12, None),
+            PathBuf::from("deploy.012")
+        );
+    }
+
+    #[test]
+    fn test_file_output_is_atomic() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let output_path = temp_dir.path().join("output.sql");
+        std::fs::write(&output_path, "old").unwrap();
+
+        // Dropped before finishing: the old output is untouched and nothing is left behind
+        let mut output = FileOutput::new(&output_path).unwrap();
+        output.write_str("partial").unwrap();
+        drop(output);
+        assert_eq!(std::fs::read_to_string(&output_path).unwrap(), "old");
+        assert_eq!(std::fs::read_dir(temp_dir.path()).unwrap().count(), 1);
+
+        let mut output = Box::new(FileOutput::new(&output_path).unwrap());
+        output.write_str("new").unwrap();
+        assert_eq!(std::fs::read_to_string(&output_path).unwrap(), "old");
+        output.finish().unwrap();
+        assert_eq!(std::fs::read_to_string(&output_path).unwrap(), "new");
+        assert_eq!(std::fs::read_dir(temp_dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_check_outputs() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let current = temp_dir.path().join("current.sql");
+        let stale = temp_dir.path().join("stale.sql");
+        std::fs::write(&current, "SELECT 1;").unwrap();
+        std::fs::write(&stale, "SELECT 1;").unwrap();
+        let check = |path: &Path, content: &str| -> Box<dyn OutputDestination> {
+            Box::new(CheckOutput {
+                file_path: path.to_path_buf(),
+                content: content.to_string(),
+            })
+        };
+
+        assert!(finish_outputs(vec![check(&current, "SELECT 1;")]).is_ok());
+        let missing = temp_dir.path().join("missing.sql");
+        match finish_outputs(vec![
+            check(&current, "SELECT 1;"),
+            check(&stale, "SELECT 2;"),
+            check(&missing, ""),
+        ]) {
+            Err(TopCatError::OutOfDate(paths)) => assert_eq!(paths, vec![stale.clone(), missing]),
+            result => panic!("expected the output to be out of date, got {:?}", result),
+        }
+        assert_eq!(std::fs::read_to_string(&stale).unwrap(), "SELECT 1;");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_file_output_keeps_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let output_path = temp_dir.path().join("output.sql");
+        std::fs::write(&output_path, "old").unwrap();
+        std::fs::set_permissions(&output_path, std::fs::Permissions::from_mode(0o640)).unwrap();
+
+        let mut output = Box::new(FileOutput::new(&output_path).unwrap());
+        output.write_str("new").unwrap();
+        output.finish().unwrap();
+
+        let mode = std::fs::metadata(&output_path)
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o640);
+    }
+
+    #[test]
+    fn test_normalise_line_endings() {
+        let content = "a\r\nb\nc\r\n";
+        assert_eq!(normalise_line_endings(content, LineEnding::Lf), "a\nb\nc\n");
+        assert_eq!(
+            normalise_line_endings(content, LineEnding::Crlf),
+            "a\r\nb\r\nc\r\n"
+        );
+    }
 }
@@ -0,0 +1,98 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::Path;
+
+use serde::Serialize;
+
+use crate::exceptions::TopCatError;
+use crate::file_dag::TCGraph;
+use crate::file_node::FileNode;
+
+/// A single file in the build plan, in the order it will be concatenated.
+#[derive(Serialize)]
+struct PlanEntry<'a> {
+    index: usize,
+    name: &'a str,
+    path: &'a Path,
+    layer: &'a str,
+    requires: Vec<&'a str>,
+    exists: Vec<&'a str>,
+    tags: Vec<&'a str>,
+    metadata: &'a BTreeMap<String, String>,
+}
+
+impl<'a> PlanEntry<'a> {
+    fn new(index: usize, file_node: &'a FileNode) -> Self {
+        let mut requires: Vec<&str> = file_node.deps.iter().map(String::as_str).collect();
+        requires.sort_unstable();
+        let mut exists: Vec<&str> = file_node.ensure_exists.iter().map(String::as_str).collect();
+        exists.sort_unstable();
+        let mut tags: Vec<&str> = file_node.tags.iter().map(String::as_str).collect();
+        tags.sort_unstable();
+        PlanEntry {
+            index,
+            name: &file_node.name,
+            path: &file_node.path,
+            layer: &file_node.layer,
+            requires,
+            exists,
+            tags,
+            metadata: &file_node.metadata,
+        }
+    }
+}
+
+/// Write the ordered build plan as a JSON array without concatenating any files.
+///
+/// # Arguments
+///
+/// * `graph` - The built TCGraph to take the ordered files from.
+/// * `writer` - Where to write the JSON to.
+pub fn generate(graph: &TCGraph, writer: &mut dyn Write) -> Result<(), TopCatError> {
+    let entries: Vec<PlanEntry> = graph
+        .get_sorted_nodes()?
+        .into_iter()
+        .enumerate()
+        .map(|(index, file_node)| PlanEntry::new(index, file_node))
+        .collect();
+    serde_json::to_writer_pretty(&mut *writer, &entries).map_err(std::io::Error::from)?;
+    writeln!(writer)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::path::PathBuf;
+
+    use super::*;
+
+    #[test]
+    fn test_plan_entry_serialization() {
+        let file_node = FileNode::new(
+            "my_schema.a".to_string(),
+            PathBuf::from("sql/my_schema/a.sql"),
+            HashSet::from(["my_schema".to_string(), "my_schema.b".to_string()]),
+            "normal".to_string(),
+            HashSet::new(),
+            HashSet::from(["seed".to_string()]),
+            BTreeMap::from([("owner".to_string(), "payments".to_string())]),
+        );
+
+        let json = serde_json::to_value(PlanEntry::new(3, &file_node)).unwrap();
+
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "index": 3,
+                "name": "my_schema.a",
+                "path": "sql/my_schema/a.sql",
+                "layer": "normal",
+                "requires": ["my_schema", "my_schema.b"],
+                "exists": [],
+                "tags": ["seed"],
+                "metadata": {"owner": "payments"}
+            })
+        );
+    }
+}
@@ -0,0 +1,96 @@
+use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
+
+use serde::Serialize;
+
+use crate::exceptions::TopCatError;
+use crate::file_node::FileNode;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QueryFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for QueryFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(format!(
+                "unknown output format '{}', expected 'text' or 'json'",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct QueryEntry<'a> {
+    name: &'a str,
+    path: &'a Path,
+    layer: &'a str,
+}
+
+/// Write the result of a query, one name per line or as a JSON array of the files.
+pub fn write_nodes(
+    file_nodes: &[&FileNode],
+    format: QueryFormat,
+    writer: &mut dyn Write,
+) -> Result<(), TopCatError> {
+    match format {
+        QueryFormat::Text => {
+            for file_node in file_nodes {
+                writeln!(writer, "{}", file_node.name)?;
+            }
+        }
+        QueryFormat::Json => {
+            let entries: Vec<QueryEntry> = file_nodes
+                .iter()
+                .map(|file_node| QueryEntry {
+                    name: &file_node.name,
+                    path: &file_node.path,
+                    layer: &file_node.layer,
+                })
+                .collect();
+            serde_json::to_writer_pretty(&mut *writer, &entries).map_err(std::io::Error::from)?;
+            writeln!(writer)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, HashSet};
+    use std::path::PathBuf;
+
+    use super::*;
+
+    #[test]
+    fn test_write_nodes() {
+        let file_node = FileNode::new(
+            "my_schema.a".to_string(),
+            PathBuf::from("sql/a.sql"),
+            HashSet::new(),
+            "normal".to_string(),
+            HashSet::new(),
+            HashSet::new(),
+            BTreeMap::new(),
+        );
+
+        let mut text = Vec::new();
+        write_nodes(&[&file_node], QueryFormat::Text, &mut text).unwrap();
+        assert_eq!(String::from_utf8(text).unwrap(), "my_schema.a\n");
+
+        let mut json = Vec::new();
+        write_nodes(&[&file_node], QueryFormat::Json, &mut json).unwrap();
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&json).unwrap(),
+            serde_json::json!([{"name": "my_schema.a", "path": "sql/a.sql", "layer": "normal"}])
+        );
+    }
+}
@@ -0,0 +1,219 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+
+use log::info;
+
+use crate::exceptions::TopCatError;
+use crate::file_dag::TCGraph;
+use crate::header::{self, Directive};
+use crate::output;
+
+/// A header line changed by a rename.
+#[derive(Debug, PartialEq)]
+pub struct LineEdit {
+    pub path: PathBuf,
+    /// The line's number in the file, starting at 1.
+    pub line: usize,
+    pub before: String,
+    pub after: String,
+}
+
+/// Rewrite a header line with every value matching `old` replaced by `new`, or `None` if nothing
+/// matches. The text up to the directive's colon is kept as it is.
+fn rename_values(line: &str, comment_str: &str, old: &str, new: &str) -> Option<String> {
+    let colon = comment_str.len() + line[comment_str.len()..].find(':')?;
+    let values = header::split_values(&line[colon + 1..]);
+    if !values.iter().any(|value| value.to_lowercase() == old) {
+        return None;
+    }
+    let renamed: Vec<String> = values
+        .into_iter()
+        .map(|value| {
+            if value.to_lowercase() == old {
+                new.to_string()
+            } else {
+                value
+            }
+        })
+        .collect();
+    Some(format!("{} {}", &line[..=colon], renamed.join(", ")))
+}
+
+/// Find the header lines that need to change to rename `old` to `new`: the `name` of the file
+/// defining it and every `requires`, `dropped_by` and `exists` naming it.
+pub fn rename(graph: &TCGraph, old: &str, new: &str) -> Result<Vec<LineEdit>, TopCatError> {
+    let old_node = graph
+        .node(old)
+        .ok_or_else(|| TopCatError::NodeNotFound(old.to_string()))?;
+    if new.is_empty() || new.chars().any(|c| c.is_whitespace() || c == ',') {
+        return Err(TopCatError::InvalidFileHeader(
+            old_node.path.clone(),
+            format!("Invalid name '{}'", new),
+        ));
+    }
+    if let Some(new_node) = graph.node(new) {
+        return Err(TopCatError::NameClash(
+            new.to_string(),
+            new_node.path.clone(),
+            old_node.path.clone(),
+        ));
+    }
+
+    let mut edits = Vec::new();
+    for file_node in graph.nodes() {
+        for line in header::read_header(&file_node.path, &graph.comment_str)? {
+            let renames = match line.directive {
+                Directive::Name => file_node.name == old,
+                Directive::Requires | Directive::DroppedBy | Directive::Exists => true,
+                _ => false,
+            };
+            if !renames {
+                continue;
+            }
+            if let Some(after) = rename_values(&line.text, &graph.comment_str, old, new) {
+                edits.push(LineEdit {
+                    path: file_node.path.clone(),
+                    line: line.number,
+                    before: line.text,
+                    after,
+                });
+            }
+        }
+    }
+    if !edits.iter().any(|edit| edit.path == old_node.path) {
+        return Err(TopCatError::InvalidFileHeader(
+            old_node.path.clone(),
+            format!(
+                "{} has no name header to rename, its name comes from its path",
+                old
+            ),
+        ));
+    }
+    Ok(edits)
+}
+
+/// Write the edits to their files.
+///
+/// Returns the number of files changed.
+pub fn apply(edits: &[LineEdit]) -> Result<usize, TopCatError> {
+    let mut by_path: BTreeMap<&PathBuf, Vec<&LineEdit>> = BTreeMap::new();
+    for edit in edits {
+        by_path.entry(&edit.path).or_default().push(edit);
+    }
+
+    for (path, edits) in &by_path {
+        let content = fs::read_to_string(path)?;
+        let mut result = String::with_capacity(content.len());
+        for (i, line) in content.split_inclusive('\n').enumerate() {
+            match edits.iter().find(|edit| edit.line == i + 1) {
+                Some(edit) => {
+                    let text = line.trim_end_matches(['\r', '\n']);
+                    result.push_str(&edit.after);
+                    result.push_str(&line[text.len()..]);
+                }
+                None => result.push_str(line),
+            }
+        }
+        output::write_text(Some(path), &result)?;
+        info!("Updated {}", path.display());
+    }
+    Ok(by_path.len())
+}
+
+/// Write the edits as a unified diff.
+pub fn write_diff(edits: &[LineEdit], writer: &mut dyn Write) -> Result<(), TopCatError> {
+    let mut current = None;
+    for edit in edits {
+        if current != Some(&edit.path) {
+            writeln!(writer, "--- {}", edit.path.display())?;
+            writeln!(writer, "+++ {}", edit.path.display())?;
+            current = Some(&edit.path);
+        }
+        writeln!(writer, "@@ -{},1 +{},1 @@", edit.line, edit.line)?;
+        writeln!(writer, "-{}", edit.before)?;
+        writeln!(writer, "+{}", edit.after)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+
+    #[test]
+    fn test_rename_values() {
+        assert_eq!(
+            rename_values("--requires: a, Old b", "--", "old", "new"),
+            Some("--requires: a, new, b".to_string())
+        );
+        assert_eq!(
+            rename_values("-- requires: older", "--", "old", "new"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_rename() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path();
+        fs::write(dir.join("a.sql"), "-- name: old\r\nSELECT 1;\r\n").unwrap();
+        fs::write(
+            dir.join("b.sql"),
+            "-- name: b\n-- requires: old\n-- exists: old, c\nSELECT old;\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("c.sql"),
+            "-- name: c\n-- dropped_by: b\nSELECT 1;\n",
+        )
+        .unwrap();
+        let config = test_utils::config(dir);
+        let graph = test_utils::load_graph(&config);
+
+        assert!(matches!(
+            rename(&graph, "old", "c"),
+            Err(TopCatError::NameClash(..))
+        ));
+        assert!(matches!(
+            rename(&graph, "missing", "new"),
+            Err(TopCatError::NodeNotFound(_))
+        ));
+
+        let edits = rename(&graph, "old", "new").unwrap();
+        let mut diff = Vec::new();
+        write_diff(&edits, &mut diff).unwrap();
+        let dir_display = dir.display();
+        assert_eq!(
+            String::from_utf8(diff).unwrap(),
+            format!(
+                "--- {dir_display}/a.sql
++++ {dir_display}/a.sql
+@@ -1,1 +1,1 @@
+--- name: old
++-- name: new
+--- {dir_display}/b.sql
++++ {dir_display}/b.sql
+@@ -2,1 +2,1 @@
+--- requires: old
++-- requires: new
+@@ -3,1 +3,1 @@
+--- exists: old, c
++-- exists: new, c
+"
+            )
+        );
+
+        assert_eq!(apply(&edits).unwrap(), 2);
+        assert_eq!(
+            fs::read_to_string(dir.join("a.sql")).unwrap(),
+            "-- name: new\r\nSELECT 1;\r\n"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("b.sql")).unwrap(),
+            "-- name: b\n-- requires: new\n-- exists: new, c\nSELECT old;\n"
+        );
+    }
+}
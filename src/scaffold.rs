@@ -0,0 +1,269 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use log::info;
+
+use crate::exceptions::TopCatError;
+use crate::file_dag::TCGraph;
+use crate::warning::{self, WarningCode};
+
+/// The header of a file created by `topcat new`.
+#[derive(Debug, Default)]
+pub struct NewFile {
+    pub name: String,
+    pub layer: Option<String>,
+    pub requires: Vec<String>,
+    pub tags: Vec<String>,
+}
+
+impl NewFile {
+    /// The header in the same canonical form `topcat fmt` writes, followed by a blank line.
+    pub fn header(&self, comment_str: &str) -> String {
+        let mut lines = vec![format!("{} name: {}", comment_str, self.name)];
+        if let Some(layer) = &self.layer {
+            lines.push(format!("{} layer: {}", comment_str, layer));
+        }
+        for (key, values) in [("requires", &self.requires), ("tags", &self.tags)] {
+            let mut values = values.clone();
+            values.sort();
+            values.dedup();
+            if !values.is_empty() {
+                lines.push(format!("{} {}: {}", comment_str, key, values.join(", ")));
+            }
+        }
+        lines.push(String::new());
+        lines.push(String::new());
+        lines.join("\n")
+    }
+}
+
+/// Check `new_file` fits into the loaded graph: its name must be free, its layer configured and
+/// everything it requires must exist in the same or an earlier layer that allows it.
+fn validate(graph: &TCGraph, path: &Path, new_file: &NewFile) -> Result<(), TopCatError> {
+    let is_valid_name =
+        !new_file.name.is_empty() && !new_file.name.chars().any(|c| c.is_whitespace() || c == ',');
+    if !is_valid_name {
+        return Err(TopCatError::InvalidFileHeader(
+            path.to_path_buf(),
+            format!("Invalid name '{}'", new_file.name),
+        ));
+    }
+    if let Some(other) = graph.node(&new_file.name) {
+        return Err(TopCatError::NameClash(
+            new_file.name.clone(),
+            path.to_path_buf(),
+            other.path.clone(),
+        ));
+    }
+
+    let layer = new_file
+        .layer
+        .as_deref()
+        .unwrap_or_else(|| graph.fallback_layer());
+    if !graph.layers().iter().any(|l| l == layer) {
+        return Err(TopCatError::InvalidFileHeader(
+            path.to_path_buf(),
+            format!(
+                "Invalid layer '{}', expected one of: {}",
+                layer,
+                graph.layers().join(", ")
+            ),
+        ));
+    }
+
+    for dep in &new_file.requires {
+        let dep_node = graph
+            .node(dep)
+            .ok_or_else(|| TopCatError::MissingDependency(new_file.name.clone(), dep.clone()))?;
+        graph.check_layer_dependency(&new_file.name, layer, dep_node)?;
+    }
+    Ok(())
+}
+
+/// Create a file at `path` with the header of `new_file`, after checking it against the graph.
+///
+/// Existing files are never overwritten.
+/// `path` with its longest existing ancestor canonicalized, since the file and its directory may
+/// not exist yet.
+fn canonicalize_new(path: &Path) -> Option<PathBuf> {
+    let mut missing = Vec::new();
+    let mut existing = path;
+    loop {
+        let dir = if existing.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            existing
+        };
+        match dir.canonicalize() {
+            Ok(canonical) => {
+                return Some(missing.into_iter().rev().fold(canonical, |p, c| p.join(c)))
+            }
+            Err(_) => {
+                missing.push(existing.file_name()?);
+                existing = existing.parent()?;
+            }
+        }
+    }
+}
+
+pub fn create(graph: &TCGraph, path: &Path, new_file: &NewFile) -> Result<(), TopCatError> {
+    validate(graph, path, new_file)?;
+
+    let in_input_dirs = canonicalize_new(path).is_some_and(|path| {
+        graph
+            .file_dirs
+            .iter()
+            .filter_map(|dir| dir.canonicalize().ok())
+            .any(|dir| path.starts_with(dir))
+    });
+    if !in_input_dirs {
+        warning::emit(
+            WarningCode::OutsideInputDirs,
+            Some(path),
+            format!(
+                "{} is not in any of the input directories, so it won't be concatenated",
+                path.display()
+            ),
+        );
+        // Stop before writing, as when any other warning is denied
+        if warning::has_denied() {
+            return Ok(());
+        }
+    }
+
+    if let Some(parent) = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::AlreadyExists => TopCatError::FileExists(path.to_path_buf()),
+            _ => TopCatError::Io(e),
+        })?;
+    file.write_all(new_file.header(&graph.comment_str).as_bytes())?;
+    info!("Created {} as {}", path.display(), new_file.name);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::format::format_header;
+    use crate::test_utils;
+
+    #[test]
+    fn test_create() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path();
+        fs::write(
+            dir.join("a.sql"),
+            "-- name: my_schema\nCREATE SCHEMA my_schema;",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("b.sql"),
+            "-- name: late\n-- layer: append\nSELECT 1;",
+        )
+        .unwrap();
+        let config = Config {
+            layers: vec![
+                "prepend".to_string(),
+                "normal".to_string(),
+                "append".to_string(),
+            ],
+            ..test_utils::config(dir)
+        };
+        let graph = test_utils::load_graph(&config);
+
+        let path = dir.join("functions/fn.sql");
+        let new_file = NewFile {
+            name: "my_schema.fn".to_string(),
+            requires: vec!["my_schema".to_string()],
+            tags: vec!["b".to_string(), "a".to_string()],
+            ..Default::default()
+        };
+        create(&graph, &path, &new_file).unwrap();
+        let content = fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            content,
+            "-- name: my_schema.fn\n-- requires: my_schema\n-- tags: a, b\n\n"
+        );
+        assert_eq!(format_header(&content, "--"), content);
+        assert!(matches!(
+            create(&graph, &path, &new_file),
+            Err(TopCatError::FileExists(_))
+        ));
+
+        let invalid = |new_file: NewFile| create(&graph, &dir.join("c.sql"), &new_file);
+        assert!(matches!(
+            invalid(NewFile {
+                name: "my_schema".to_string(),
+                ..Default::default()
+            }),
+            Err(TopCatError::NameClash(..))
+        ));
+        assert!(matches!(
+            invalid(NewFile {
+                name: "c".to_string(),
+                requires: vec!["missing".to_string()],
+                ..Default::default()
+            }),
+            Err(TopCatError::MissingDependency(..))
+        ));
+        assert!(matches!(
+            invalid(NewFile {
+                name: "c".to_string(),
+                requires: vec!["late".to_string()],
+                ..Default::default()
+            }),
+            Err(TopCatError::InvalidDependency(..))
+        ));
+        assert!(matches!(
+            invalid(NewFile {
+                name: "c".to_string(),
+                layer: Some("nope".to_string()),
+                ..Default::default()
+            }),
+            Err(TopCatError::InvalidFileHeader(..))
+        ));
+        assert!(!dir.join("c.sql").exists());
+    }
+
+    #[test]
+    fn test_create_outside_input_dirs() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let input_dir = temp_dir.path().join("sql");
+        fs::create_dir(&input_dir).unwrap();
+        let graph = test_utils::load_graph(&test_utils::config(&input_dir));
+        let new_file = NewFile {
+            name: "a".to_string(),
+            ..Default::default()
+        };
+
+        create(&graph, &input_dir.join("new/a.sql"), &new_file).unwrap();
+        assert!(warning::take().is_empty());
+
+        let outside = temp_dir.path().join("other/a.sql");
+        warning::set_policy(warning::Policy {
+            deny: vec![WarningCode::OutsideInputDirs],
+            ..Default::default()
+        });
+        create(&graph, &outside, &new_file).unwrap();
+        assert!(warning::has_denied());
+        assert!(!outside.exists());
+        assert!(!temp_dir.path().join("other").exists());
+        warning::take();
+
+        warning::set_policy(warning::Policy::default());
+        create(&graph, &outside, &new_file).unwrap();
+        assert_eq!(warning::take()[0].code, WarningCode::OutsideInputDirs);
+        assert!(outside.exists());
+    }
+}
@@ -0,0 +1,156 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::exceptions::TopCatError;
+
+const SOURCE_MAP_VERSION: u32 = 1;
+
+/// The lines of the output that were copied from a single input file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Section {
+    pub name: String,
+    pub path: PathBuf,
+    /// First output line of the file's content, starting at 1.
+    pub output_start_line: usize,
+    /// Last output line of the file's content, inclusive.
+    pub output_end_line: usize,
+    /// The input file line that `output_start_line` came from.
+    pub source_start_line: usize,
+}
+
+/// Maps line ranges of a generated output file back to the input files they came from.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SourceMap {
+    pub version: u32,
+    pub sections: Vec<Section>,
+}
+
+impl SourceMap {
+    pub fn new(sections: Vec<Section>) -> SourceMap {
+        SourceMap {
+            version: SOURCE_MAP_VERSION,
+            sections,
+        }
+    }
+
+    /// The sidecar path the source map of `output` is written to, eg `out.sql.map.json`.
+    pub fn path_for(output: &Path) -> PathBuf {
+        let mut file_name = output.file_name().unwrap_or_default().to_os_string();
+        file_name.push(".map.json");
+        output.with_file_name(file_name)
+    }
+
+    /// Shift every section down by `lines`, eg to make room for a header written above them.
+    pub fn offset(&mut self, lines: usize) {
+        for section in &mut self.sections {
+            section.output_start_line += lines;
+            section.output_end_line += lines;
+        }
+    }
+
+    /// Resolve an output line to the input file and line it came from.
+    pub fn locate(&self, output_line: usize) -> Option<(&Section, usize)> {
+        self.sections
+            .iter()
+            .find(|section| {
+                section.output_start_line <= output_line && output_line <= section.output_end_line
+            })
+            .map(|section| {
+                (
+                    section,
+                    section.source_start_line + output_line - section.output_start_line,
+                )
+            })
+    }
+
+    pub fn read(path: &Path) -> Result<SourceMap, TopCatError> {
+        let reader = BufReader::new(File::open(path)?);
+        let source_map: SourceMap =
+            serde_json::from_reader(reader).map_err(std::io::Error::from)?;
+        if source_map.version != SOURCE_MAP_VERSION {
+            return Err(TopCatError::UnknownError(format!(
+                "Unsupported source map version {} in {}",
+                source_map.version,
+                path.display()
+            )));
+        }
+        Ok(source_map)
+    }
+
+    pub fn to_json(&self) -> Result<String, TopCatError> {
+        let mut json = serde_json::to_string_pretty(self).map_err(std::io::Error::from)?;
+        json.push('\n');
+        Ok(json)
+    }
+}
+
+/// Split an `output.sql:4812` location into the output path and line number.
+pub fn parse_location(location: &str) -> Result<(PathBuf, usize), String> {
+    let (path, line) = location
+        .rsplit_once(':')
+        .ok_or_else(|| format!("expected FILE:LINE, got '{}'", location))?;
+    let line: usize = line
+        .parse()
+        .map_err(|_| format!("invalid line number '{}' in '{}'", line, location))?;
+    if path.is_empty() || line == 0 {
+        return Err(format!("expected FILE:LINE, got '{}'", location));
+    }
+    Ok((PathBuf::from(path), line))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn section(name: &str, start: usize, end: usize, source_start: usize) -> Section {
+        Section {
+            name: name.to_string(),
+            path: PathBuf::from(format!("{}.sql", name)),
+            output_start_line: start,
+            output_end_line: end,
+            source_start_line: source_start,
+        }
+    }
+
+    #[test]
+    fn test_locate() {
+        let mut source_map = SourceMap::new(vec![section("a", 3, 10, 1), section("b", 13, 20, 4)]);
+        source_map.offset(5);
+
+        assert_eq!(
+            source_map.locate(8).map(|(s, l)| (&s.name[..], l)),
+            Some(("a", 1))
+        );
+        assert_eq!(
+            source_map.locate(15).map(|(s, l)| (&s.name[..], l)),
+            Some(("a", 8))
+        );
+        assert_eq!(source_map.locate(16), None);
+        assert_eq!(
+            source_map.locate(20).map(|(s, l)| (&s.name[..], l)),
+            Some(("b", 6))
+        );
+    }
+
+    #[test]
+    fn test_parse_location() {
+        assert_eq!(
+            parse_location("out/output.sql:4812"),
+            Ok((PathBuf::from("out/output.sql"), 4812))
+        );
+        assert!(parse_location("output.sql").is_err());
+        assert!(parse_location("output.sql:x").is_err());
+        assert!(parse_location("output.sql:0").is_err());
+    }
+
+    #[test]
+    fn test_path_for() {
+        assert_eq!(
+            SourceMap::path_for(Path::new("out/output.sql")),
+            PathBuf::from("out/output.sql.map.json")
+        );
+    }
+}
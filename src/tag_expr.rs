@@ -0,0 +1,171 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::str::FromStr;
+
+/// A boolean expression over node tags, eg `seed and not (slow or flaky)`.
+///
+/// `not` binds tightest, then `and`, then `or`. Tags are matched case-insensitively since
+/// header values are lowercased when parsed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TagExpr {
+    Tag(String),
+    Not(Box<TagExpr>),
+    And(Box<TagExpr>, Box<TagExpr>),
+    Or(Box<TagExpr>, Box<TagExpr>),
+}
+
+impl TagExpr {
+    pub fn matches(&self, tags: &HashSet<String>) -> bool {
+        match self {
+            Self::Tag(tag) => tags.contains(tag),
+            Self::Not(expr) => !expr.matches(tags),
+            Self::And(left, right) => left.matches(tags) && right.matches(tags),
+            Self::Or(left, right) => left.matches(tags) || right.matches(tags),
+        }
+    }
+}
+
+impl fmt::Display for TagExpr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Tag(tag) => write!(f, "{}", tag),
+            Self::Not(expr) => write!(f, "not {}", expr),
+            Self::And(left, right) => write!(f, "({} and {})", left, right),
+            Self::Or(left, right) => write!(f, "({} or {})", left, right),
+        }
+    }
+}
+
+fn tokenize(input: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    for c in input.chars() {
+        if c.is_whitespace() || c == '(' || c == ')' {
+            if !current.is_empty() {
+                tokens.push(std::mem::take(&mut current));
+            }
+            if !c.is_whitespace() {
+                tokens.push(c.to_string());
+            }
+        } else {
+            current.push(c);
+        }
+    }
+    if !current.is_empty() {
+        tokens.push(current);
+    }
+    tokens
+}
+
+/// Recursive descent parser over the tokens of a tag expression.
+struct Parser {
+    tokens: Vec<String>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.pos).map(String::as_str)
+    }
+
+    fn next(&mut self) -> Option<String> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn parse_or(&mut self) -> Result<TagExpr, String> {
+        let mut expr = self.parse_and()?;
+        while self.peek().map(str::to_lowercase).as_deref() == Some("or") {
+            self.next();
+            expr = TagExpr::Or(Box::new(expr), Box::new(self.parse_and()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_and(&mut self) -> Result<TagExpr, String> {
+        let mut expr = self.parse_not()?;
+        while self.peek().map(str::to_lowercase).as_deref() == Some("and") {
+            self.next();
+            expr = TagExpr::And(Box::new(expr), Box::new(self.parse_not()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_not(&mut self) -> Result<TagExpr, String> {
+        if self.peek().map(str::to_lowercase).as_deref() == Some("not") {
+            self.next();
+            return Ok(TagExpr::Not(Box::new(self.parse_not()?)));
+        }
+        self.parse_atom()
+    }
+
+    fn parse_atom(&mut self) -> Result<TagExpr, String> {
+        match self.next() {
+            Some(token) if token == "(" => {
+                let expr = self.parse_or()?;
+                match self.next() {
+                    Some(token) if token == ")" => Ok(expr),
+                    _ => Err("expected ')'".to_string()),
+                }
+            }
+            Some(token) if token == ")" => Err("unexpected ')'".to_string()),
+            Some(token) => match token.to_lowercase().as_str() {
+                "and" | "or" | "not" => Err(format!("expected a tag, found '{}'", token)),
+                tag => Ok(TagExpr::Tag(tag.to_string())),
+            },
+            None => Err("expected a tag, found end of expression".to_string()),
+        }
+    }
+}
+
+impl FromStr for TagExpr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser {
+            tokens: tokenize(s),
+            pos: 0,
+        };
+        let expr = parser.parse_or()?;
+        match parser.next() {
+            None => Ok(expr),
+            Some(token) => Err(format!("unexpected '{}' in tag expression", token)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags(tags: &[&str]) -> HashSet<String> {
+        tags.iter().map(|t| t.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_precedence() {
+        let expr: TagExpr = "seed or test and not slow".parse().unwrap();
+        assert_eq!(expr.to_string(), "(seed or (test and not slow))");
+
+        let expr: TagExpr = "(seed or test) and not slow".parse().unwrap();
+        assert_eq!(expr.to_string(), "((seed or test) and not slow)");
+    }
+
+    #[test]
+    fn test_matches() {
+        let expr: TagExpr = "Seed and not slow".parse().unwrap();
+        assert!(expr.matches(&tags(&["seed"])));
+        assert!(expr.matches(&tags(&["seed", "fast"])));
+        assert!(!expr.matches(&tags(&["seed", "slow"])));
+        assert!(!expr.matches(&tags(&["test"])));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!("".parse::<TagExpr>().is_err());
+        assert!("seed and".parse::<TagExpr>().is_err());
+        assert!("(seed".parse::<TagExpr>().is_err());
+        assert!("seed slow".parse::<TagExpr>().is_err());
+    }
+}
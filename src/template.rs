@@ -0,0 +1,131 @@
+use crate::exceptions::TopCatError;
+
+/// Replace the escape sequences `\n`, `\t` and `\\` in a template given on the command line.
+///
+/// Any other backslash is kept as is, so Windows style paths survive unchanged.
+pub fn unescape(template: &str) -> String {
+    let mut result = String::with_capacity(template.len());
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.peek() {
+                Some('n') => {
+                    result.push('\n');
+                    chars.next();
+                }
+                Some('t') => {
+                    result.push('\t');
+                    chars.next();
+                }
+                Some('\\') => {
+                    result.push('\\');
+                    chars.next();
+                }
+                _ => result.push(c),
+            }
+        } else {
+            result.push(c);
+        }
+    }
+    result
+}
+
+/// Render a template by replacing each `{placeholder}` with the value returned by `lookup`.
+///
+/// `{{` and `}}` produce literal braces. A placeholder that `lookup` doesn't know about is an
+/// error rather than being silently dropped, so a typo fails the run instead of leaving a gap in
+/// the output.
+///
+/// # Example
+///
+/// ```rust
+/// let rendered = render("-- {path}", &|key| match key {
+///     "path" => Some("a.sql".to_string()),
+///     _ => None,
+/// });
+///
+/// assert_eq!(rendered.unwrap(), "-- a.sql")
+/// ```
+pub fn render(
+    template: &str,
+    lookup: &dyn Fn(&str) -> Option<String>,
+) -> Result<String, TopCatError> {
+    let mut result = String::with_capacity(template.len());
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                result.push('{');
+                chars.next();
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                result.push('}');
+                chars.next();
+            }
+            '{' => {
+                let mut placeholder = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => placeholder.push(c),
+                        None => {
+                            return Err(TopCatError::InvalidTemplate(
+                                template.to_string(),
+                                format!("unclosed placeholder '{{{}'", placeholder),
+                            ))
+                        }
+                    }
+                }
+                match lookup(placeholder.trim()) {
+                    Some(value) => result.push_str(&value),
+                    None => {
+                        return Err(TopCatError::InvalidTemplate(
+                            template.to_string(),
+                            format!("unknown placeholder '{{{}}}'", placeholder),
+                        ))
+                    }
+                }
+            }
+            '}' => {
+                return Err(TopCatError::InvalidTemplate(
+                    template.to_string(),
+                    "unmatched '}', use '}}' for a literal brace".to_string(),
+                ))
+            }
+            c => result.push(c),
+        }
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lookup(key: &str) -> Option<String> {
+        match key {
+            "name" => Some("my_schema.a".to_string()),
+            "index" => Some("3".to_string()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        assert_eq!(
+            render("\\echo [{index}] {name}", &lookup).unwrap(),
+            "\\echo [3] my_schema.a"
+        );
+        assert_eq!(render("{{{name}}}", &lookup).unwrap(), "{my_schema.a}");
+        assert!(render("{unknown}", &lookup).is_err());
+        assert!(render("{name", &lookup).is_err());
+        assert!(render("name}", &lookup).is_err());
+    }
+
+    #[test]
+    fn test_unescape() {
+        assert_eq!(unescape("BEGIN;\\n"), "BEGIN;\n");
+        assert_eq!(unescape("a\\tb\\\\n"), "a\tb\\n");
+        assert_eq!(unescape("C:\\sql"), "C:\\sql");
+    }
+}
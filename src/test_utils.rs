@@ -0,0 +1,38 @@
+//! Helpers shared by the unit tests.
+
+use std::path::Path;
+
+use crate::config::Config;
+use crate::file_dag::TCGraph;
+
+/// Write a file named `file_name` in `dir` with `header` followed by a line of SQL.
+pub fn write_node(dir: &Path, file_name: &str, header: &str) {
+    std::fs::write(dir.join(file_name), format!("{}\nSELECT 1;\n", header)).unwrap();
+}
+
+/// A config reading the files in `dir` with `--` comments into the single layer `normal`.
+pub fn config(dir: &Path) -> Config<'static> {
+    Config {
+        input_dirs: vec![dir.to_path_buf()],
+        comment_str: "--".to_string(),
+        layers: vec!["normal".to_string()],
+        fallback_layer: "normal".to_string(),
+        // temp directories are named `.tmpXXXX`
+        include_hidden: true,
+        ..Default::default()
+    }
+}
+
+/// Read the headers of the files with `config`, without building the graph.
+pub fn load_graph(config: &Config) -> TCGraph {
+    let mut graph = TCGraph::new(config);
+    graph.load_nodes().unwrap();
+    graph
+}
+
+/// Read the files with `config` and build the graph.
+pub fn build_graph(config: &Config) -> TCGraph {
+    let mut graph = TCGraph::new(config);
+    graph.build_graph().unwrap();
+    graph
+}
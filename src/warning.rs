@@ -0,0 +1,259 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use log::debug;
+
+/// A problem that doesn't stop topcat, but is likely a mistake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum WarningCode {
+    /// A file has no name header, so it's left out.
+    NoName,
+    /// A directory under an input directory couldn't be read.
+    UnreadableDir,
+    /// A glob pattern is invalid or one of its matches couldn't be read.
+    GlobError,
+    /// A file created by `topcat new` isn't in any of the input directories.
+    OutsideInputDirs,
+    /// The selection filters leave nothing to concatenate.
+    EmptySelection,
+}
+
+impl WarningCode {
+    pub const ALL: [WarningCode; 5] = [
+        WarningCode::NoName,
+        WarningCode::UnreadableDir,
+        WarningCode::GlobError,
+        WarningCode::OutsideInputDirs,
+        WarningCode::EmptySelection,
+    ];
+
+    /// The name used by `--allow` and `--deny` and in reports. These won't change between releases.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WarningCode::NoName => "no-name",
+            WarningCode::UnreadableDir => "unreadable-dir",
+            WarningCode::GlobError => "glob-error",
+            WarningCode::OutsideInputDirs => "outside-input-dirs",
+            WarningCode::EmptySelection => "empty-selection",
+        }
+    }
+}
+
+impl fmt::Display for WarningCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for WarningCode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.to_lowercase();
+        WarningCode::ALL
+            .into_iter()
+            .find(|code| code.as_str() == s)
+            .ok_or_else(|| {
+                let codes: Vec<&str> = WarningCode::ALL.iter().map(|code| code.as_str()).collect();
+                format!(
+                    "unknown warning '{}', expected one of: {}",
+                    s,
+                    codes.join(", ")
+                )
+            })
+    }
+}
+
+/// What happens when a warning is emitted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    /// Ignored.
+    Allow,
+    /// Reported.
+    Warn,
+    /// Reported, and the run fails.
+    Deny,
+}
+
+/// The level of each warning, from `--allow`, `--deny` and `--deny-warnings`.
+///
+/// Naming a warning takes precedence over `--deny-warnings`, and `--deny` over `--allow`.
+#[derive(Debug, Clone, Default)]
+pub struct Policy {
+    pub deny_warnings: bool,
+    pub deny: Vec<WarningCode>,
+    pub allow: Vec<WarningCode>,
+}
+
+impl Policy {
+    pub fn level(&self, code: WarningCode) -> Level {
+        if self.deny.contains(&code) {
+            Level::Deny
+        } else if self.allow.contains(&code) {
+            Level::Allow
+        } else if self.deny_warnings {
+            Level::Deny
+        } else {
+            Level::Warn
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Warning {
+    pub code: WarningCode,
+    pub level: Level,
+    pub message: String,
+    /// The file or directory the warning is about, if any.
+    pub path: Option<PathBuf>,
+}
+
+#[derive(Default)]
+struct Channel {
+    policy: Policy,
+    warnings: Vec<Warning>,
+}
+
+thread_local! {
+    static CHANNEL: RefCell<Channel> = RefCell::new(Channel::default());
+}
+
+/// Set the policy deciding which warnings emitted from now on are ignored or denied.
+pub fn set_policy(policy: Policy) {
+    CHANNEL.with(|channel| channel.borrow_mut().policy = policy);
+}
+
+/// Record a warning to be reported at the end of the run, unless it is allowed.
+///
+/// Like the `log` macros this can be called from anywhere, so the code finding the problem
+/// doesn't need to be handed anything to report it.
+pub fn emit(code: WarningCode, path: Option<&Path>, message: impl Into<String>) {
+    let message = message.into();
+    debug!("{} [{}]", message, code);
+    CHANNEL.with(|channel| {
+        let mut channel = channel.borrow_mut();
+        let level = channel.policy.level(code);
+        if level != Level::Allow {
+            channel.warnings.push(Warning {
+                code,
+                level,
+                message,
+                path: path.map(Path::to_path_buf),
+            });
+        }
+    });
+}
+
+/// Whether any warning emitted so far is denied.
+pub fn has_denied() -> bool {
+    CHANNEL.with(|channel| {
+        channel
+            .borrow()
+            .warnings
+            .iter()
+            .any(|warning| warning.level == Level::Deny)
+    })
+}
+
+/// Take the warnings emitted so far, in the order they were emitted.
+pub fn take() -> Vec<Warning> {
+    CHANNEL.with(|channel| std::mem::take(&mut channel.borrow_mut().warnings))
+}
+
+/// One line counting the warnings by code, or `None` if there are none.
+///
+/// For example `warning: 3 warnings emitted: 2 no-name, 1 glob-error`.
+pub fn summary(warnings: &[Warning]) -> Option<String> {
+    if warnings.is_empty() {
+        return None;
+    }
+    let mut counts: BTreeMap<WarningCode, usize> = BTreeMap::new();
+    for warning in warnings {
+        *counts.entry(warning.code).or_default() += 1;
+    }
+    let counts: Vec<String> = counts
+        .iter()
+        .map(|(code, count)| format!("{} {}", count, code))
+        .collect();
+    let plural = |n: usize| if n == 1 { "" } else { "s" };
+    let denied = warnings
+        .iter()
+        .filter(|warning| warning.level == Level::Deny)
+        .count();
+    Some(if denied > 0 {
+        format!(
+            "error: aborting due to {} denied warning{}: {}",
+            denied,
+            plural(denied),
+            counts.join(", ")
+        )
+    } else {
+        format!(
+            "warning: {} warning{} emitted: {}",
+            warnings.len(),
+            plural(warnings.len()),
+            counts.join(", ")
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_policy() {
+        let policy = Policy {
+            deny_warnings: true,
+            deny: vec![WarningCode::GlobError],
+            allow: vec![WarningCode::NoName, WarningCode::GlobError],
+        };
+        assert_eq!(policy.level(WarningCode::GlobError), Level::Deny);
+        assert_eq!(policy.level(WarningCode::NoName), Level::Allow);
+        assert_eq!(policy.level(WarningCode::EmptySelection), Level::Deny);
+        assert_eq!(
+            Policy::default().level(WarningCode::EmptySelection),
+            Level::Warn
+        );
+        assert_eq!("No-Name".parse(), Ok(WarningCode::NoName));
+        assert!("nope".parse::<WarningCode>().is_err());
+    }
+
+    #[test]
+    fn test_emit() {
+        set_policy(Policy {
+            allow: vec![WarningCode::EmptySelection],
+            ..Default::default()
+        });
+        emit(WarningCode::NoName, Some(Path::new("a.sql")), "a");
+        emit(WarningCode::EmptySelection, None, "ignored");
+        emit(WarningCode::NoName, Some(Path::new("b.sql")), "b");
+        emit(WarningCode::GlobError, None, "c");
+        assert!(!has_denied());
+
+        let warnings = take();
+        assert_eq!(warnings.len(), 3);
+        assert_eq!(warnings[0].path, Some(PathBuf::from("a.sql")));
+        assert_eq!(
+            summary(&warnings).unwrap(),
+            "warning: 3 warnings emitted: 2 no-name, 1 glob-error"
+        );
+        assert!(take().is_empty());
+        assert_eq!(summary(&[]), None);
+
+        set_policy(Policy {
+            deny: vec![WarningCode::GlobError],
+            ..Default::default()
+        });
+        emit(WarningCode::GlobError, None, "c");
+        assert!(has_denied());
+        assert_eq!(
+            summary(&take()).unwrap(),
+            "error: aborting due to 1 denied warning: 1 glob-error"
+        );
+        set_policy(Policy::default());
+    }
+}
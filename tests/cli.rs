@@ -0,0 +1,173 @@
+use std::process::{Command, Output};
+
+fn topcat(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_topcat"))
+        .args(args)
+        .output()
+        .expect("topcat should run")
+}
+
+#[test]
+fn test_output_to_stdout() {
+    let output = topcat(&["-i", "tests/input/sql", "-e", "sql", "-o", "-"]);
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.starts_with("-- This file was generated by topcat."));
+    let schema = stdout
+        .find("CREATE SCHEMA IF NOT EXISTS my_schema;")
+        .unwrap();
+    let function = stdout.find("CREATE FUNCTION my_schema.a()").unwrap();
+    assert!(schema < function);
+    // Logs stay on stderr so the output can be piped
+    assert!(!stdout.contains("INFO"));
+    assert!(String::from_utf8(output.stderr).unwrap().contains("INFO"));
+    assert!(!std::path::Path::new("-").exists());
+}
+
+#[test]
+fn test_path_notes_swapped_direction() {
+    let args = [
+        "path",
+        "my_schema",
+        "my_schema.c",
+        "-i",
+        "tests/input/sql",
+        "-e",
+        "sql",
+    ];
+    let output = topcat(&args);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "my_schema.c\nmy_schema\n"
+    );
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("my_schema doesn't require my_schema.c, showing the path from my_schema.c to my_schema instead"));
+
+    let output = topcat(&[
+        "path",
+        "my_schema.c",
+        "my_schema",
+        "-i",
+        "tests/input/sql",
+        "-e",
+        "sql",
+    ]);
+    assert!(output.status.success());
+    assert!(!String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("instead"));
+}
+
+#[test]
+fn test_json_diagnostics_go_to_stderr() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    std::fs::write(
+        temp_dir.path().join("a.sql"),
+        "-- name: a\n-- requires: b\n",
+    )
+    .unwrap();
+    std::fs::write(
+        temp_dir.path().join("b.sql"),
+        "-- name: b\n-- requires: a\n",
+    )
+    .unwrap();
+    let dir = temp_dir.path().to_str().unwrap();
+
+    let output = topcat(&[
+        "plan",
+        "-i",
+        dir,
+        "--include-hidden",
+        "--message-format",
+        "json",
+    ]);
+    assert_eq!(output.status.code(), Some(5));
+    assert!(output.stdout.is_empty());
+    // Only the diagnostics are written to stderr, one JSON object per line
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let diagnostics: Vec<serde_json::Value> = stderr
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0]["code"], "cyclic-dependency");
+
+    let output = topcat(&["-i", dir, "--message-format", "sarif"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(output.stdout.is_empty());
+    let sarif: serde_json::Value = serde_json::from_slice(&output.stderr).unwrap();
+    assert_eq!(
+        sarif["runs"][0]["results"][0]["ruleId"],
+        "invalid-arguments"
+    );
+}
+
+#[test]
+fn test_check_after_generating() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let output_path = temp_dir.path().join("out.sql");
+    let output_path = output_path.to_str().unwrap();
+    let args = ["-i", "tests/input/sql", "-e", "sql", "--reproducible"];
+    assert!(topcat(&[&args[..], &["-o", output_path]].concat())
+        .status
+        .success());
+
+    // Options that don't change the output can differ from the ones it was generated with
+    let check = [
+        &args[..],
+        &[
+            "--check",
+            "--output-file",
+            output_path,
+            "--message-format",
+            "json",
+            "--deny-warnings",
+            "-v",
+        ],
+    ]
+    .concat();
+    let output = topcat(&check);
+    assert_eq!(output.status.code(), Some(0), "{:?}", output);
+    assert_eq!(std::fs::read_dir(temp_dir.path()).unwrap().count(), 1);
+
+    let mut content = std::fs::read_to_string(output_path).unwrap();
+    content.push_str("-- edited\n");
+    std::fs::write(output_path, &content).unwrap();
+    let output = topcat(&check);
+    assert_eq!(output.status.code(), Some(7));
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("\"code\":\"out-of-date\""));
+    assert_eq!(std::fs::read_to_string(output_path).unwrap(), content);
+}
+
+#[test]
+fn test_denied_empty_selection_writes_nothing() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let output_path = temp_dir.path().join("out.sql");
+    std::fs::write(&output_path, "SELECT 1;\n").unwrap();
+
+    let output = topcat(&[
+        "-i",
+        "tests/input/sql",
+        "-e",
+        "sql",
+        "--include-prefix",
+        "zzz",
+        "--deny",
+        "empty-selection",
+        "-o",
+        output_path.to_str().unwrap(),
+    ]);
+    assert_eq!(output.status.code(), Some(8));
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("No files match the selection filters"));
+    assert_eq!(
+        std::fs::read_to_string(&output_path).unwrap(),
+        "SELECT 1;\n"
+    );
+}